@@ -0,0 +1,338 @@
+// Copyright Claudio Mattera 2024.
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Authentication of HTTP requests
+//!
+//! An [`Authenticator`] attached to a request with
+//! [`Request::with_authenticator`][crate::http::Request::with_authenticator]
+//! adds its headers every time the request is sent, so that retries and
+//! redirects carry fresh timestamps, nonces and tokens.
+
+use core::cell::RefCell;
+use core::fmt::Arguments;
+use core::fmt::Debug;
+use core::fmt::Formatter;
+use core::fmt::Result as FmtResult;
+use core::fmt::Write as _;
+use core::str::from_utf8;
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::Duration;
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine as _;
+
+use hmac::Hmac;
+use hmac::Mac as _;
+
+use sha2::Digest as _;
+use sha2::Sha256;
+
+use heapless::String;
+use heapless::Vec;
+
+use rand_core::Rng;
+
+use reqwless::request::Method;
+
+use crate::body::Body;
+use crate::clock::Clock;
+use crate::jwt::Error as JwtError;
+use crate::jwt::JwtSigner;
+use crate::jwt::Token;
+
+/// Maximal length of an authentication header value, enough for a JWT
+const VALUE_SIZE: usize = 576;
+
+/// Maximal number of authentication headers
+const MAX_HEADERS: usize = 3;
+
+/// Maximal length of Basic credentials before encoding
+const CREDENTIALS_SIZE: usize = 128;
+
+/// Size of the random nonce of signed requests
+const NONCE_SIZE: usize = 16;
+
+/// How long before expiry a JWT is replaced
+const JWT_REFRESH_MARGIN: Duration = Duration::from_secs(60);
+
+/// Add authentication headers to requests
+///
+/// Authenticators are shared with the task sending requests, hence they
+/// must be `Sync`.
+pub trait Authenticator: Sync {
+    /// Add authentication headers to a request
+    ///
+    /// `path` includes the query string, if any.  Bodies can be visited
+    /// with [`Body::visit`], as many times as needed.
+    fn authenticate(&self, method: Method, path: &str, body: Option<&Body<'_>>, headers: &mut AuthHeaders) -> Result<(), Error>;
+}
+
+impl Debug for dyn Authenticator + '_ {
+    fn fmt(&self, formatter: &mut Formatter<'_>) -> FmtResult {
+        // Never print credentials
+        formatter.write_str("Authenticator")
+    }
+}
+
+/// Headers added by an authenticator
+#[derive(Debug, Default)]
+pub struct AuthHeaders(Vec<(&'static str, String<VALUE_SIZE>), MAX_HEADERS>);
+
+impl AuthHeaders {
+    /// Add a header
+    pub fn push(&mut self, name: &'static str, value: Arguments<'_>) -> Result<(), Error> {
+        let mut string = String::new();
+        string.write_fmt(value).map_err(|_| Error::TooLong)?;
+        self.0.push((name, string)).map_err(|_| Error::TooManyHeaders)
+    }
+
+    /// Iterate over all headers
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0.iter().map(|(name, value)| (*name, value.as_str()))
+    }
+}
+
+/// HTTP Basic authentication
+pub struct Basic<'a> {
+    /// User name
+    username: &'a str,
+
+    /// Password
+    password: &'a str,
+}
+
+impl<'a> Basic<'a> {
+    /// Create a new Basic authenticator
+    #[allow(unused)]
+    pub fn new(username: &'a str, password: &'a str) -> Self {
+        Self { username, password }
+    }
+}
+
+impl Authenticator for Basic<'_> {
+    fn authenticate(&self, _method: Method, _path: &str, _body: Option<&Body<'_>>, headers: &mut AuthHeaders) -> Result<(), Error> {
+        let mut credentials: String<CREDENTIALS_SIZE> = String::new();
+        write!(credentials, "{}:{}", self.username, self.password).map_err(|_| Error::TooLong)?;
+
+        let mut encoded = [0_u8; VALUE_SIZE];
+        let length = BASE64
+            .encode_slice(credentials.as_bytes(), &mut encoded)
+            .map_err(|_| Error::TooLong)?;
+        // Base64 is always ASCII
+        let encoded = from_utf8(&encoded[..length]).unwrap_or_default();
+
+        headers.push("Authorization", format_args!("Basic {encoded}"))
+    }
+}
+
+/// Static Bearer token authentication
+pub struct Bearer<'a> {
+    /// Token
+    token: &'a str,
+}
+
+impl<'a> Bearer<'a> {
+    /// Create a new Bearer authenticator
+    #[allow(unused)]
+    pub fn new(token: &'a str) -> Self {
+        Self { token }
+    }
+}
+
+impl Authenticator for Bearer<'_> {
+    fn authenticate(&self, _method: Method, _path: &str, _body: Option<&Body<'_>>, headers: &mut AuthHeaders) -> Result<(), Error> {
+        headers.push("Authorization", format_args!("Bearer {}", self.token))
+    }
+}
+
+/// HMAC-SHA256 request signature
+///
+/// The signature covers the canonical string
+///
+/// ```text
+/// METHOD\nPATH\nTIMESTAMP\nNONCE\nBODY_HASH
+/// ```
+///
+/// where the timestamp is a Unix epoch in seconds, the nonce is 16 random
+/// bytes and the body hash is the SHA-256 digest of the body, the latter two
+/// hex encoded.  Timestamp and nonce are sent in `X-Timestamp` and `X-Nonce`
+/// headers, and the signature in an `Authorization` header like
+///
+/// ```text
+/// HMAC-SHA256 Credential=KEY_ID, Signature=HEX_SIGNATURE
+/// ```
+pub struct HmacSigner<'a, R> {
+    /// Identifier of the key, telling the server which key to check with
+    key_id: &'a str,
+
+    /// Shared secret key
+    key: &'a [u8],
+
+    /// Clock for timestamps
+    clock: &'a Clock,
+
+    /// Random numbers generator for nonces
+    rng: Mutex<CriticalSectionRawMutex, RefCell<R>>,
+}
+
+impl<'a, R> HmacSigner<'a, R>
+where
+    R: Rng + Send,
+{
+    /// Create a new signer
+    ///
+    /// The clock must be synchronized, as servers usually reject requests
+    /// with stale timestamps.
+    #[allow(unused)]
+    pub fn new(key_id: &'a str, key: &'a [u8], clock: &'a Clock, rng: R) -> Self {
+        Self {
+            key_id,
+            key,
+            clock,
+            rng: Mutex::new(RefCell::new(rng)),
+        }
+    }
+}
+
+impl<R> Authenticator for HmacSigner<'_, R>
+where
+    R: Rng + Send,
+{
+    fn authenticate(&self, method: Method, path: &str, body: Option<&Body<'_>>, headers: &mut AuthHeaders) -> Result<(), Error> {
+        let timestamp = self.clock.now_as_epoch();
+
+        let mut nonce = [0_u8; NONCE_SIZE];
+        self.rng.lock(|rng| rng.borrow_mut().fill_bytes(&mut nonce));
+        let nonce: String<{ 2 * NONCE_SIZE }> = hex(&nonce)?;
+
+        let mut hasher = Sha256::new();
+        if let Some(body) = body {
+            body.visit(|chunk| hasher.update(chunk)).map_err(|_| Error::Body)?;
+        }
+        let body_hash: String<64> = hex(&hasher.finalize())?;
+
+        let mut mac = Hmac::<Sha256>::new_from_slice(self.key).map_err(|_| Error::InvalidKey)?;
+        let mut timestamp_string: String<20> = String::new();
+        write!(timestamp_string, "{timestamp}").map_err(|_| Error::TooLong)?;
+        for (index, part) in [method.as_str(), path, timestamp_string.as_str(), nonce.as_str(), body_hash.as_str()]
+            .into_iter()
+            .enumerate()
+        {
+            if index > 0 {
+                mac.update(b"\n");
+            }
+            mac.update(part.as_bytes());
+        }
+        let signature: String<64> = hex(&mac.finalize().into_bytes())?;
+
+        headers.push("X-Timestamp", format_args!("{timestamp}"))?;
+        headers.push("X-Nonce", format_args!("{nonce}"))?;
+        headers.push(
+            "Authorization",
+            format_args!("HMAC-SHA256 Credential={}, Signature={signature}", self.key_id),
+        )
+    }
+}
+
+/// JWT Bearer authentication
+///
+/// Tokens are minted on first use and kept until one minute before they
+/// expire, when a new one is minted.
+pub struct JwtAuthenticator<'a, R> {
+    /// Token signer
+    signer: JwtSigner<'a>,
+
+    /// Clock for issue and expiration times
+    clock: &'a Clock,
+
+    /// Random numbers generator for signatures
+    rng: Mutex<CriticalSectionRawMutex, RefCell<R>>,
+
+    /// Last minted token
+    token: Mutex<CriticalSectionRawMutex, RefCell<Option<Token>>>,
+}
+
+impl<'a, R> JwtAuthenticator<'a, R>
+where
+    R: Rng + Send,
+{
+    /// Create a new JWT authenticator
+    ///
+    /// The clock must be synchronized, as servers reject tokens issued in
+    /// the future or already expired.
+    #[allow(unused)]
+    pub fn new(signer: JwtSigner<'a>, clock: &'a Clock, rng: R) -> Self {
+        Self {
+            signer,
+            clock,
+            rng: Mutex::new(RefCell::new(rng)),
+            token: Mutex::new(RefCell::new(None)),
+        }
+    }
+
+    /// Return a fresh token, minting a new one if needed
+    fn token(&self) -> Result<Token, Error> {
+        let now = self.clock.now_as_epoch();
+
+        let cached = self.token.lock(|token| token.borrow().clone());
+        if let Some(token) = cached.filter(|token| token.is_fresh(now, JWT_REFRESH_MARGIN)) {
+            return Ok(token);
+        }
+
+        // Sign outside of the critical section, it takes a while
+        let mut seed = [0_u8; 32];
+        self.rng.lock(|rng| rng.borrow_mut().fill_bytes(&mut seed));
+        let token = self.signer.mint_with_seed(now, seed)?;
+        self.token.lock(|cached| *cached.borrow_mut() = Some(token.clone()));
+        Ok(token)
+    }
+}
+
+impl<R> Authenticator for JwtAuthenticator<'_, R>
+where
+    R: Rng + Send,
+{
+    fn authenticate(&self, _method: Method, _path: &str, _body: Option<&Body<'_>>, headers: &mut AuthHeaders) -> Result<(), Error> {
+        let token = self.token()?;
+        headers.push("Authorization", format_args!("Bearer {}", token.as_str()))
+    }
+}
+
+/// Encode bytes as lowercase hexadecimal
+fn hex<const N: usize>(bytes: &[u8]) -> Result<String<N>, Error> {
+    let mut string = String::new();
+    for byte in bytes {
+        write!(string, "{byte:02x}").map_err(|_| Error::TooLong)?;
+    }
+    Ok(string)
+}
+
+/// An error within request authentication
+#[derive(Debug)]
+pub enum Error {
+    /// A header value is too long
+    TooLong,
+
+    /// Too many authentication headers
+    TooManyHeaders,
+
+    /// Signing key is not valid
+    InvalidKey,
+
+    /// Body could not be read for signing
+    Body,
+
+    /// Token could not be minted
+    Jwt(#[allow(unused)] JwtError),
+}
+
+impl From<JwtError> for Error {
+    fn from(error: JwtError) -> Self {
+        Self::Jwt(error)
+    }
+}
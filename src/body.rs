@@ -0,0 +1,315 @@
+// Copyright Claudio Mattera 2024.
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Request bodies
+//!
+//! Besides contiguous bodies, requests can send bodies produced while
+//! sending, such as files read from flash, and `multipart/form-data` bodies.
+//! Both are sent with chunked transfer encoding through a fixed size buffer,
+//! so memory use does not depend on the body size.
+//!
+//! Bodies are read from the start every time a request is sent, so that
+//! retries and redirects send them again.
+
+use core::fmt::Debug;
+use core::fmt::Formatter;
+use core::fmt::Result as FmtResult;
+use core::fmt::Write as _;
+
+use heapless::String;
+
+use reqwless::headers::ContentType;
+
+use crate::http::Error;
+
+/// Maximal length of the headers of a multipart part
+const PART_HEADER_SIZE: usize = 256;
+
+/// Size of the buffer used to visit a body
+const VISIT_SIZE: usize = 256;
+
+/// Boundary between multipart parts, unless set otherwise
+const DEFAULT_BOUNDARY: &str = "----esp32c3-form-boundary-7MA4YWxkTrZu0gW";
+
+/// A source of body data
+///
+/// Sources are read by offset, which lets a body be sent again from the
+/// start, and are shared with the task sending requests, hence they must be
+/// `Sync`.
+#[allow(clippy::result_large_err)]
+pub trait BodySource: Sync {
+    /// Fill `buffer` with data starting at `offset`
+    ///
+    /// Return the number of bytes read, zero at the end of the data.
+    fn read_at(&self, offset: usize, buffer: &mut [u8]) -> Result<usize, Error>;
+}
+
+impl Debug for dyn BodySource + '_ {
+    fn fmt(&self, formatter: &mut Formatter<'_>) -> FmtResult {
+        formatter.write_str("BodySource")
+    }
+}
+
+/// Data of a body or of a part
+#[derive(Clone, Copy, Debug)]
+enum Data<'a> {
+    /// Contiguous data
+    Bytes(&'a [u8]),
+
+    /// Data read from a source
+    Stream(&'a dyn BodySource),
+}
+
+#[allow(clippy::result_large_err)]
+impl Data<'_> {
+    /// Fill `buffer` with data starting at `offset`
+    fn read_at(&self, offset: usize, buffer: &mut [u8]) -> Result<usize, Error> {
+        match self {
+            Self::Bytes(bytes) => Ok(copy_at(bytes, offset, buffer)),
+            Self::Stream(source) => source.read_at(offset, buffer),
+        }
+    }
+}
+
+/// Fill `buffer` with bytes starting at `offset`
+fn copy_at(bytes: &[u8], offset: usize, buffer: &mut [u8]) -> usize {
+    let remaining = bytes.get(offset..).unwrap_or_default();
+    let length = remaining.len().min(buffer.len());
+    buffer[..length].copy_from_slice(&remaining[..length]);
+    length
+}
+
+/// Return the MIME type of a content type
+///
+/// Content types of `reqwless` cannot be copied, while bodies are copied for
+/// every attempt, so bodies keep the MIME type instead.
+pub const fn mime_type(content_type: &ContentType) -> &'static str {
+    match content_type {
+        ContentType::TextHtml => "text/html",
+        ContentType::TextPlain => "text/plain",
+        ContentType::ApplicationJson => "application/json",
+        ContentType::ApplicationCbor => "application/cbor",
+        ContentType::ApplicationOctetStream => "application/octet-stream",
+    }
+}
+
+/// Body of a request
+#[derive(Clone, Copy, Debug)]
+pub enum Body<'a> {
+    /// Contiguous body, sent with `Content-Length`
+    Bytes(&'static str, &'a [u8]),
+
+    /// Body read from a source while sending
+    Stream(&'static str, &'a dyn BodySource),
+
+    /// `multipart/form-data` body
+    Multipart(&'a Multipart<'a>),
+}
+
+#[allow(clippy::result_large_err)]
+impl<'a> Body<'a> {
+    /// Return a reader over the body bytes, from the start
+    pub fn reader(&self) -> BodyReader<'a> {
+        BodyReader {
+            body: *self,
+            segment: 0,
+            offset: 0,
+        }
+    }
+
+    /// Pass all body bytes to a callback, chunk by chunk
+    pub fn visit<F>(&self, mut on_chunk: F) -> Result<(), Error>
+    where
+        F: FnMut(&[u8]),
+    {
+        let mut reader = self.reader();
+        let mut chunk = [0_u8; VISIT_SIZE];
+
+        loop {
+            let length = reader.read(&mut chunk)?;
+            if length == 0 {
+                return Ok(());
+            }
+            on_chunk(&chunk[..length]);
+        }
+    }
+}
+
+/// A `multipart/form-data` body
+#[derive(Clone, Copy, Debug)]
+pub struct Multipart<'a> {
+    /// Parts
+    parts: &'a [Part<'a>],
+
+    /// Boundary between parts
+    boundary: &'a str,
+}
+
+impl<'a> Multipart<'a> {
+    /// Create a new multipart body
+    #[allow(unused)]
+    pub const fn new(parts: &'a [Part<'a>]) -> Self {
+        Self {
+            parts,
+            boundary: DEFAULT_BOUNDARY,
+        }
+    }
+
+    /// Set the boundary between parts
+    ///
+    /// It must not appear in any part.
+    #[allow(unused)]
+    #[must_use]
+    pub const fn with_boundary(self, boundary: &'a str) -> Self {
+        Self { boundary, ..self }
+    }
+
+    /// Return the boundary between parts
+    pub fn boundary(&self) -> &str {
+        self.boundary
+    }
+}
+
+/// A named part of a `multipart/form-data` body
+#[derive(Clone, Copy, Debug)]
+pub struct Part<'a> {
+    /// Form field name
+    name: &'a str,
+
+    /// File name, for file uploads
+    filename: Option<&'a str>,
+
+    /// Content type of the data
+    content_type: &'static str,
+
+    /// Data
+    data: Data<'a>,
+}
+
+#[allow(clippy::result_large_err)]
+impl<'a> Part<'a> {
+    /// Create a new part with contiguous data
+    #[allow(unused)]
+    pub const fn bytes(name: &'a str, content_type: ContentType, data: &'a [u8]) -> Self {
+        Self {
+            name,
+            filename: None,
+            content_type: mime_type(&content_type),
+            data: Data::Bytes(data),
+        }
+    }
+
+    /// Create a new part with data read from a source
+    #[allow(unused)]
+    pub const fn stream(name: &'a str, content_type: ContentType, data: &'a dyn BodySource) -> Self {
+        Self {
+            name,
+            filename: None,
+            content_type: mime_type(&content_type),
+            data: Data::Stream(data),
+        }
+    }
+
+    /// Set the file name
+    #[allow(unused)]
+    #[must_use]
+    pub const fn with_filename(self, filename: &'a str) -> Self {
+        Self {
+            filename: Some(filename),
+            ..self
+        }
+    }
+
+    /// Format the delimiter and headers preceding the data
+    fn header(&self, boundary: &str) -> Result<String<PART_HEADER_SIZE>, Error> {
+        let mut header = String::new();
+        let result = write!(
+            header,
+            "--{boundary}\r\nContent-Disposition: form-data; name=\"{}\"",
+            self.name,
+        )
+        .and_then(|()| match self.filename {
+            Some(filename) => write!(header, "; filename=\"{filename}\""),
+            None => Ok(()),
+        })
+        .and_then(|()| write!(header, "\r\nContent-Type: {}\r\n\r\n", self.content_type));
+
+        result.map_err(|_| Error::Body)?;
+        Ok(header)
+    }
+}
+
+/// Reader over the bytes of a body
+///
+/// Multipart bodies are read as a sequence of segments: for each part its
+/// headers, its data and a line break, and finally the closing delimiter.
+pub struct BodyReader<'a> {
+    /// Body
+    body: Body<'a>,
+
+    /// Current segment
+    segment: usize,
+
+    /// Offset within the current segment
+    offset: usize,
+}
+
+#[allow(clippy::result_large_err)]
+impl BodyReader<'_> {
+    /// Fill `buffer` with the next body bytes
+    ///
+    /// Return the number of bytes read, zero at the end of the body.
+    pub fn read(&mut self, buffer: &mut [u8]) -> Result<usize, Error> {
+        loop {
+            let Some(length) = self.read_segment(buffer)? else {
+                return Ok(0);
+            };
+
+            if length == 0 {
+                self.segment += 1;
+                self.offset = 0;
+            } else {
+                self.offset += length;
+                return Ok(length);
+            }
+        }
+    }
+
+    /// Read from the current segment
+    ///
+    /// Return `None` after the last segment.
+    fn read_segment(&self, buffer: &mut [u8]) -> Result<Option<usize>, Error> {
+        let multipart = match self.body {
+            Body::Bytes(_, bytes) => return self.read_single(Data::Bytes(bytes), buffer),
+            Body::Stream(_, source) => return self.read_single(Data::Stream(source), buffer),
+            Body::Multipart(multipart) => multipart,
+        };
+
+        let boundary = multipart.boundary;
+        let index = self.segment / 3;
+        let length = match (multipart.parts.get(index), self.segment % 3) {
+            (Some(part), 0) => copy_at(part.header(boundary)?.as_bytes(), self.offset, buffer),
+            (Some(part), 1) => part.data.read_at(self.offset, buffer)?,
+            (Some(_), _) => copy_at(b"\r\n", self.offset, buffer),
+            (None, 0) => {
+                let mut closing: String<PART_HEADER_SIZE> = String::new();
+                write!(closing, "--{boundary}--\r\n").map_err(|_| Error::Body)?;
+                copy_at(closing.as_bytes(), self.offset, buffer)
+            }
+            (None, _) => return Ok(None),
+        };
+
+        Ok(Some(length))
+    }
+
+    /// Read the only segment of a body that is not multipart
+    fn read_single(&self, data: Data<'_>, buffer: &mut [u8]) -> Result<Option<usize>, Error> {
+        match self.segment {
+            0 => data.read_at(self.offset, buffer).map(Some),
+            _ => Ok(None),
+        }
+    }
+}
@@ -0,0 +1,113 @@
+// Copyright Claudio Mattera 2024.
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Validators for conditional GET requests
+//!
+//! The cache remembers the `ETag` and `Last-Modified` headers of the last
+//! successful response for a few URLs, so that the next request to the same
+//! URL can ask the server whether the resource changed.  Bodies are not
+//! cached, callers keep what they parsed from the last response.
+
+use heapless::String;
+use heapless::Vec;
+
+use log::debug;
+
+use crate::http::Headers;
+
+/// Maximal length of a cached URL
+const URL_SIZE: usize = 128;
+
+/// Maximal length of a validator
+const VALIDATOR_SIZE: usize = 128;
+
+/// Validators of a resource
+#[derive(Clone, Debug, Default)]
+pub struct Validators {
+    /// Value of the `ETag` header
+    pub etag: Option<String<VALIDATOR_SIZE>>,
+
+    /// Value of the `Last-Modified` header
+    pub last_modified: Option<String<VALIDATOR_SIZE>>,
+}
+
+impl Validators {
+    /// Extract validators from response headers
+    fn from_headers(headers: &Headers) -> Self {
+        let extract = |name| headers.get(name).and_then(|value| String::try_from(value).ok());
+
+        Self {
+            etag: extract("ETag"),
+            last_modified: extract("Last-Modified"),
+        }
+    }
+
+    /// Check whether there is no validator
+    fn is_empty(&self) -> bool {
+        self.etag.is_none() && self.last_modified.is_none()
+    }
+}
+
+/// A cached resource
+#[derive(Clone, Debug)]
+struct Entry {
+    /// Resource URL
+    url: String<URL_SIZE>,
+
+    /// Its validators
+    validators: Validators,
+}
+
+/// Cache of validators for up to `N` URLs
+///
+/// When full, the least recently stored entry is replaced.
+#[derive(Clone, Debug, Default)]
+pub struct EtagCache<const N: usize> {
+    /// Entries, oldest first
+    entries: Vec<Entry, N>,
+}
+
+impl<const N: usize> EtagCache<N> {
+    /// Create a new empty cache
+    pub const fn new() -> Self {
+        Self { entries: Vec::new() }
+    }
+
+    /// Return the validators stored for a URL
+    pub fn get(&self, url: &str) -> Option<&Validators> {
+        self.entries
+            .iter()
+            .find(|entry| entry.url == url)
+            .map(|entry| &entry.validators)
+    }
+
+    /// Store the validators from the headers of a successful response
+    ///
+    /// Responses without validators remove the URL from the cache.
+    pub fn store(&mut self, url: &str, headers: &Headers) {
+        self.remove(url);
+
+        let validators = Validators::from_headers(headers);
+        if validators.is_empty() {
+            return;
+        }
+
+        let Ok(url) = String::try_from(url) else {
+            debug!("Do not cache {url}, URL too long");
+            return;
+        };
+
+        if self.entries.is_full() && !self.entries.is_empty() {
+            self.entries.remove(0);
+        }
+        let _ = self.entries.push(Entry { url, validators });
+    }
+
+    /// Remove the validators stored for a URL
+    pub fn remove(&mut self, url: &str) {
+        self.entries.retain(|entry| entry.url != url);
+    }
+}
@@ -15,9 +15,19 @@ use time::error::ComponentRange as TimeComponentRange;
 use time::OffsetDateTime;
 use time::UtcOffset;
 
-use crate::http::Client as HttpClient;
+use embedded_nal_async::Dns;
+
+use log::debug;
+
+use crate::date::Error as DateError;
+use crate::date::ServerDate;
+use crate::http::Error as HttpError;
+use crate::http::ErrorClass as HttpErrorClass;
+use crate::sntp::Error as SntpError;
+use crate::sntp::NtpSocket;
+use crate::sntp::SntpClient;
 use crate::worldtimeapi::Error as WorldTimeApiError;
-use crate::worldtimeapi::WorldTimeApiClient as _;
+use crate::worldtimeapi::WorldTimeApiClient;
 
 /// Stored boot time between deep sleep cycles
 ///
@@ -30,7 +40,7 @@ static mut BOOT_TIME: (u64, i32) = (0, 0);
 /// A clock
 #[derive(Clone, Debug)]
 pub struct Clock {
-    /// The boot time in Unix epoch
+    /// The boot time in Unix epoch, in microseconds
     #[allow(unused)]
     boot_time: u64,
 
@@ -39,11 +49,16 @@ pub struct Clock {
     offset: UtcOffset,
 }
 
+#[allow(clippy::result_large_err)]
 impl Clock {
     /// Create a new clock
     pub fn new(current_time: u64, offset: UtcOffset) -> Self {
-        let from_boot = Instant::now().as_secs();
-        let boot_time = current_time - from_boot;
+        Self::from_unix_micros(current_time * 1_000_000, Instant::now(), offset)
+    }
+
+    /// Create a new clock from the Unix time at an instant, in microseconds
+    pub fn from_unix_micros(unix_micros: u64, at: Instant, offset: UtcOffset) -> Self {
+        let boot_time = unix_micros.saturating_sub(at.as_micros());
 
         Self { boot_time, offset }
     }
@@ -51,9 +66,8 @@ impl Clock {
     /// Return the current time
     #[allow(unused)]
     pub fn now(&self) -> Result<OffsetDateTime, Error> {
-        let epoch = self.now_as_epoch();
-        #[allow(clippy::cast_possible_wrap)]
-        let utc = OffsetDateTime::from_unix_timestamp(epoch as i64)?;
+        let nanos = i128::from(self.now_as_epoch_micros()) * 1_000;
+        let utc = OffsetDateTime::from_unix_timestamp_nanos(nanos)?;
         let local = utc
             .checked_to_offset(self.offset)
             .ok_or(Error::InvalidInOffset)?;
@@ -61,21 +75,87 @@ impl Clock {
     }
 
     /// Create a new clock by synchronizing with a server
+    ///
+    /// The server is queried `samples` times, and the answer with the
+    /// shortest round trip is kept.  Following Cristian's algorithm, the
+    /// server time is assumed to be taken halfway through the round trip,
+    /// so the error bound is half the round trip.
     pub async fn from_server(
-        http_client: &mut HttpClient<'static>,
+        http_client: &mut impl WorldTimeApiClient,
         timeout: Duration,
-        // stack: &'static Stack<WifiDevice<'static, WifiStaDevice>>,
-    ) -> Result<Self, Error> {
-        let now = http_client.fetch_current_time(timeout).await?;
+        samples: u8,
+    ) -> Result<Synchronized, Error> {
+        let mut best: Option<(OffsetDateTime, Duration, Instant)> = None;
+        let mut last_error = Error::NoSource;
+
+        for _ in 0..samples.max(1) {
+            let start = Instant::now();
+            let result = http_client.fetch_current_time(timeout).await;
+            let end = Instant::now();
 
-        let current_time = now.unix_timestamp();
+            let now = match result {
+                Ok(now) => now,
+                Err(error) => {
+                    last_error = error.into();
+                    continue;
+                }
+            };
+
+            let round_trip = end - start;
+            debug!(
+                "Server time sample with round trip {} ms",
+                round_trip.as_millis()
+            );
+            if best.is_none_or(|(_, best_round_trip, _)| round_trip < best_round_trip) {
+                best = Some((now, round_trip, end));
+            }
+        }
+
+        let Some((now, round_trip, end)) = best else {
+            return Err(last_error);
+        };
+
+        #[allow(clippy::cast_sign_loss, clippy::cast_possible_truncation)]
+        let server_time = (now.unix_timestamp_nanos() / 1_000) as u64;
+        let half_round_trip = round_trip.as_micros() / 2;
+        let current_time = server_time + half_round_trip;
+
+        Ok(Synchronized {
+            clock: Self::from_unix_micros(current_time, end, now.offset()),
+            error_bound: Duration::from_micros(half_round_trip),
+        })
+    }
 
+    /// Create a new clock from the `Date` of a response
+    ///
+    /// The clock is accurate to about a second.
+    #[allow(unused)]
+    pub fn from_server_date(date: &ServerDate, offset: UtcOffset) -> Self {
         #[allow(clippy::cast_sign_loss)]
-        let current_time = current_time as u64;
+        let current_time = date.now().unix_timestamp() as u64;
+
+        Self::new(current_time, offset)
+    }
 
-        let offset = now.offset();
+    /// Create a new clock by synchronizing with SNTP servers
+    ///
+    /// SNTP only provides UTC, the local time offset is given.
+    #[allow(unused)]
+    pub async fn from_sntp<D>(
+        sntp_client: &mut SntpClient<'_, D>,
+        socket: &mut impl NtpSocket,
+        offset: UtcOffset,
+    ) -> Result<Self, Error>
+    where
+        D: Dns,
+    {
+        let measurement = sntp_client.query(socket).await?;
+        let at = Instant::now();
 
-        Ok(Self::new(current_time, offset))
+        #[allow(clippy::cast_sign_loss)]
+        let current_time = measurement.unix_micros_at(at) as u64;
+
+        Ok(Self::from_unix_micros(current_time, at, offset))
     }
 
     /// Initialize clock from RTC Fast memory
@@ -120,11 +200,27 @@ impl Clock {
     /// Return current time as a Unix epoch
     #[allow(unused)]
     pub fn now_as_epoch(&self) -> u64 {
-        let from_boot = Instant::now().as_secs();
+        self.now_as_epoch_micros() / 1_000_000
+    }
+
+    /// Return current time as a Unix epoch, in microseconds
+    #[allow(unused)]
+    pub fn now_as_epoch_micros(&self) -> u64 {
+        let from_boot = Instant::now().as_micros();
         self.boot_time + from_boot
     }
 }
 
+/// A clock synchronized with a server
+#[derive(Clone, Debug)]
+pub struct Synchronized {
+    /// The clock
+    pub clock: Clock,
+
+    /// Largest difference between the clock and the server time
+    pub error_bound: Duration,
+}
+
 /// Compute the next wakeup rounded down to a period
 ///
 /// * At 09:46:12 with period 1 minute, next rounded wakeup is 09:47:00.
@@ -152,6 +248,31 @@ pub enum Error {
 
     /// Error synchronizing time from World Time API
     Synchronization(#[allow(unused)] WorldTimeApiError),
+
+    /// Error synchronizing time from SNTP servers
+    Sntp(#[allow(unused)] SntpError),
+
+    /// Error requesting a `Date` header
+    Http(#[allow(unused)] HttpError),
+
+    /// `Date` header could not be parsed
+    Date(#[allow(unused)] DateError),
+
+    /// No time source was queried
+    NoSource,
+}
+
+impl Error {
+    /// Check whether synchronization may succeed if retried later
+    #[allow(unused)]
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Self::Synchronization(error) => error.class() == HttpErrorClass::Transient,
+            Self::Sntp(error) => error.class() == HttpErrorClass::Transient,
+            Self::Http(error) => error.is_transient(),
+            _ => false,
+        }
+    }
 }
 
 impl From<TimeComponentRange> for Error {
@@ -164,4 +285,22 @@ impl From<WorldTimeApiError> for Error {
     fn from(error: WorldTimeApiError) -> Self {
         Self::Synchronization(error)
     }
-}
\ No newline at end of file
+}
+
+impl From<SntpError> for Error {
+    fn from(error: SntpError) -> Self {
+        Self::Sntp(error)
+    }
+}
+
+impl From<HttpError> for Error {
+    fn from(error: HttpError) -> Self {
+        Self::Http(error)
+    }
+}
+
+impl From<DateError> for Error {
+    fn from(error: DateError) -> Self {
+        Self::Date(error)
+    }
+}
@@ -0,0 +1,201 @@
+// Copyright Claudio Mattera 2024.
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Parser of HTTP dates
+//!
+//! Servers send the `Date` header in the IMF-fixdate format of RFC 7231,
+//! such as `Sun, 06 Nov 1994 08:49:37 GMT`, always in UTC.  Recipients must
+//! also accept the obsolete RFC 850 and asctime formats.
+//!
+//! The HTTP client records the `Date` of every response, which gives the
+//! current time with about one second accuracy.
+
+use embassy_time::Instant;
+
+use time::Date;
+use time::Duration as TimeDuration;
+use time::Month;
+use time::OffsetDateTime;
+use time::PrimitiveDateTime;
+use time::Time;
+
+/// Two-digit years below this one are taken from the 21st century
+const CENTURY_PIVOT: i32 = 70;
+
+/// A date sent by a server
+#[derive(Clone, Copy, Debug)]
+pub struct ServerDate {
+    /// Date of the response
+    pub date: OffsetDateTime,
+
+    /// When the response was received
+    pub received_at: Instant,
+}
+
+impl ServerDate {
+    /// Return the current time according to the server
+    pub fn now(&self) -> OffsetDateTime {
+        #[allow(clippy::cast_possible_wrap)]
+        let elapsed = TimeDuration::microseconds(self.received_at.elapsed().as_micros() as i64);
+        self.date + elapsed
+    }
+}
+
+/// Parse an HTTP date in any of the three formats
+pub fn parse(value: &str) -> Result<OffsetDateTime, Error> {
+    let value = value.trim();
+    parse_imf_fixdate(value)
+        .or_else(|| parse_rfc850(value))
+        .or_else(|| parse_asctime(value))
+        .ok_or(Error::Invalid)
+}
+
+/// Parse an IMF-fixdate, such as `Sun, 06 Nov 1994 08:49:37 GMT`
+///
+/// The day name is not checked against the date.
+fn parse_imf_fixdate(value: &str) -> Option<OffsetDateTime> {
+    let (_, rest) = value.split_once(", ")?;
+    let mut parts = rest.split(' ');
+
+    let day = parts.next()?;
+    let month = parts.next()?;
+    let year = parts.next()?;
+    let time = parts.next()?;
+    if parts.next()? != "GMT" || parts.next().is_some() || day.len() != 2 || year.len() != 4 {
+        return None;
+    }
+
+    let date = Date::from_calendar_date(year.parse().ok()?, month_from_name(month)?, day.parse().ok()?).ok()?;
+    Some(PrimitiveDateTime::new(date, time_of_day(time)?).assume_utc())
+}
+
+/// Parse an RFC 850 date, such as `Sunday, 06-Nov-94 08:49:37 GMT`
+fn parse_rfc850(value: &str) -> Option<OffsetDateTime> {
+    let (_, rest) = value.split_once(", ")?;
+    let mut parts = rest.split(' ');
+
+    let date = parts.next()?;
+    let time = parts.next()?;
+    if parts.next()? != "GMT" || parts.next().is_some() {
+        return None;
+    }
+
+    let mut fields = date.split('-');
+    let day = fields.next().filter(|day| day.len() == 2)?;
+    let month = fields.next()?;
+    let year: i32 = fields.next().filter(|year| year.len() == 2)?.parse().ok()?;
+    if fields.next().is_some() {
+        return None;
+    }
+    let year = if year < CENTURY_PIVOT { 2000 + year } else { 1900 + year };
+
+    let date = Date::from_calendar_date(year, month_from_name(month)?, day.parse().ok()?).ok()?;
+    Some(PrimitiveDateTime::new(date, time_of_day(time)?).assume_utc())
+}
+
+/// Parse an asctime date, such as `Sun Nov  6 08:49:37 1994`
+fn parse_asctime(value: &str) -> Option<OffsetDateTime> {
+    let mut parts = value.split_whitespace();
+
+    let _day_name = parts.next()?;
+    let month = parts.next()?;
+    let day = parts.next()?;
+    let time = parts.next()?;
+    let year = parts.next()?;
+    if parts.next().is_some() || day.len() > 2 || year.len() != 4 {
+        return None;
+    }
+
+    let date = Date::from_calendar_date(year.parse().ok()?, month_from_name(month)?, day.parse().ok()?).ok()?;
+    Some(PrimitiveDateTime::new(date, time_of_day(time)?).assume_utc())
+}
+
+/// Parse a month abbreviation
+fn month_from_name(name: &str) -> Option<Month> {
+    let month = match name {
+        "Jan" => Month::January,
+        "Feb" => Month::February,
+        "Mar" => Month::March,
+        "Apr" => Month::April,
+        "May" => Month::May,
+        "Jun" => Month::June,
+        "Jul" => Month::July,
+        "Aug" => Month::August,
+        "Sep" => Month::September,
+        "Oct" => Month::October,
+        "Nov" => Month::November,
+        "Dec" => Month::December,
+        _ => return None,
+    };
+    Some(month)
+}
+
+/// Parse a time of day, such as `08:49:37`
+fn time_of_day(value: &str) -> Option<Time> {
+    let mut parts = value.split(':');
+    let mut next = || parts.next().filter(|part| part.len() == 2)?.parse().ok();
+
+    let hour = next()?;
+    let minute = next()?;
+    let second = next()?;
+    if parts.next().is_some() {
+        return None;
+    }
+
+    Time::from_hms(hour, minute, second).ok()
+}
+
+/// An error within parsing an HTTP date
+#[derive(Clone, Copy, Debug)]
+pub enum Error {
+    /// Header is missing
+    Missing,
+
+    /// Date is not in a known format
+    Invalid,
+}
+
+/// Tests of the date parsers
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn imf_fixdate() {
+        let date = parse("Sun, 06 Nov 1994 08:49:37 GMT").unwrap();
+        assert_eq!(date.unix_timestamp(), 784_111_777);
+    }
+
+    #[test]
+    fn rfc850() {
+        let date = parse("Sunday, 06-Nov-94 08:49:37 GMT").unwrap();
+        assert_eq!(date.unix_timestamp(), 784_111_777);
+    }
+
+    #[test]
+    fn rfc850_years_before_pivot_are_in_21st_century() {
+        let date = parse("Tuesday, 01-Jan-30 00:00:00 GMT").unwrap();
+        assert_eq!(date.year(), 2030);
+    }
+
+    #[test]
+    fn asctime() {
+        let date = parse("Sun Nov  6 08:49:37 1994").unwrap();
+        assert_eq!(date.unix_timestamp(), 784_111_777);
+    }
+
+    #[test]
+    fn surrounding_whitespace_is_ignored() {
+        assert!(parse(" Sun, 06 Nov 1994 08:49:37 GMT ").is_ok());
+    }
+
+    #[test]
+    fn invalid_dates_are_rejected() {
+        for value in ["", "yesterday", "Sun, 06 Nov 1994 08:49 GMT", "Sun, 31 Feb 1994 08:49:37 GMT", "Sun, 06 Foo 1994 08:49:37 GMT"] {
+            assert!(matches!(parse(value), Err(Error::Invalid)), "{value}");
+        }
+    }
+}
@@ -0,0 +1,165 @@
+// Copyright Claudio Mattera 2024.
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Transport over std sockets, for running the HTTP and SNTP clients on a host
+//!
+//! Sockets are blocking, so every future completes on its first poll.  This
+//! is enough to exercise the clients against a local server from a test
+//! driven by a simple executor, but it blocks the calling thread.  UDP
+//! sockets time out on their own, since a blocked poll cannot be cancelled.
+
+use core::net::IpAddr;
+use core::net::SocketAddr;
+
+use std::io::ErrorKind as StdErrorKind;
+use std::io::Read as _;
+use std::io::Write as _;
+use std::net::TcpStream;
+use std::net::UdpSocket;
+use std::net::ToSocketAddrs as _;
+
+use embedded_io_async::ErrorKind;
+use embedded_io_async::ErrorType;
+use embedded_io_async::Read;
+use embedded_io_async::Write;
+
+use embedded_nal_async::AddrType;
+use embedded_nal_async::Dns;
+use embedded_nal_async::TcpConnect;
+
+use embassy_time::Duration;
+
+use log::debug;
+
+use crate::sntp::Error as SntpError;
+use crate::sntp::NtpSocket;
+
+/// TCP client over std sockets
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StdTcpClient;
+
+impl TcpConnect for StdTcpClient {
+    type Error = ErrorKind;
+
+    type Connection<'a>
+        = StdTcpConnection
+    where
+        Self: 'a;
+
+    async fn connect<'a>(&'a self, remote: SocketAddr) -> Result<Self::Connection<'a>, Self::Error> {
+        debug!("Open std TCP connection to {remote}");
+        let stream = TcpStream::connect(remote).map_err(kind)?;
+        stream.set_nodelay(true).map_err(kind)?;
+        Ok(StdTcpConnection(stream))
+    }
+}
+
+/// TCP connection over a std socket
+#[derive(Debug)]
+pub struct StdTcpConnection(TcpStream);
+
+impl ErrorType for StdTcpConnection {
+    type Error = ErrorKind;
+}
+
+impl Read for StdTcpConnection {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        // A blocking socket would wait for data even with nothing to read
+        // into, while readers must return at once
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        self.0.read(buf).map_err(kind)
+    }
+}
+
+impl Write for StdTcpConnection {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        self.0.write(buf).map_err(kind)
+    }
+
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        self.0.flush().map_err(kind)
+    }
+}
+
+/// DNS resolver using the host name resolution
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StdDns;
+
+impl Dns for StdDns {
+    type Error = ErrorKind;
+
+    async fn get_host_by_name(&self, host: &str, addr_type: AddrType) -> Result<IpAddr, Self::Error> {
+        (host, 0)
+            .to_socket_addrs()
+            .map_err(kind)?
+            .map(|address| address.ip())
+            .find(|address| match addr_type {
+                AddrType::IPv4 => address.is_ipv4(),
+                AddrType::IPv6 => address.is_ipv6(),
+                AddrType::Either => true,
+            })
+            .ok_or(ErrorKind::NotFound)
+    }
+
+    async fn get_host_by_address(&self, _addr: IpAddr, _result: &mut [u8]) -> Result<usize, Self::Error> {
+        Err(ErrorKind::Unsupported)
+    }
+}
+
+/// UDP socket over a std socket
+#[derive(Debug)]
+pub struct StdUdpSocket(UdpSocket);
+
+impl StdUdpSocket {
+    /// Bind a socket, receiving with a timeout
+    #[allow(unused)]
+    pub fn bind(local: SocketAddr, timeout: Duration) -> std::io::Result<Self> {
+        let socket = UdpSocket::bind(local)?;
+        socket.set_read_timeout(Some(std::time::Duration::from_micros(timeout.as_micros())))?;
+        Ok(Self(socket))
+    }
+}
+
+impl NtpSocket for StdUdpSocket {
+    async fn send_to(&mut self, buffer: &[u8], remote: SocketAddr) -> Result<(), SntpError> {
+        self.0.send_to(buffer, remote).map(|_| ()).map_err(|error| {
+            debug!("Failed to send datagram to {remote}: {error}");
+            SntpError::Io
+        })
+    }
+
+    async fn receive_from(&mut self, buffer: &mut [u8]) -> Result<(usize, SocketAddr), SntpError> {
+        self.0.recv_from(buffer).map_err(|error| match kind(error) {
+            ErrorKind::TimedOut => SntpError::Timeout,
+            _ => SntpError::Io,
+        })
+    }
+}
+
+/// Convert a std I/O error to its embedded-io kind
+fn kind(error: std::io::Error) -> ErrorKind {
+    match error.kind() {
+        StdErrorKind::NotFound => ErrorKind::NotFound,
+        StdErrorKind::PermissionDenied => ErrorKind::PermissionDenied,
+        StdErrorKind::ConnectionRefused => ErrorKind::ConnectionRefused,
+        StdErrorKind::ConnectionReset => ErrorKind::ConnectionReset,
+        StdErrorKind::ConnectionAborted => ErrorKind::ConnectionAborted,
+        StdErrorKind::NotConnected => ErrorKind::NotConnected,
+        StdErrorKind::AddrInUse => ErrorKind::AddrInUse,
+        StdErrorKind::AddrNotAvailable => ErrorKind::AddrNotAvailable,
+        StdErrorKind::BrokenPipe => ErrorKind::BrokenPipe,
+        StdErrorKind::InvalidInput => ErrorKind::InvalidInput,
+        StdErrorKind::InvalidData => ErrorKind::InvalidData,
+        StdErrorKind::TimedOut | StdErrorKind::WouldBlock => ErrorKind::TimedOut,
+        StdErrorKind::Interrupted => ErrorKind::Interrupted,
+        StdErrorKind::Unsupported => ErrorKind::Unsupported,
+        StdErrorKind::OutOfMemory => ErrorKind::OutOfMemory,
+        StdErrorKind::WriteZero => ErrorKind::WriteZero,
+        _ => ErrorKind::Other,
+    }
+}
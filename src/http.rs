@@ -6,288 +6,1839 @@
 
 //! HTTP client
 
-use embassy_net::dns::DnsSocket;
-use embassy_net::dns::Error as DnsError;
-use embassy_net::tcp::client::TcpClient;
-use embassy_net::tcp::client::TcpClientState;
-use embassy_net::tcp::ConnectError as TcpConnectError;
-use embassy_net::tcp::Error as TcpError;
-use embassy_net::Stack;
-use embassy_time::{Duration, WithTimeout};
+use embassy_time::{Duration, Instant, Timer, WithTimeout};
 use log::debug;
+use log::warn;
+
+use core::fmt::Write as _;
+use core::net::SocketAddr;
+use core::str::from_utf8;
+
+use embedded_nal_async::AddrType;
+use embedded_nal_async::Dns;
+use embedded_nal_async::TcpConnect;
+
+use nourl::Url;
+use nourl::UrlScheme;
 
-use reqwless::client::HttpClient;
-use reqwless::client::TlsConfig;
-use reqwless::client::TlsVerify;
 use reqwless::request::Method;
+use reqwless::request::Request as ReqlessRequest;
 use reqwless::request::RequestBuilder;
 use reqwless::headers::ContentType;
+use reqwless::response::Response as ReqlessResponse;
 use reqwless::Error as ReqlessError;
 
+use embedded_io_async::Error as _;
+use embedded_io_async::ErrorKind;
+use embedded_io_async::ErrorType;
+use embedded_io_async::Read;
+use embedded_io_async::Write;
+
+use embedded_tls::TlsError;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use serde_json_core::de::Error as JsonDeserializeError;
+use serde_json_core::ser::Error as JsonSerializeError;
+
+use heapless::String;
 use heapless::Vec;
 
-use rand_core::RngCore as _;
+use rand_core::Rng;
 
-use crate::RngWrapper;
+use crate::auth::AuthHeaders;
+use crate::body::mime_type;
+use crate::body::Body;
+use crate::body::BodySource;
+use crate::body::Multipart;
+use crate::cache::EtagCache;
+use crate::date;
+use crate::date::ServerDate;
+use crate::inflate::Encoding;
+use crate::inflate::Inflate;
+use crate::inflate::Inflater;
+use crate::auth::Authenticator;
+use crate::auth::Error as AuthError;
+use crate::retry::RetryPolicy;
+use crate::tls;
+use crate::tls::Credentials as TlsCredentials;
+use crate::tls::Error as TlsOpenError;
+use crate::tls::TlsBufferPool;
+use crate::tls::TlsStream;
+use crate::tls::Verification;
+use crate::tls::VerificationError;
 //use esp_mbedtls::Tls;
 
 /// Response size
 const RESPONSE_SIZE: usize = 4096;
 
+/// Maximal size of a serialized JSON request body
+const JSON_BODY_SIZE: usize = 1024;
+
+/// Size of the buffer for response status line and headers
+const HEADER_BUFFER_SIZE: usize = 2048;
+
+/// Size of each TCP socket buffer
+pub const TCP_BUFFER_SIZE: usize = 4096;
+
+/// Maximal length of a host name
+const HOST_SIZE: usize = 64;
+
+/// Maximal length of a URL a request is redirected to
+const URL_SIZE: usize = 256;
+
+/// Timeout for requests that do not set one
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Size of the chunks passed to streaming callbacks
+const CHUNK_SIZE: usize = 512;
+
+/// Size of the response body excerpt kept in status errors
+const STATUS_BODY_SIZE: usize = 128;
+
+/// Maximal length of the content type of chunked bodies
+const CONTENT_TYPE_SIZE: usize = 128;
+
+/// Maximal number of request headers, including authentication headers
+const MAX_REQUEST_HEADERS: usize = 16;
+
+/// Maximal number of response headers that are kept
+const MAX_HEADERS: usize = 16;
+
+/// Maximal length of a response header name
+const HEADER_NAME_SIZE: usize = 32;
+
+/// Maximal length of a response header value
+const HEADER_VALUE_SIZE: usize = 128;
+
+/// Response headers the client and its users rely on, kept in place of
+/// others when there are too many
+const IMPORTANT_HEADERS: &[&str] = &[
+    "Connection",
+    "Content-Encoding",
+    "Date",
+    "ETag",
+    "Last-Modified",
+    "Retry-After",
+    "Sec-WebSocket-Accept",
+    "Upgrade",
+];
+
 /// HTTP client
 ///
 /// This trait exists to be extended with requests to specific sites, like in
 /// [`WorldTimeApiClient`][crate::worldtimeapi::WorldTimeApiClient].
 pub trait ClientTrait {
+    /// Send an HTTP request and stream the response body
+    ///
+    /// The body is passed to `on_chunk` in pieces of at most [`CHUNK_SIZE`]
+    /// bytes as it arrives, both for `Content-Length` and chunked transfer
+    /// encoded responses, so it never needs to fit in memory.
+    #[allow(unused, async_fn_in_trait)]
+    async fn request_stream<F>(&mut self, request: &Request<'_>, on_chunk: F) -> Result<ResponseHead, Error>
+    where
+        F: FnMut(&[u8]) -> Result<(), Error>;
+
+    /// Return the `Date` of the last response that had a valid one
+    ///
+    /// Clients that do not record it return `None`.
+    #[allow(unused)]
+    fn server_date(&self) -> Option<ServerDate> {
+        None
+    }
+
     /// Send an HTTP request
+    ///
+    /// Responses are returned whatever their status, use
+    /// [`Response::error_for_status`] to turn non-successful ones into
+    /// errors.
+    #[allow(unused, async_fn_in_trait)]
+    async fn request(&mut self, request: &Request<'_>) -> Result<Response, Error> {
+        collect_response(self, request).await
+    }
+
+    /// Send an HTTP GET request
+    ///
+    /// Fail with [`Error::Status`] unless the response status is 2xx.
+    #[allow(unused, async_fn_in_trait)]
+    async fn get_request(&mut self, url: &str, timeout: Duration) -> Result<Vec<u8, RESPONSE_SIZE>, Error> {
+        let request = Request::get(url).with_timeout(timeout);
+        let response = self.request(&request).await?.error_for_status()?;
+        Ok(response.body)
+    }
+
+    /// Send an HTTP POST request
+    ///
+    /// Fail with [`Error::Status`] unless the response status is 2xx.
+    #[allow(unused, async_fn_in_trait)]
+    async fn post_request(&mut self, url: &str, ct: ContentType, body: &[u8], timeout: Duration) -> Result<Vec<u8, RESPONSE_SIZE>, Error> {
+        let request = Request::post(url).with_body(ct, body).with_timeout(timeout);
+        let response = self.request(&request).await?.error_for_status()?;
+        Ok(response.body)
+    }
+
+    /// Send an HTTP POST request with a body read from a source
+    ///
+    /// The body is sent with chunked transfer encoding, so it never needs to
+    /// fit in memory.
+    /// Fail with [`Error::Status`] unless the response status is 2xx.
+    #[allow(unused, async_fn_in_trait)]
+    async fn upload(&mut self, url: &str, ct: ContentType, source: &dyn BodySource, timeout: Duration) -> Result<Vec<u8, RESPONSE_SIZE>, Error> {
+        let request = Request::post(url).with_stream(ct, source).with_timeout(timeout);
+        let response = self.request(&request).await?.error_for_status()?;
+        Ok(response.body)
+    }
+
+    /// Send an HTTP POST request with a `multipart/form-data` body
+    ///
+    /// See [`ClientTrait::upload`].
+    #[allow(unused, async_fn_in_trait)]
+    async fn post_multipart(&mut self, url: &str, multipart: &Multipart<'_>, timeout: Duration) -> Result<Vec<u8, RESPONSE_SIZE>, Error> {
+        let request = Request::post(url).with_multipart(multipart).with_timeout(timeout);
+        let response = self.request(&request).await?.error_for_status()?;
+        Ok(response.body)
+    }
+
+    /// Send an HTTP GET request and stream the response body
+    ///
+    /// See [`ClientTrait::request_stream`].
+    /// Return the total number of bytes in the body, or fail with
+    /// [`Error::Status`] without streaming anything unless the status is 2xx.
+    #[allow(unused, async_fn_in_trait)]
+    async fn get_stream<F>(&mut self, url: &str, timeout: Duration, on_chunk: F) -> Result<usize, Error>
+    where
+        F: FnMut(&[u8]) -> Result<(), Error>,
+    {
+        let request = Request::get(url).with_timeout(timeout).with_success_only();
+        stream_counting(self, &request, on_chunk).await
+    }
+
+    /// Send an HTTP POST request and stream the response body
+    ///
+    /// See [`ClientTrait::get_stream`].
+    #[allow(unused, async_fn_in_trait)]
+    async fn post_stream<F>(&mut self, url: &str, ct: ContentType, body: &[u8], timeout: Duration, on_chunk: F) -> Result<usize, Error>
+    where
+        F: FnMut(&[u8]) -> Result<(), Error>,
+    {
+        let request = Request::post(url)
+            .with_body(ct, body)
+            .with_timeout(timeout)
+            .with_success_only();
+        stream_counting(self, &request, on_chunk).await
+    }
+
+    /// Send a conditional HTTP GET request
+    ///
+    /// Validators stored in `cache` for this URL are sent as `If-None-Match`
+    /// and `If-Modified-Since`, and the validators of a successful response
+    /// replace them.  Return [`Conditional::NotModified`] if the server
+    /// answers 304 Not Modified, or fail with [`Error::Status`] unless the
+    /// response status is 2xx.
+    #[allow(unused, async_fn_in_trait)]
+    async fn get_conditional<const N: usize>(
+        &mut self,
+        url: &str,
+        cache: &mut EtagCache<N>,
+        timeout: Duration,
+    ) -> Result<Conditional, Error> {
+        let validators = cache.get(url).cloned().unwrap_or_default();
+
+        let mut headers: Vec<(&str, &str), 2> = Vec::new();
+        if let Some(etag) = &validators.etag {
+            let _ = headers.push(("If-None-Match", etag.as_str()));
+        }
+        if let Some(last_modified) = &validators.last_modified {
+            let _ = headers.push(("If-Modified-Since", last_modified.as_str()));
+        }
+
+        let request = Request::get(url).with_headers(&headers).with_timeout(timeout);
+        let response = self.request(&request).await?;
+
+        if response.status == 304 {
+            debug!("{url} not modified");
+            return Ok(Conditional::NotModified);
+        }
+
+        let response = response.error_for_status()?;
+        cache.store(url, &response.headers);
+        Ok(Conditional::Modified(response))
+    }
+
+    /// Send an HTTP GET request and deserialize its JSON response
+    ///
+    /// Fail with [`Error::Status`] unless the response status is 2xx.
     #[allow(unused, async_fn_in_trait)]
-    async fn get_request(&mut self, url: &str, timeout: Duration) -> Result<Vec<u8, RESPONSE_SIZE>, Error>;
+    async fn get_json<T>(&mut self, url: &str, timeout: Duration) -> Result<T, Error>
+    where
+        T: DeserializeOwned,
+    {
+        let body = self.get_request(url, timeout).await?;
+        from_json(&body)
+    }
+
+    /// Send a value as JSON in an HTTP POST request and deserialize its JSON
+    /// response
+    ///
+    /// The serialized value must fit in [`JSON_BODY_SIZE`] bytes.
+    /// Fail with [`Error::Status`] unless the response status is 2xx.
     #[allow(unused, async_fn_in_trait)]
-    async fn post_request(&mut self, url: &str, ct: ContentType, body: &[u8]) -> Result<Vec<u8, RESPONSE_SIZE>, Error>;
+    async fn post_json<B, T>(&mut self, url: &str, body: &B, timeout: Duration) -> Result<T, Error>
+    where
+        B: Serialize + ?Sized,
+        T: DeserializeOwned,
+    {
+        let mut buffer = [0_u8; JSON_BODY_SIZE];
+        let length = serde_json_core::to_slice(body, &mut buffer)?;
+        debug!("Serialized {length} bytes of JSON");
+
+        let body = self
+            .post_request(url, ContentType::ApplicationJson, &buffer[..length], timeout)
+            .await?;
+        from_json(&body)
+    }
+}
+
+/// Deserialize a JSON response body
+#[allow(clippy::result_large_err)]
+fn from_json<T>(body: &[u8]) -> Result<T, Error>
+where
+    T: DeserializeOwned,
+{
+    let (value, _length) = serde_json_core::from_slice(body)?;
+    Ok(value)
+}
+
+/// Send a request and collect its response body
+#[allow(clippy::result_large_err)]
+async fn collect_response<C>(client: &mut C, request: &Request<'_>) -> Result<Response, Error>
+where
+    C: ClientTrait + ?Sized,
+{
+    let mut body = Vec::new();
+    let head = client
+        .request_stream(request, |chunk| {
+            body.extend_from_slice(chunk)
+                .map_err(|_| Error::ResponseTooLarge)
+        })
+        .await?;
+
+    debug!("Read {} bytes", body.len());
+
+    Ok(Response {
+        status: head.status,
+        headers: head.headers,
+        body,
+    })
+}
+
+/// Stream a response body and count its bytes
+///
+/// Statuses are checked again, for clients ignoring
+/// [`Request::with_success_only`].
+#[allow(clippy::result_large_err)]
+async fn stream_counting<C, F>(client: &mut C, request: &Request<'_>, mut on_chunk: F) -> Result<usize, Error>
+where
+    C: ClientTrait + ?Sized,
+    F: FnMut(&[u8]) -> Result<(), Error>,
+{
+    let mut total = 0;
+    client
+        .request_stream(request, |chunk| {
+            total += chunk.len();
+            on_chunk(chunk)
+        })
+        .await?
+        .error_for_status()?;
+    Ok(total)
+}
+
+/// An HTTP request
+#[derive(Clone, Copy, Debug)]
+pub struct Request<'a> {
+    /// Request method
+    method: Method,
+
+    /// Request URL
+    url: &'a str,
+
+    /// Additional request headers
+    headers: &'a [(&'a str, &'a str)],
+
+    /// Request body
+    body: Option<Body<'a>>,
+
+    /// Timeout for the whole exchange
+    timeout: Option<Duration>,
+
+    /// Authenticator adding headers when the request is sent
+    authenticator: Option<&'a dyn Authenticator>,
+
+    /// Whether only bodies of successful responses are streamed
+    success_only: bool,
+}
+
+impl<'a> Request<'a> {
+    /// Create a new request without headers nor body
+    pub fn new(method: Method, url: &'a str) -> Self {
+        Self {
+            method,
+            url,
+            headers: &[],
+            body: None,
+            timeout: None,
+            authenticator: None,
+            success_only: false,
+        }
+    }
+
+    /// Create a new GET request
+    pub fn get(url: &'a str) -> Self {
+        Self::new(Method::GET, url)
+    }
+
+    /// Create a new POST request
+    pub fn post(url: &'a str) -> Self {
+        Self::new(Method::POST, url)
+    }
+
+    /// Set additional request headers, such as `Authorization`
+    #[must_use]
+    pub fn with_headers(self, headers: &'a [(&'a str, &'a str)]) -> Self {
+        Self { headers, ..self }
+    }
+
+    /// Set the request body
+    #[must_use]
+    pub fn with_body(self, content_type: ContentType, body: &'a [u8]) -> Self {
+        Self {
+            body: Some(Body::Bytes(mime_type(&content_type), body)),
+            ..self
+        }
+    }
+
+    /// Set a request body read from a source while sending
+    ///
+    /// It is sent with chunked transfer encoding.
+    #[allow(unused)]
+    #[must_use]
+    pub fn with_stream(self, content_type: ContentType, source: &'a dyn BodySource) -> Self {
+        Self {
+            body: Some(Body::Stream(mime_type(&content_type), source)),
+            ..self
+        }
+    }
+
+    /// Set a `multipart/form-data` request body
+    ///
+    /// It is sent with chunked transfer encoding.
+    #[allow(unused)]
+    #[must_use]
+    pub fn with_multipart(self, multipart: &'a Multipart<'a>) -> Self {
+        Self {
+            body: Some(Body::Multipart(multipart)),
+            ..self
+        }
+    }
+
+    /// Authenticate the request
+    ///
+    /// The authenticator adds its headers every time the request is sent,
+    /// including retries and redirects to the same host.
+    #[allow(unused)]
+    #[must_use]
+    pub fn with_authenticator(self, authenticator: &'a dyn Authenticator) -> Self {
+        Self {
+            authenticator: Some(authenticator),
+            ..self
+        }
+    }
+
+    /// Set a timeout
+    ///
+    /// It covers the whole exchange, from DNS resolution to reading the last
+    /// byte of the response body.  Requests without a timeout use
+    /// [`DEFAULT_TIMEOUT`].
+    #[must_use]
+    pub fn with_timeout(self, timeout: Duration) -> Self {
+        Self {
+            timeout: Some(timeout),
+            ..self
+        }
+    }
+
+    /// Only stream the body of successful responses
+    ///
+    /// The status is checked as soon as the headers arrive.  Bodies of
+    /// non-2xx responses are then read but never streamed, and the request
+    /// fails with [`Error::Status`] keeping their beginning.
+    #[must_use]
+    pub fn with_success_only(self) -> Self {
+        Self {
+            success_only: true,
+            ..self
+        }
+    }
+}
+
+/// Status and headers of an HTTP response
+#[derive(Clone, Debug)]
+pub struct ResponseHead {
+    /// Status code
+    pub status: u16,
+
+    /// Response headers
+    pub headers: Headers,
+
+    /// When status and headers were received
+    pub received_at: Instant,
+}
+
+#[allow(clippy::result_large_err)]
+impl ResponseHead {
+    /// Check whether the status is 2xx
+    pub fn is_success(&self) -> bool {
+        is_success(self.status)
+    }
+
+    /// Turn a non-successful status into an error
+    pub fn error_for_status(self) -> Result<Self, Error> {
+        if self.is_success() {
+            Ok(self)
+        } else {
+            Err(Error::Status(self.status, Vec::new()))
+        }
+    }
+}
+
+/// Result of a conditional request
+#[derive(Clone, Debug)]
+#[allow(clippy::large_enum_variant)]
+pub enum Conditional {
+    /// Resource changed since the cached response, or was not cached
+    Modified(Response),
+
+    /// Resource did not change since the cached response
+    NotModified,
+}
+
+/// An HTTP response
+#[derive(Clone, Debug)]
+pub struct Response {
+    /// Status code
+    pub status: u16,
+
+    /// Response headers
+    pub headers: Headers,
+
+    /// Response body
+    pub body: Vec<u8, RESPONSE_SIZE>,
+}
+
+#[allow(clippy::result_large_err)]
+impl Response {
+    /// Check whether the status is 2xx
+    pub fn is_success(&self) -> bool {
+        is_success(self.status)
+    }
+
+    /// Turn a non-successful status into an error
+    ///
+    /// The error keeps the beginning of the body, which usually explains
+    /// what went wrong.
+    pub fn error_for_status(self) -> Result<Self, Error> {
+        if self.is_success() {
+            Ok(self)
+        } else {
+            let length = self.body.len().min(STATUS_BODY_SIZE);
+            let excerpt = Vec::from_slice(&self.body[..length]).unwrap_or_default();
+            Err(Error::Status(self.status, excerpt))
+        }
+    }
+}
+
+/// Check whether a status is 2xx
+fn is_success(status: u16) -> bool {
+    (200..300).contains(&status)
+}
+
+/// Check whether a status redirects to another URL
+fn is_redirect(status: u16) -> bool {
+    matches!(status, 301 | 302 | 303 | 307 | 308)
+}
+
+/// Check whether a response header is one of [`IMPORTANT_HEADERS`]
+fn is_important(name: &str) -> bool {
+    IMPORTANT_HEADERS
+        .iter()
+        .any(|important| important.eq_ignore_ascii_case(name))
+}
+
+/// Headers of an HTTP response
+///
+/// At most [`MAX_HEADERS`] headers are kept, and headers with names or
+/// values too long to be stored are skipped.  Headers listed in
+/// [`IMPORTANT_HEADERS`] are never skipped: they take the place of other
+/// headers, and the response fails if they cannot be stored.
+///
+/// `Location` is kept apart, in room for a whole URL.  When it is too long,
+/// only following the redirect fails.
+#[derive(Clone, Debug, Default)]
+pub struct Headers {
+    /// Headers other than `Location`
+    headers: Vec<(String<HEADER_NAME_SIZE>, String<HEADER_VALUE_SIZE>), MAX_HEADERS>,
+
+    /// Value of the `Location` header
+    location: Option<String<URL_SIZE>>,
+
+    /// Whether a `Location` header was too long to be stored
+    location_too_long: bool,
+}
+
+#[allow(clippy::result_large_err)]
+impl Headers {
+    /// Return the value of a header, ignoring case in its name
+    #[allow(unused)]
+    pub fn get(&self, name: &str) -> Option<&str> {
+        if name.eq_ignore_ascii_case("Location") {
+            return self.location.as_deref();
+        }
+
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Iterate over all headers
+    #[allow(unused)]
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.headers
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+            .chain(self.location.as_deref().map(|location| ("Location", location)))
+    }
+
+    /// Check whether a `Location` header was received, even one too long
+    fn has_location(&self) -> bool {
+        self.location.is_some() || self.location_too_long
+    }
+
+    /// Store a header
+    pub(crate) fn push(&mut self, name: &str, value: &[u8]) -> Result<(), Error> {
+        if name.eq_ignore_ascii_case("Location") {
+            self.location = from_utf8(value)
+                .ok()
+                .and_then(|value| String::try_from(value.trim()).ok());
+            self.location_too_long = self.location.is_none();
+            if self.location_too_long {
+                warn!("Location header cannot be stored");
+            }
+            return Ok(());
+        }
+
+        let important = is_important(name);
+
+        let header = from_utf8(value).ok().and_then(|value| {
+            let name = String::try_from(name).ok()?;
+            let value = String::try_from(value.trim()).ok()?;
+            Some((name, value))
+        });
+        let Some(header) = header else {
+            if important {
+                warn!("Header {name} cannot be stored");
+                return Err(Error::HeaderNotStorable);
+            }
+            warn!("Skip header {name}, not storable");
+            return Ok(());
+        };
+
+        if self.headers.is_full() {
+            if !important {
+                warn!("Skip header {name}, too many headers");
+                return Ok(());
+            }
+            let Some(index) = self.headers.iter().rposition(|(key, _)| !is_important(key)) else {
+                warn!("Header {name} cannot be stored, too many headers");
+                return Err(Error::TooManyHeaders);
+            };
+            let (skipped, _) = self.headers.remove(index);
+            warn!("Skip header {skipped} to keep header {name}, too many headers");
+        }
+
+        // Room was made above
+        let _ = self.headers.push(header);
+        Ok(())
+    }
 }
 
 /// HTTP client
+///
+/// The URL scheme selects between plain HTTP and HTTPS, and URLs may carry
+/// an explicit port.
+///
+/// With [`Client::with_keep_alive`], the connection is kept open after a
+/// request and reused by the next request to the same host.
+///
+/// The client is generic over the transport, so that it runs both on the
+/// embassy-net stack, with `TcpClient` and `DnsSocket`, and on a host, with
+/// the std sockets of [`crate::host`].
 //pub struct Client<'a,'d> {
-pub struct Client<'a> {
-    /// Wifi stack
-    stack: Stack<'a>,
+pub struct Client<'a, T, D, R>
+where
+    T: TcpConnect + 'a,
+{
+    /// TCP client
+    tcp_client: &'a T,
+
+    /// DNS resolver
+    dns: D,
+
+    /// Pool of buffers for TLS records
+    tls_buffers: &'a dyn TlsBufferPool,
 
     /// Random numbers generator
-    rng: RngWrapper,
+    rng: R,
+
+    /// How to verify server certificates
+    verification: Verification,
+
+    /// How to authenticate to servers
+    credentials: TlsCredentials,
+
+    /// When to retry failed requests
+    retry_policy: RetryPolicy,
+
+    /// Authenticator of requests that have none
+    authenticator: Option<&'a dyn Authenticator>,
 
     //tls: esp_mbedtls::Tls<'d>,
 
-    /// TCP client state
-    tcp_client_state: TcpClientState<1, 4096, 4096>,
+    /// How long an idle connection is kept open, if at all
+    keep_alive: Option<Duration>,
+
+    /// Maximal number of redirects followed by a request
+    max_redirects: u8,
 
-    // Buffer for received TLS data
-    read_record_buffer: [u8; 16640],
+    /// Buffers for inflating compressed responses, if enabled
+    inflater: Option<&'a mut Inflater>,
 
-    // Buffer for transmitted TLS data
-    write_record_buffer: [u8; 16640],
+    /// Connection kept open from the last request
+    connection: Option<IdleConnection<'a, T::Connection<'a>>>,
+
+    /// `Date` of the last response that had a valid one
+    server_date: Option<ServerDate>,
+
+    /// Buffer for response status line and headers
+    header_buffer: [u8; HEADER_BUFFER_SIZE],
 }
 
 //impl<'a,'d> Client<'a,'d> {
-impl<'a> Client<'a> {
+#[allow(clippy::result_large_err)]
+impl<'a, T, D, R> Client<'a, T, D, R>
+where
+    T: TcpConnect + 'a,
+    D: Dns,
+    R: Rng,
+{
     /// Create a new client
-    pub fn new(stack: Stack<'a>, rng: RngWrapper/*tls: esp_mbedtls::Tls<'d>*/) -> Self { //, rng: RngWrapper
-        debug!("Create TCP client state");
-        let tcp_client_state = TcpClientState::<1, 4096, 4096>::new();
-
+    ///
+    /// Server certificates are checked according to `verification`, and the
+    /// client authenticates itself with `credentials`, if any.
+    ///
+    /// The TCP client and TLS buffers are borrowed rather than owned, so
+    /// that connections can outlive a single request, and so that several
+    /// clients can share them.
+    pub fn new(
+        tcp_client: &'a T,
+        dns: D,
+        tls_buffers: &'a dyn TlsBufferPool,
+        rng: R,
+        verification: Verification,
+        credentials: TlsCredentials,
+        /*tls: esp_mbedtls::Tls<'d>*/
+    ) -> Self {
         Self {
-            stack,
+            tcp_client,
+            dns,
+            tls_buffers,
             rng,
+            verification,
+            credentials,
+            retry_policy: RetryPolicy::default(),
+            authenticator: None,
             //tls,
-            tcp_client_state,
+            keep_alive: None,
+            max_redirects: 0,
+            inflater: None,
+            connection: None,
+            server_date: None,
+            header_buffer: [0_u8; HEADER_BUFFER_SIZE],
+        }
+    }
+
+    /// Set the policy for retrying failed requests
+    ///
+    /// By default requests are retried according to
+    /// [`RetryPolicy::default`].
+    #[allow(unused)]
+    #[must_use]
+    pub fn with_retry_policy(self, retry_policy: RetryPolicy) -> Self {
+        Self { retry_policy, ..self }
+    }
 
-            read_record_buffer: [0_u8; 16640],
-            write_record_buffer: [0_u8; 16640],
+    /// Authenticate every request
+    ///
+    /// This applies to the requests built by the helpers of [`ClientTrait`]
+    /// too.  A request with its own authenticator, set with
+    /// [`Request::with_authenticator`], uses that one instead.
+    #[allow(unused)]
+    #[must_use]
+    pub fn with_authenticator(self, authenticator: &'a dyn Authenticator) -> Self {
+        Self {
+            authenticator: Some(authenticator),
+            ..self
         }
     }
-}
 
-impl ClientTrait for Client<'static> {
-    async fn get_request(&mut self, url: &str, timeout: Duration) -> Result<Vec<u8, RESPONSE_SIZE>, Error> {
-        debug!("Send HTTPs request to {url}");
+    /// Keep connections open between requests
+    ///
+    /// A connection is reused by the next request to the same scheme, host
+    /// and port, unless it has been idle for longer than `idle_timeout`.
+    /// Since there is a single TCP socket, a request to another host closes
+    /// the idle connection.
+    #[allow(unused)]
+    #[must_use]
+    pub fn with_keep_alive(self, idle_timeout: Duration) -> Self {
+        Self {
+            keep_alive: Some(idle_timeout),
+            ..self
+        }
+    }
 
-        debug!("Create DNS socket");
-        let dns_socket = DnsSocket::new(self.stack);
+    /// Follow redirects
+    ///
+    /// A request follows at most `max_redirects` redirects, after which it
+    /// fails with [`Error::TooManyRedirects`].  By default redirects are
+    /// returned as normal responses.
+    ///
+    /// Statuses 307 and 308 keep method and body.  301 and 302 turn a POST
+    /// into a body-less GET, as browsers do, and 303 turns any method but
+    /// HEAD into one.  Additional headers, such as `Authorization`, are
+    /// dropped when redirected to another host, and so is the authenticator.
+    /// Redirects to another scheme are refused unless server certificates
+    /// are not verified.
+    #[allow(unused)]
+    #[must_use]
+    pub fn with_redirects(self, max_redirects: u8) -> Self {
+        Self { max_redirects, ..self }
+    }
 
-        let seed = self.rng.next_u64();
+    /// Accept compressed responses
+    ///
+    /// Requests send `Accept-Encoding: gzip, deflate`, and compressed
+    /// response bodies are inflated as they arrive, also when streamed.
+    /// Response headers are left as sent by the server, including
+    /// `Content-Encoding`.
+    #[allow(unused)]
+    #[must_use]
+    pub fn with_decompression(self, inflater: &'a mut Inflater) -> Self {
+        Self {
+            inflater: Some(inflater),
+            ..self
+        }
+    }
 
-        /*
-        let tls_config = TlsConfig::new(
-            reqwless::TlsVersion::Tls1_3,
-            reqwless::Certificates {
-                ca_chain: None,
-                certificate: None,
-                private_key: None,
-                password: None,
-                //ca_chain: reqwless::X509::pem(concat!(include_str!(".././certs/www.google.com.pem"), "\0").as_bytes()).ok(),
-                //certificate: reqwless::X509::pem(concat!(include_str!(".././certs/certificate.pem"), "\0").as_bytes()).ok(),
-                //private_key: reqwless::X509::pem(concat!(include_str!(".././certs/private_key.pem"), "\0").as_bytes()).ok(), 
-                //..Default::default()
-            },
-            self.tls.reference(), // Will use hardware acceleration
-        );
-        */
+    /// Return the `Date` of the last response that had a valid one
+    ///
+    /// Every response is checked, so this gives the current time to about a
+    /// second without a dedicated time service.
+    #[allow(unused)]
+    pub fn server_date(&self) -> Option<ServerDate> {
+        self.server_date
+    }
 
-        let tls_config = TlsConfig::new(
-            seed,
-            &mut self.read_record_buffer,
-            &mut self.write_record_buffer,
-            TlsVerify::None,
-        );
-        
-        /*
-        let tls_config;
-        if let Some(cert) = cert {
-            if let Some(key) = key {
-                tls_config = TlsConfig::new(
-                    seed,
-                    &mut self.read_record_buffer,
-                    &mut self.write_record_buffer,
-                    TlsVerify::Psk { identity: cert, psk: key },
-                );
-            }else{
-                tls_config = TlsConfig::new(
-                    seed,
-                    &mut self.read_record_buffer,
-                    &mut self.write_record_buffer,
-                    TlsVerify::None,
-                );
+    /// Close the idle connection, if any
+    #[allow(unused)]
+    pub fn close(&mut self) {
+        if self.connection.take().is_some() {
+            debug!("Close idle connection");
+        }
+    }
+
+    /// Send a request asking to switch protocols, and take over its connection
+    ///
+    /// The request must carry the `Upgrade` and `Connection` headers of the
+    /// new protocol, and no body.  It is always sent over a new connection,
+    /// which is returned if the server responds 101 Switching Protocols, and
+    /// is never kept for later requests.  Redirects are not followed.
+    #[allow(unused)]
+    pub async fn upgrade(
+        &mut self,
+        request: &Request<'_>,
+    ) -> Result<(ResponseHead, Connection<'a, T::Connection<'a>>), Error> {
+        let timeout = request.timeout.unwrap_or(DEFAULT_TIMEOUT);
+        self.switch_protocols(request).with_timeout(timeout).await?
+    }
+
+    /// Send an upgrade request over a new connection and read the response
+    /// head
+    async fn switch_protocols(
+        &mut self,
+        request: &Request<'_>,
+    ) -> Result<(ResponseHead, Connection<'a, T::Connection<'a>>), Error> {
+        let url = Url::parse(request.url).map_err(ReqlessError::InvalidUrl)?;
+        let target = Target::from_url(&url)?;
+        let mut connection = self.connect(&target).await?;
+
+        debug!("Send HTTP {:?} upgrade request to {}", request.method, request.url);
+
+        let mut auth_headers = AuthHeaders::default();
+        if let Some(authenticator) = request.authenticator.or(self.authenticator) {
+            authenticator.authenticate(request.method, url.path(), None, &mut auth_headers)?;
+        }
+
+        let mut headers: Vec<(&str, &str), MAX_REQUEST_HEADERS> = Vec::new();
+        for header in request.headers.iter().copied().chain(auth_headers.iter()) {
+            headers.push(header).map_err(|_| Error::TooManyHeaders)?;
+        }
+
+        ReqlessRequest::new(request.method, url.path())
+            .host(url.host())
+            .headers(&headers)
+            .build()
+            .write_header(&mut connection)
+            .await?;
+        flush(&mut connection).await?;
+
+        let head = read_head(&mut connection, &mut self.header_buffer).await?;
+        self.record_date(&head);
+        if head.status != 101 {
+            warn!("Server refused to switch protocols: {}", head.status);
+            return Err(Error::Status(head.status, Vec::new()));
+        }
+
+        Ok((head, connection))
+    }
+
+    /// Send a request following redirects, and pass the final response body
+    /// to a callback
+    async fn follow<F>(&mut self, request: &Request<'_>, on_chunk: &mut F) -> Result<ResponseHead, Error>
+    where
+        F: FnMut(&[u8]) -> Result<(), Error>,
+    {
+        let mut location: Option<String<URL_SIZE>> = None;
+        let mut method = request.method;
+        let mut headers = request.headers;
+        let mut authenticator = request.authenticator.or(self.authenticator);
+        let mut body = request.body;
+        let mut hops = 0;
+
+        loop {
+            let current = Request {
+                method,
+                url: location.as_deref().unwrap_or(request.url),
+                headers,
+                body,
+                timeout: request.timeout,
+                authenticator,
+                success_only: request.success_only,
+            };
+
+            let follow_redirects = self.max_redirects > 0;
+            let (head, kept_back) = self.send(&current, follow_redirects, on_chunk).await?;
+            self.record_date(&head);
+            if follow_redirects && is_redirect(head.status) && head.headers.location_too_long {
+                warn!("Redirect from {} is too long to follow", current.url);
+                return Err(Error::UrlTooLong);
+            }
+            if let Some(excerpt) = kept_back {
+                return Err(Error::Status(head.status, excerpt));
+            }
+
+            let next = match head.headers.get("Location") {
+                Some(next) if follow_redirects && is_redirect(head.status) => next,
+                _ => return Ok(head),
+            };
+
+            if hops >= self.max_redirects {
+                warn!("Too many redirects from {}", request.url);
+                return Err(Error::TooManyRedirects);
+            }
+            hops += 1;
+
+            let next = resolve_location(current.url, next)?;
+            debug!("Redirect {} to {next}", head.status);
+
+            let from = Url::parse(current.url).map_err(ReqlessError::InvalidUrl)?;
+            let to = Url::parse(&next).map_err(ReqlessError::InvalidUrl)?;
+            if from.scheme() != to.scheme() && !matches!(self.verification, Verification::None) {
+                warn!("Refuse redirect from {} to {next}", current.url);
+                return Err(Error::RedirectRefused);
+            }
+            if !from.host().eq_ignore_ascii_case(to.host()) {
+                headers = &[];
+                authenticator = None;
             }
-        }else{
-            tls_config = TlsConfig::new(
-                seed,
-                &mut self.read_record_buffer,
-                &mut self.write_record_buffer,
-                TlsVerify::None,
-            );
-        }
-        */
-        
-
-        debug!("Create TCP client");
-        let tcp_client = TcpClient::new(self.stack, &self.tcp_client_state);
-
-        debug!("Create HTTP client");
-        let mut client = HttpClient::new_with_tls(&tcp_client, &dns_socket, tls_config);
-        //let mut client = HttpClient::new(&tcp_client, &dns_socket);
-
-        debug!("Create HTTP request");
-        let mut buffer = [0_u8; 4096];
-        let mut request = client.request(Method::GET, url)
-            .with_timeout(timeout)
-            .await??;
 
-        debug!("Send HTTP request");
-        let response = request.send(&mut buffer).await?;
+            let keeps_method = matches!(head.status, 307 | 308)
+                || method == Method::HEAD
+                || (head.status != 303 && method != Method::POST);
+            if !keeps_method {
+                method = Method::GET;
+                body = None;
+            }
 
-        debug!("Response status: {:?}", response.status);
+            location = Some(next);
+        }
+    }
 
-        let buffer = response.body().read_to_end().await?;
+    /// Send a request and pass its response body to a callback
+    ///
+    /// When `follow_redirects` is set, the body of redirect responses is
+    /// discarded.
+    ///
+    /// This covers the whole exchange: DNS resolution, TCP connection, TLS
+    /// handshake for `https` URLs, sending the request and reading the
+    /// response.
+    ///
+    /// If a reused connection fails before any body was received, for
+    /// instance because the server closed it in the meantime, the request is
+    /// sent again over a new connection.
+    ///
+    /// The beginning of a body kept back from the callback is returned along
+    /// with the head.
+    async fn send<F>(&mut self, request: &Request<'_>, follow_redirects: bool, on_chunk: &mut F) -> Result<Exchanged, Error>
+    where
+        F: FnMut(&[u8]) -> Result<(), Error>,
+    {
+        let url = Url::parse(request.url).map_err(ReqlessError::InvalidUrl)?;
+        let target = Target::from_url(&url)?;
 
-        debug!("Read {} bytes", buffer.len());
+        if let Some(mut connection) = self.reuse(&target) {
+            debug!("Reuse connection to {}", target.host);
 
-        let output =
-            Vec::<u8, RESPONSE_SIZE>::from_slice(buffer).map_err(|()| Error::ResponseTooLarge)?;
+            let mut received = false;
+            let mut on_chunk_received = |chunk: &[u8]| {
+                received = true;
+                on_chunk(chunk)
+            };
+            let result = exchange(
+                &mut connection,
+                request,
+                &url,
+                &mut self.header_buffer,
+                self.inflater.as_deref_mut(),
+                follow_redirects,
+                &mut on_chunk_received,
+            )
+            .await;
+
+            match result {
+                Ok((head, kept_back)) => {
+                    self.keep(target, connection, &head);
+                    return Ok((head, kept_back));
+                }
+                Err(error) if !received && error.is_transient() => {
+                    warn!("Reused connection failed: {error:?}, reconnect");
+                }
+                Err(error) => return Err(error),
+            }
+        }
 
-        Ok(output)
+        let mut connection = self.connect(&target).await?;
+        let (head, kept_back) = exchange(
+            &mut connection,
+            request,
+            &url,
+            &mut self.header_buffer,
+            self.inflater.as_deref_mut(),
+            follow_redirects,
+            on_chunk,
+        )
+        .await?;
+        self.keep(target, connection, &head);
+        Ok((head, kept_back))
     }
 
-    async fn post_request(&mut self, url: &str, ct: ContentType, body: &[u8]) -> Result<Vec<u8, RESPONSE_SIZE>, Error> {
-        debug!("Send HTTPs request to {url}");
+    /// Open a new connection
+    async fn connect(&mut self, target: &Target) -> Result<Connection<'a, T::Connection<'a>>, Error> {
+        // Drop the idle connection to release its socket
+        self.close();
 
-        debug!("Create DNS socket");
-        let dns_socket = DnsSocket::new(self.stack);
+        debug!("Resolve {}", target.host);
+        let address = self
+            .dns
+            .get_host_by_name(&target.host, AddrType::Either)
+            .await
+            .map_err(|error| {
+                warn!("Failed to resolve {}: {error:?}", target.host);
+                Error::Dns
+            })?;
+        let remote = SocketAddr::new(address, target.port);
+
+        debug!("Open TCP connection to {remote}");
+        let tcp_client = self.tcp_client;
+        let socket = tcp_client
+            .connect(remote)
+            .await
+            .map_err(|error| Error::TcpConnect(error.kind()))?;
+
+        if !target.https {
+            return Ok(Connection::Plain(socket));
+        }
 
         let seed = self.rng.next_u64();
-        let tls_config = TlsConfig::new(
+        let stream = tls::open(
+            socket,
+            &target.host,
+            self.verification,
+            self.credentials,
             seed,
-            &mut self.read_record_buffer,
-            &mut self.write_record_buffer,
-            TlsVerify::None,
-        );
+            self.tls_buffers,
+        )
+        .await?;
 
-        /*
-        let tls_config = TlsConfig::new(
-            seed,
-            &mut self.read_record_buffer,
-            &mut self.write_record_buffer,
-            TlsVerify::None,
-        );
-        */
-
-        /*
-        let tls_config = TlsConfig::new(
-            reqwless::TlsVersion::Tls1_2,
-            reqwless::Certificates {
-                //ca_chain: reqwless::X509::pem(CERT.as_bytes()).ok(),
-                //certificate: reqwless::X509::pem(concat!(include_str!(".././pki/ECC-secp256r1/load_client01.pem"), "\0").as_bytes()).ok(),
-                //private_key: reqwless::X509::pem(concat!(include_str!(".././pki/ECC-secp256r1/load_client01.key"), "\0").as_bytes()).ok(),
-                //password: None,
-                //ca_chain: None,
-                //ca_chain: reqwless::X509::pem(concat!(include_str!(".././pki/ECC-secp256r1/ca.pem"), "\0").as_bytes()).ok()
-                ..Default::default()
-            },
-            self.tls.reference(), // Will use hardware acceleration
-        );
-        */
+        Ok(Connection::Tls(stream))
+    }
+
+    /// Record the `Date` header of a response, if valid
+    fn record_date(&mut self, head: &ResponseHead) {
+        let Some(value) = head.headers.get("Date") else {
+            return;
+        };
+
+        match date::parse(value) {
+            Ok(date) => {
+                self.server_date = Some(ServerDate {
+                    date,
+                    received_at: head.received_at,
+                });
+            }
+            Err(_) => debug!("Ignore invalid Date header {value}"),
+        }
+    }
+
+    /// Take the idle connection if it can be reused for a target
+    fn reuse(&mut self, target: &Target) -> Option<Connection<'a, T::Connection<'a>>> {
+        let idle = self.connection.take()?;
+        let idle_timeout = self.keep_alive?;
+
+        if idle.target != *target {
+            debug!("Close idle connection to {}", idle.target.host);
+            None
+        } else if idle.since.elapsed() > idle_timeout {
+            debug!("Close connection to {}, idle for too long", idle.target.host);
+            None
+        } else {
+            Some(idle.connection)
+        }
+    }
+
+    /// Keep a connection open after a response, if possible
+    fn keep(&mut self, target: Target, connection: Connection<'a, T::Connection<'a>>, head: &ResponseHead) {
+        if self.keep_alive.is_none() {
+            return;
+        }
+
+        let closing = head
+            .headers
+            .get("Connection")
+            .is_some_and(|value| value.eq_ignore_ascii_case("close"));
+        if closing {
+            debug!("Server closes connection to {}", target.host);
+            return;
+        }
+
+        self.connection = Some(IdleConnection {
+            target,
+            connection,
+            since: Instant::now(),
+        });
+    }
+}
+
+impl<T, D, R> ClientTrait for Client<'static, T, D, R>
+where
+    T: TcpConnect + 'static,
+    D: Dns,
+    R: Rng,
+{
+    fn server_date(&self) -> Option<ServerDate> {
+        self.server_date
+    }
+
+    /// Send an HTTP request, retrying according to the retry policy
+    ///
+    /// Streamed requests are never retried, since part of the body may
+    /// already have been consumed.
+    async fn request(&mut self, request: &Request<'_>) -> Result<Response, Error> {
+        let policy = self.retry_policy;
+        let mut attempt = 1;
+
+        loop {
+            let result = collect_response(self, request).await;
+
+            let delay = if attempt >= policy.max_attempts() {
+                None
+            } else {
+                match &result {
+                    Ok(response) if policy.should_retry_status(request.method, response.status) => {
+                        policy.delay(attempt, Some(response), &mut self.rng)
+                    }
+                    Err(error) if policy.should_retry_error(request.method, error) => {
+                        policy.delay(attempt, None, &mut self.rng)
+                    }
+                    _ => None,
+                }
+            };
+
+            let Some(delay) = delay else {
+                return result;
+            };
+
+            warn!("Attempt {attempt} to {} failed, retry in {} ms", request.url, delay.as_millis());
+            Timer::after(delay).await;
+            attempt += 1;
+        }
+    }
+
+    async fn request_stream<F>(&mut self, request: &Request<'_>, mut on_chunk: F) -> Result<ResponseHead, Error>
+    where
+        F: FnMut(&[u8]) -> Result<(), Error>,
+    {
+        let timeout = request.timeout.unwrap_or(DEFAULT_TIMEOUT);
+        self.follow(request, &mut on_chunk).with_timeout(timeout).await?
+    }
+}
+
+/// Where a connection leads to
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Target {
+    /// Whether the connection uses TLS
+    https: bool,
+
+    /// Host name
+    host: String<HOST_SIZE>,
+
+    /// TCP port
+    port: u16,
+}
+
+#[allow(clippy::result_large_err)]
+impl Target {
+    /// Extract the target of a URL
+    fn from_url(url: &Url<'_>) -> Result<Self, Error> {
+        let host = String::try_from(url.host()).map_err(|_| Error::HostTooLong)?;
+
+        Ok(Self {
+            https: url.scheme() == UrlScheme::HTTPS,
+            host,
+            port: url.port_or_default(),
+        })
+    }
+}
+
+/// A connection kept open between requests
+struct IdleConnection<'a, S>
+where
+    S: Read + Write,
+{
+    /// Where the connection leads to
+    target: Target,
+
+    /// The connection
+    connection: Connection<'a, S>,
+
+    /// When the connection became idle
+    since: Instant,
+}
+
+/// A plain TCP or a TLS connection over a socket
+#[allow(clippy::large_enum_variant)]
+pub enum Connection<'a, S>
+where
+    S: Read + Write,
+{
+    /// Plain TCP connection
+    Plain(S),
+
+    /// TLS connection
+    Tls(TlsStream<'a, S>),
+}
+
+impl<S> ErrorType for Connection<'_, S>
+where
+    S: Read + Write,
+{
+    type Error = ErrorKind;
+}
+
+impl<S> Read for Connection<'_, S>
+where
+    S: Read + Write,
+{
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        match self {
+            Self::Plain(connection) => connection.read(buf).await.map_err(|error| error.kind()),
+            Self::Tls(connection) => connection.read(buf).await.map_err(|error| error.kind()),
+        }
+    }
+}
+
+impl<S> Write for Connection<'_, S>
+where
+    S: Read + Write,
+{
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        match self {
+            Self::Plain(connection) => connection.write(buf).await.map_err(|error| error.kind()),
+            Self::Tls(connection) => connection.write(buf).await.map_err(|error| error.kind()),
+        }
+    }
+
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        match self {
+            Self::Plain(connection) => connection.flush().await.map_err(|error| error.kind()),
+            Self::Tls(connection) => connection.flush().await.map_err(|error| error.kind()),
+        }
+    }
+}
+
+/// Head of a response, with the beginning of its body if it was kept back
+/// from the callback
+type Exchanged = (ResponseHead, Option<Vec<u8, STATUS_BODY_SIZE>>);
+
+/// Send a request over a connection and pass its response body to a
+/// callback
+#[allow(clippy::result_large_err)]
+async fn exchange<C, F>(
+    connection: &mut C,
+    request: &Request<'_>,
+    url: &Url<'_>,
+    header_buffer: &mut [u8],
+    inflater: Option<&mut Inflater>,
+    follow_redirects: bool,
+    on_chunk: &mut F,
+) -> Result<Exchanged, Error>
+where
+    C: Read + Write,
+    F: FnMut(&[u8]) -> Result<(), Error>,
+{
+    debug!("Send HTTP {:?} request to {}", request.method, request.url);
+
+    let mut auth_headers = AuthHeaders::default();
+    if let Some(authenticator) = request.authenticator {
+        authenticator.authenticate(request.method, url.path(), request.body.as_ref(), &mut auth_headers)?;
+    }
+
+    // Chunked bodies are written here rather than by reqwless, which
+    // would not let errors of the body source through
+    let mut content_type: String<CONTENT_TYPE_SIZE> = String::new();
+    match request.body {
+        Some(Body::Stream(mime, _)) => content_type.push_str(mime).map_err(|_| Error::Body)?,
+        Some(Body::Multipart(multipart)) => {
+            write!(content_type, "multipart/form-data; boundary={}", multipart.boundary()).map_err(|_| Error::Body)?;
+        }
+        Some(Body::Bytes(..)) | None => {}
+    }
+
+    let mut headers: Vec<(&str, &str), MAX_REQUEST_HEADERS> = Vec::new();
+    if let Some(Body::Bytes(mime, _)) = request.body {
+        headers.push(("Content-Type", mime)).map_err(|_| Error::TooManyHeaders)?;
+    } else if !content_type.is_empty() {
+        headers
+            .extend_from_slice(&[("Content-Type", content_type.as_str()), ("Transfer-Encoding", "chunked")])
+            .map_err(|_| Error::TooManyHeaders)?;
+    }
+    if inflater.is_some() {
+        headers
+            .push(("Accept-Encoding", "gzip, deflate"))
+            .map_err(|_| Error::TooManyHeaders)?;
+    }
+    for header in request.headers.iter().copied().chain(auth_headers.iter()) {
+        headers.push(header).map_err(|_| Error::TooManyHeaders)?;
+    }
+
+    let builder = ReqlessRequest::new(request.method, url.path())
+        .host(url.host())
+        .headers(&headers);
+
+    // Setting a body changes the type of the request, so both cases
+    // are handled separately
+    match request.body {
+        Some(Body::Bytes(_, body)) => {
+            builder.body(body).build().write_header(connection).await?;
+            write_all(connection, body).await?;
+        }
+        Some(body) => {
+            builder.build().write_header(connection).await?;
+            write_chunked(connection, &body).await?;
+        }
+        None => builder.build().write_header(connection).await?,
+    }
+    flush(connection).await?;
+
+    let response = ReqlessResponse::read(connection, request.method, header_buffer).await?;
+    read_response(response, request.method, inflater, follow_redirects, request.success_only, on_chunk).await
+}
+
+/// Write a body with chunked transfer encoding
+///
+/// Memory use does not depend on the body size, the body is read in pieces
+/// of at most [`CHUNK_SIZE`] bytes.
+#[allow(clippy::result_large_err)]
+async fn write_chunked<C>(connection: &mut C, body: &Body<'_>) -> Result<(), Error>
+where
+    C: Write,
+{
+    let mut reader = body.reader();
+    let mut chunk = [0_u8; CHUNK_SIZE];
+    let mut total = 0;
+
+    loop {
+        let length = reader.read(&mut chunk)?;
+
+        let mut size: String<8> = String::new();
+        write!(size, "{length:x}\r\n").map_err(|_| Error::Body)?;
+        write_all(connection, size.as_bytes()).await?;
+
+        if length == 0 {
+            write_all(connection, b"\r\n").await?;
+            break;
+        }
+
+        write_all(connection, &chunk[..length]).await?;
+        write_all(connection, b"\r\n").await?;
+        total += length;
+    }
+
+    debug!("Uploaded {total} bytes");
+
+    Ok(())
+}
+
+/// Write all bytes to a connection
+#[allow(clippy::result_large_err)]
+async fn write_all<C>(connection: &mut C, bytes: &[u8]) -> Result<(), Error>
+where
+    C: Write,
+{
+    connection
+        .write_all(bytes)
+        .await
+        .map_err(|error| ReqlessError::Network(error.kind()))?;
+    Ok(())
+}
+
+/// Send out bytes buffered by a connection
+///
+/// Writing the request head does not flush, and a TLS session keeps what
+/// is written in its record buffer until flushed.
+#[allow(clippy::result_large_err)]
+async fn flush<C>(connection: &mut C) -> Result<(), Error>
+where
+    C: Write,
+{
+    connection
+        .flush()
+        .await
+        .map_err(|error| ReqlessError::Network(error.kind()))?;
+    Ok(())
+}
+
+/// Read status and headers of a response, and pass its body to a callback
+///
+/// The body reader takes care of both `Content-Length` and chunked transfer
+/// encoding.  The body of a redirect is read but discarded when
+/// `follow_redirects` is set, so that the connection can be reused.
+///
+/// Compressed bodies are inflated if an inflater is given, unless the
+/// response has no body: answers to `HEAD` requests, and 1xx, 204 and 304
+/// responses carry the `Content-Encoding` of a body they do not include.
+///
+/// When `success_only` is set, the body of a non-2xx response is not passed
+/// to the callback, but read to keep the connection usable, and its
+/// beginning is returned along with the head.
+#[allow(clippy::result_large_err)]
+async fn read_response<C, F>(
+    response: ReqlessResponse<'_, '_, C>,
+    method: Method,
+    inflater: Option<&mut Inflater>,
+    follow_redirects: bool,
+    success_only: bool,
+    on_chunk: &mut F,
+) -> Result<Exchanged, Error>
+where
+    C: Read + Write,
+    F: FnMut(&[u8]) -> Result<(), Error>,
+{
+    debug!("Response status: {:?}", response.status);
+
+    let mut headers = Headers::default();
+    for (name, value) in response.headers() {
+        headers.push(name, value)?;
+    }
+
+    let head = ResponseHead {
+        status: response.status.0,
+        headers,
+        received_at: Instant::now(),
+    };
+
+    let discard = follow_redirects && is_redirect(head.status) && head.headers.has_location();
+    if discard {
+        debug!("Discard body of redirect");
+    }
+
+    let failed = success_only && !discard && !head.is_success();
+    if failed {
+        debug!("Keep only an excerpt of the body of status {}", head.status);
+    }
+    let mut excerpt: Vec<u8, STATUS_BODY_SIZE> = Vec::new();
+    let mut sink = |bytes: &[u8]| {
+        if failed {
+            let length = bytes.len().min(STATUS_BODY_SIZE - excerpt.len());
+            let _ = excerpt.extend_from_slice(&bytes[..length]);
+            Ok(())
+        } else {
+            on_chunk(bytes)
+        }
+    };
 
-        debug!("Create TCP client");
-        let tcp_client = TcpClient::new(self.stack, &self.tcp_client_state);
+    let bodiless = method == Method::HEAD || matches!(head.status, 100..=199 | 204 | 304);
+    let mut inflate = match inflater {
+        Some(inflater) if !discard && !bodiless => {
+            let encoding = Encoding::parse(head.headers.get("Content-Encoding"))?;
+            Some(Inflate::new(inflater, encoding))
+        }
+        _ => None,
+    };
 
-        debug!("Create HTTP client");
-        let mut client = HttpClient::new_with_tls(&tcp_client, &dns_socket, tls_config);
-        //let mut client = HttpClient::new(&tcp_client, &dns_socket);
+    let mut reader = response.body().reader();
+    let mut chunk = [0_u8; CHUNK_SIZE];
+    let mut total = 0;
 
-        debug!("Create HTTP request");
-        let mut buffer = [0_u8; 4096];
-        let mut request = client
-            .request(Method::POST, url)
-            .await?
-            .body(body)
-            .content_type(ct);
+    loop {
+        let length = reader.read(&mut chunk).await?;
+        if length == 0 {
+            break;
+        }
 
-        debug!("Send HTTP request");
-        let response = request.send(&mut buffer).await.unwrap();
+        if let Some(inflate) = &mut inflate {
+            inflate.feed(&chunk[..length], &mut sink)?;
+        } else if !discard {
+            sink(&chunk[..length])?;
+        }
+        total += length;
+    }
 
-        debug!("Response status: {:?}", response.status);
+    if let Some(inflate) = &inflate {
+        inflate.finish()?;
+    }
+
+    debug!("Streamed {total} bytes");
 
-        let buffer = response.body().read_to_end().await?;
+    Ok((head, failed.then_some(excerpt)))
+}
 
-        debug!("Read {} bytes", buffer.len());
+/// Read status line and headers of a response, but not its body
+///
+/// Bytes are read one at a time, so that nothing past the headers is
+/// consumed: after switching protocols, it belongs to the new protocol.
+#[allow(clippy::result_large_err)]
+async fn read_head<C>(connection: &mut C, header_buffer: &mut [u8]) -> Result<ResponseHead, Error>
+where
+    C: Read,
+{
+    let mut length = 0;
+    while !header_buffer[..length].ends_with(b"\r\n\r\n") {
+        let byte = header_buffer
+            .get_mut(length..=length)
+            .ok_or(Error::ResponseTooLarge)?;
+        let read = connection
+            .read(byte)
+            .await
+            .map_err(|error| ReqlessError::Network(error.kind()))?;
+        if read == 0 {
+            return Err(ReqlessError::ConnectionAborted.into());
+        }
+        length += 1;
+    }
 
-        let output =
-            Vec::<u8, RESPONSE_SIZE>::from_slice(buffer).map_err(|()| Error::ResponseTooLarge)?;
+    let head = from_utf8(&header_buffer[..length]).map_err(|_| ReqlessError::Codec)?;
+    let mut lines = head.split("\r\n");
+    let status = lines
+        .next()
+        .and_then(|line| line.split(' ').nth(1))
+        .and_then(|status| status.parse().ok())
+        .ok_or(ReqlessError::Codec)?;
+    debug!("Response status: {status}");
 
-        Ok(output)
+    let mut headers = Headers::default();
+    for (name, value) in lines.filter_map(|line| line.split_once(':')) {
+        headers.push(name, value.as_bytes())?;
     }
+
+    Ok(ResponseHead {
+        status,
+        headers,
+        received_at: Instant::now(),
+    })
+}
+
+/// Resolve the `Location` of a redirect against the URL of the request
+///
+/// Absolute `http` and `https` URLs are kept as they are, network-path
+/// references such as `//example.com/path` inherit the scheme, and other
+/// references are resolved as described by RFC 3986: query-only ones
+/// against the request path, relative paths against its directory, and
+/// `.` and `..` segments are removed.  Fragments are dropped, they are
+/// never sent.
+#[allow(clippy::result_large_err)]
+fn resolve_location(base: &str, location: &str) -> Result<String<URL_SIZE>, Error> {
+    let location = location.split('#').next().unwrap_or_default();
+    let mut url = String::new();
+
+    if starts_with_ignore_case(location, "http://") || starts_with_ignore_case(location, "https://") {
+        url.push_str(location).map_err(|_| Error::UrlTooLong)?;
+        return Ok(url);
+    }
+    if has_scheme(location) {
+        warn!("Unsupported redirect to {location}");
+        return Err(ReqlessError::InvalidUrl(nourl::Error::UnsupportedScheme).into());
+    }
+
+    let base = Url::parse(base).map_err(ReqlessError::InvalidUrl)?;
+    let scheme = if base.scheme() == UrlScheme::HTTPS { "https" } else { "http" };
+
+    let result = if let Some(rest) = location.strip_prefix("//") {
+        write!(url, "{scheme}://{rest}")
+    } else {
+        let authority = match base.port() {
+            Some(port) => write!(url, "{scheme}://{}:{port}", base.host()),
+            None => write!(url, "{scheme}://{}", base.host()),
+        };
+
+        let base_path = base.path().split('#').next().unwrap_or_default();
+        let path = base_path.split('?').next().unwrap_or_default();
+        authority.and_then(|()| {
+            if location.is_empty() {
+                push_path(&mut url, base_path)
+            } else if location.starts_with('?') {
+                push_path(&mut url, path)?;
+                url.write_str(location)
+            } else if location.starts_with('/') {
+                push_path(&mut url, location)
+            } else {
+                let directory = path.rfind('/').map_or("/", |index| &path[..=index]);
+                let mut merged: String<URL_SIZE> = String::new();
+                write!(merged, "{directory}{location}")?;
+                push_path(&mut url, &merged)
+            }
+        })
+    };
+
+    result.map_err(|_| Error::UrlTooLong)?;
+    Ok(url)
+}
+
+/// Append an absolute path and its query, with `.` and `..` segments
+/// removed
+fn push_path(url: &mut String<URL_SIZE>, path: &str) -> core::fmt::Result {
+    let (path, query) = path.split_at(path.find('?').unwrap_or(path.len()));
+    let start = url.len();
+
+    let mut segments = path.split('/').skip(1).peekable();
+    while let Some(segment) = segments.next() {
+        let last = segments.peek().is_none();
+        match segment {
+            "." => {}
+            ".." => {
+                if let Some(index) = url[start..].rfind('/') {
+                    url.truncate(start + index);
+                }
+            }
+            segment => {
+                write!(url, "/{segment}")?;
+                continue;
+            }
+        }
+        // A path ending with a dot segment names a directory
+        if last {
+            url.write_char('/')?;
+        }
+    }
+
+    if url.len() == start {
+        url.write_char('/')?;
+    }
+    url.write_str(query)
+}
+
+/// Check whether a string starts with a prefix, ignoring ASCII case
+fn starts_with_ignore_case(string: &str, prefix: &str) -> bool {
+    string
+        .get(..prefix.len())
+        .is_some_and(|start| start.eq_ignore_ascii_case(prefix))
+}
+
+/// Check whether a URL reference starts with a scheme, as RFC 3986 defines
+/// it
+fn has_scheme(reference: &str) -> bool {
+    let Some((scheme, _)) = reference.split_once(':') else {
+        return false;
+    };
+    let mut characters = scheme.chars();
+    characters.next().is_some_and(|first| first.is_ascii_alphabetic())
+        && characters.all(|character| character.is_ascii_alphanumeric() || matches!(character, '+' | '-' | '.'))
 }
 
 /// An error within an HTTP request
+///
+/// Status errors keep an excerpt of the response body, which makes this
+/// type large.  It is returned rarely and never kept around, so functions
+/// returning it allow `clippy::result_large_err`.
 #[derive(Debug)]
 pub enum Error {
     /// Response was too large
     ResponseTooLarge,
 
-    /// Error within TCP streams
-    Tcp(#[allow(unused)] TcpError),
-
     /// Error within TCP connection
-    TcpConnect(#[allow(unused)] TcpConnectError),
+    TcpConnect(#[allow(unused)] ErrorKind),
 
-    /// Error within DNS system
-    Dns(#[allow(unused)] DnsError),
+    /// Host name could not be resolved
+    Dns,
 
     /// Error in HTTP client
     Reqless(#[allow(unused)] ReqlessError),
 
-    Time(embassy_time::TimeoutError)
+    /// Host name is too long
+    HostTooLong,
+
+    /// Redirect URL is too long
+    UrlTooLong,
+
+    /// Request or response has too many headers
+    TooManyHeaders,
+
+    /// Response header that the client relies on is too long or not UTF-8
+    HeaderNotStorable,
+
+    /// Request body could not be read from its source
+    Body,
+
+    /// Response body uses an unsupported content encoding
+    UnsupportedEncoding,
+
+    /// Compressed response body is invalid or truncated
+    Decompression,
+
+    /// Request could not be authenticated
+    Auth(#[allow(unused)] AuthError),
+
+    /// Request was redirected more times than allowed
+    TooManyRedirects,
+
+    /// Request was redirected to another scheme while server certificates
+    /// are verified
+    RedirectRefused,
+
+    /// TLS buffers are lent to another connection
+    Busy,
+
+    /// Consumer of a streamed response did not keep up
+    Overflow,
+
+    /// Error within TLS session
+    Tls(#[allow(unused)] TlsError),
+
+    /// Server certificate was rejected
+    TlsVerification(#[allow(unused)] VerificationError),
+
+    /// Server responded with a non-successful status
+    ///
+    /// The beginning of the response body is kept, if any.
+    Status(u16, #[allow(unused)] Vec<u8, STATUS_BODY_SIZE>),
+
+    /// Request did not complete within its timeout
+    Time(#[allow(unused)] embassy_time::TimeoutError),
+
+    /// Request body could not be serialized to JSON
+    JsonSerialize(#[allow(unused)] JsonSerializeError),
+
+    /// Response body could not be deserialized from JSON
+    JsonDeserialize(#[allow(unused)] JsonDeserializeError),
 }
 
-impl From<embassy_time::TimeoutError> for Error {
-    fn from(error: embassy_time::TimeoutError) -> Self {
-        Self::Time(error)
+impl Error {
+    /// Classify this error as transient or permanent
+    pub fn class(&self) -> ErrorClass {
+        match self {
+            Self::Time(_) | Self::TcpConnect(_) | Self::Dns => ErrorClass::Transient,
+            Self::Reqless(ReqlessError::Dns | ReqlessError::Network(_) | ReqlessError::ConnectionAborted) => {
+                ErrorClass::Transient
+            }
+            Self::Status(status, _) if *status == 429 || (500..600).contains(status) => ErrorClass::Transient,
+            Self::Busy | Self::Overflow | Self::Tls(TlsError::Io(_)) => ErrorClass::Transient,
+            Self::Status(..)
+            | Self::Reqless(_)
+            | Self::Tls(_)
+            | Self::TlsVerification(_)
+            | Self::HostTooLong
+            | Self::UrlTooLong
+            | Self::TooManyHeaders
+            | Self::HeaderNotStorable
+            | Self::Body
+            | Self::UnsupportedEncoding
+            | Self::Decompression
+            | Self::Auth(_)
+            | Self::TooManyRedirects
+            | Self::RedirectRefused
+            | Self::ResponseTooLarge
+            | Self::JsonSerialize(_)
+            | Self::JsonDeserialize(_) => ErrorClass::Permanent,
+        }
+    }
+
+    /// Check whether the same request may succeed if retried later
+    pub fn is_transient(&self) -> bool {
+        self.class() == ErrorClass::Transient
     }
 }
 
-impl From<TcpError> for Error {
-    fn from(error: TcpError) -> Self {
-        Self::Tcp(error)
+/// Class of an HTTP error
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorClass {
+    /// The same request may succeed later, such as after a timeout, a
+    /// network failure, a 5xx status or a 429 status
+    Transient,
+
+    /// The same request will fail again, such as after a 4xx status or a
+    /// rejected server certificate
+    Permanent,
+}
+
+impl From<embassy_time::TimeoutError> for Error {
+    fn from(error: embassy_time::TimeoutError) -> Self {
+        Self::Time(error)
     }
 }
 
-impl From<TcpConnectError> for Error {
-    fn from(error: TcpConnectError) -> Self {
-        Self::TcpConnect(error)
+impl From<TlsOpenError> for Error {
+    fn from(error: TlsOpenError) -> Self {
+        match error {
+            TlsOpenError::BuffersInUse => Self::Busy,
+            TlsOpenError::Tls(error) => Self::Tls(error),
+            TlsOpenError::Verification(error) => Self::TlsVerification(error),
+        }
     }
 }
 
-impl From<DnsError> for Error {
-    fn from(error: DnsError) -> Self {
-        Self::Dns(error)
+impl From<AuthError> for Error {
+    fn from(error: AuthError) -> Self {
+        Self::Auth(error)
     }
 }
 
@@ -295,4 +1846,76 @@ impl From<ReqlessError> for Error {
     fn from(error: ReqlessError) -> Self {
         Self::Reqless(error)
     }
-}
\ No newline at end of file
+}
+
+impl From<JsonSerializeError> for Error {
+    fn from(error: JsonSerializeError) -> Self {
+        Self::JsonSerialize(error)
+    }
+}
+
+impl From<JsonDeserializeError> for Error {
+    fn from(error: JsonDeserializeError) -> Self {
+        Self::JsonDeserialize(error)
+    }
+}
+
+/// Tests of redirect resolution
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// URL of the redirected request
+    const BASE: &str = "http://example.com:8080/a/b?q=1";
+
+    /// Resolve a location against [`BASE`]
+    fn resolve(location: &str) -> String<URL_SIZE> {
+        resolve_location(BASE, location).unwrap()
+    }
+
+    #[test]
+    fn absolute_urls_are_kept() {
+        assert_eq!(resolve("https://other.com/x?y"), "https://other.com/x?y");
+        assert_eq!(resolve("HTTP://other.com/x"), "HTTP://other.com/x");
+    }
+
+    #[test]
+    fn network_paths_inherit_scheme() {
+        assert_eq!(resolve("//other.com/x"), "http://other.com/x");
+        assert_eq!(
+            resolve_location("https://example.com/", "//other.com/x").unwrap(),
+            "https://other.com/x"
+        );
+    }
+
+    #[test]
+    fn absolute_paths_keep_authority() {
+        assert_eq!(resolve("/c?r=2"), "http://example.com:8080/c?r=2");
+    }
+
+    #[test]
+    fn relative_paths_are_merged_with_directory() {
+        assert_eq!(resolve("c/d"), "http://example.com:8080/a/c/d");
+        assert_eq!(resolve("./c"), "http://example.com:8080/a/c");
+        assert_eq!(resolve("../c"), "http://example.com:8080/c");
+        assert_eq!(resolve("../../../c"), "http://example.com:8080/c");
+    }
+
+    #[test]
+    fn queries_replace_the_request_query() {
+        assert_eq!(resolve("?r=2"), "http://example.com:8080/a/b?r=2");
+        assert_eq!(resolve(""), "http://example.com:8080/a/b?q=1");
+    }
+
+    #[test]
+    fn fragments_are_dropped() {
+        assert_eq!(resolve("c#part"), "http://example.com:8080/a/c");
+        assert_eq!(resolve("https://other.com/x#part"), "https://other.com/x");
+    }
+
+    #[test]
+    fn other_schemes_are_rejected() {
+        assert!(resolve_location(BASE, "ftp://example.com/file").is_err());
+        assert!(resolve_location(BASE, "mailto:someone@example.com").is_err());
+    }
+}
@@ -0,0 +1,208 @@
+// Copyright Claudio Mattera 2024.
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! HTTP service shared by all tasks
+//!
+//! A single task owns the HTTP client and serves requests sent by other
+//! tasks over channels, one channel per [`Priority`].  Since requests are
+//! served one at a time, all tasks share a single TCP socket instead of
+//! competing for the few sockets of the network stack.
+//!
+//! Requests cross task boundaries, so their URL, headers and body must be
+//! `'static`.  Each requesting task owns a [`ReplySignal`], through which it
+//! receives its responses.
+
+use core::cell::Cell;
+
+use embassy_futures::select::select3;
+use embassy_futures::select::Either3;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::channel::Channel;
+use embassy_sync::signal::Signal;
+use log::debug;
+
+use crate::http::ClientTrait;
+use crate::http::Error as HttpError;
+use crate::http::Request;
+use crate::http::Response;
+
+/// Maximal number of queued requests for each priority
+const QUEUE_SIZE: usize = 4;
+
+/// Signal through which a requesting task receives its responses
+///
+/// Responses carry the sequence number of their request, so that the
+/// response to an abandoned request is never taken for the one to the next.
+pub struct ReplySignal {
+    /// Latest response, with the sequence number of its request
+    signal: Signal<CriticalSectionRawMutex, (u32, Result<Response, HttpError>)>,
+
+    /// Sequence number of the latest request
+    sequence: Mutex<CriticalSectionRawMutex, Cell<u32>>,
+}
+
+impl Default for ReplySignal {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ReplySignal {
+    /// Create a new signal
+    pub const fn new() -> Self {
+        Self {
+            signal: Signal::new(),
+            sequence: Mutex::new(Cell::new(0)),
+        }
+    }
+
+    /// Start a new request and return its sequence number
+    fn next_sequence(&self) -> u32 {
+        self.sequence.lock(|sequence| {
+            sequence.set(sequence.get().wrapping_add(1));
+            sequence.get()
+        })
+    }
+
+    /// Check whether a request is the latest one, the only one still awaited
+    fn is_latest(&self, sequence: u32) -> bool {
+        self.sequence.lock(|latest| latest.get() == sequence)
+    }
+}
+
+/// Priority of a request
+///
+/// Queued requests with higher priority are served first, requests with the
+/// same priority are served in order.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    /// Background requests, such as update checks
+    Low,
+
+    /// Regular requests, such as telemetry uploads
+    Normal,
+
+    /// Time critical requests, such as clock synchronization
+    High,
+}
+
+/// A request waiting to be served
+struct Job {
+    /// The request
+    request: Request<'static>,
+
+    /// Where to send the response
+    reply: &'static ReplySignal,
+
+    /// Sequence number of the request on its reply signal
+    sequence: u32,
+}
+
+/// HTTP service
+///
+/// Create it in static memory, pass it to [`HttpService::run`] in a
+/// dedicated task, and send requests with [`HttpService::request`] from
+/// any other task.
+pub struct HttpService {
+    /// Queue of low priority requests
+    low: Channel<CriticalSectionRawMutex, Job, QUEUE_SIZE>,
+
+    /// Queue of normal priority requests
+    normal: Channel<CriticalSectionRawMutex, Job, QUEUE_SIZE>,
+
+    /// Queue of high priority requests
+    high: Channel<CriticalSectionRawMutex, Job, QUEUE_SIZE>,
+}
+
+impl Default for HttpService {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[allow(clippy::result_large_err)]
+impl HttpService {
+    /// Create a new service
+    pub const fn new() -> Self {
+        Self {
+            low: Channel::new(),
+            normal: Channel::new(),
+            high: Channel::new(),
+        }
+    }
+
+    /// Send a request and wait for its response
+    ///
+    /// Each task must own its `reply` signal, which serves one request at a
+    /// time.  If this future is dropped while waiting, the request is skipped
+    /// unless it is already being served, and its response is then discarded
+    /// by the next request sent through the same signal.
+    #[allow(unused)]
+    pub async fn request(
+        &self,
+        priority: Priority,
+        request: Request<'static>,
+        reply: &'static ReplySignal,
+    ) -> Result<Response, HttpError> {
+        let sequence = reply.next_sequence();
+
+        let job = Job {
+            request,
+            reply,
+            sequence,
+        };
+        self.queue(priority).send(job).await;
+
+        loop {
+            let (answered, response) = reply.signal.wait().await;
+            if answered == sequence {
+                return response;
+            }
+            debug!("Discard response to abandoned request {answered}");
+        }
+    }
+
+    /// Serve requests forever
+    pub async fn run(&self, client: &mut impl ClientTrait) -> ! {
+        loop {
+            let (priority, job) = self.next().await;
+            if !job.reply.is_latest(job.sequence) {
+                debug!("Skip abandoned {priority:?} priority request");
+                continue;
+            }
+            debug!("Serve {priority:?} priority request");
+
+            let response = client.request(&job.request).await;
+            job.reply.signal.signal((job.sequence, response));
+        }
+    }
+
+    /// Wait for the next request, highest priority first
+    async fn next(&self) -> (Priority, Job) {
+        let queued = [Priority::High, Priority::Normal, Priority::Low]
+            .into_iter()
+            .find_map(|priority| Some((priority, self.queue(priority).try_receive().ok()?)));
+        if let Some(queued) = queued {
+            return queued;
+        }
+
+        match select3(self.high.receive(), self.normal.receive(), self.low.receive()).await {
+            Either3::First(job) => (Priority::High, job),
+            Either3::Second(job) => (Priority::Normal, job),
+            Either3::Third(job) => (Priority::Low, job),
+        }
+    }
+
+    /// Return the queue for a priority
+    fn queue(&self, priority: Priority) -> &Channel<CriticalSectionRawMutex, Job, QUEUE_SIZE> {
+        match priority {
+            Priority::Low => &self.low,
+            Priority::Normal => &self.normal,
+            Priority::High => &self.high,
+        }
+    }
+}
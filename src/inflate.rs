@@ -0,0 +1,349 @@
+// Copyright Claudio Mattera 2024.
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Decompression of HTTP response bodies
+//!
+//! Bodies with `Content-Encoding: gzip` or `deflate` are inflated as they
+//! arrive, so compressed downloads never need to be buffered.  Inflating
+//! needs the last 32 KiB of output as a window, plus about 11 KiB of state,
+//! which live in an [`Inflater`] in static memory.
+//!
+//! The gzip trailer is not checked, TCP and TLS already protect the data.
+
+use log::debug;
+
+use miniz_oxide::inflate::core::decompress;
+use miniz_oxide::inflate::core::inflate_flags::TINFL_FLAG_HAS_MORE_INPUT;
+use miniz_oxide::inflate::core::inflate_flags::TINFL_FLAG_PARSE_ZLIB_HEADER;
+use miniz_oxide::inflate::core::DecompressorOxide;
+use miniz_oxide::inflate::TINFLStatus;
+
+use crate::http::Error;
+
+/// Size of the inflate window, the largest distance of deflate references
+const WINDOW_SIZE: usize = 32 * 1024;
+
+/// Gzip header flag for extra fields
+const GZIP_FEXTRA: u8 = 0x04;
+
+/// Gzip header flag for a file name
+const GZIP_FNAME: u8 = 0x08;
+
+/// Gzip header flag for a comment
+const GZIP_FCOMMENT: u8 = 0x10;
+
+/// Gzip header flag for a header checksum
+const GZIP_FHCRC: u8 = 0x02;
+
+/// Buffers for inflating response bodies
+pub struct Inflater {
+    /// Decompressor state
+    ///
+    /// It cannot be built in a `const` context, so it is created on first
+    /// use.
+    decompressor: Option<DecompressorOxide>,
+
+    /// Window of the last inflated bytes
+    window: [u8; WINDOW_SIZE],
+}
+
+impl Inflater {
+    /// Create new buffers
+    pub const fn new() -> Self {
+        Self {
+            decompressor: None,
+            window: [0; WINDOW_SIZE],
+        }
+    }
+}
+
+impl Default for Inflater {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Content encoding of a response body
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    /// Not compressed
+    Identity,
+
+    /// Gzip
+    Gzip,
+
+    /// Deflate, with or without zlib header
+    Deflate,
+}
+
+#[allow(clippy::result_large_err)]
+impl Encoding {
+    /// Parse a `Content-Encoding` header
+    pub fn parse(value: Option<&str>) -> Result<Self, Error> {
+        match value.map(str::trim) {
+            None => Ok(Self::Identity),
+            Some(value) if value.is_empty() || value.eq_ignore_ascii_case("identity") => Ok(Self::Identity),
+            Some(value) if value.eq_ignore_ascii_case("gzip") || value.eq_ignore_ascii_case("x-gzip") => Ok(Self::Gzip),
+            Some(value) if value.eq_ignore_ascii_case("deflate") => Ok(Self::Deflate),
+            Some(value) => {
+                debug!("Unsupported content encoding {value}");
+                Err(Error::UnsupportedEncoding)
+            }
+        }
+    }
+}
+
+/// Parsing state of a gzip header
+#[derive(Clone, Copy, Debug)]
+enum GzipHeader {
+    /// Within the fixed part, at this offset
+    Fixed(usize),
+
+    /// Within the length of the extra field, at this offset
+    ExtraLength(usize),
+
+    /// Within the extra field, with this many bytes left
+    Extra(usize),
+
+    /// Within the zero terminated file name
+    Name,
+
+    /// Within the zero terminated comment
+    Comment,
+
+    /// Within the header checksum, at this offset
+    Checksum(usize),
+
+    /// Header is over
+    Done,
+}
+
+/// An ongoing decompression of a body
+pub struct Inflate<'a> {
+    /// Buffers
+    inflater: &'a mut Inflater,
+
+    /// Content encoding
+    encoding: Encoding,
+
+    /// Gzip header parsing state
+    header: GzipHeader,
+
+    /// Gzip header flags
+    flags: u8,
+
+    /// Gzip extra field length
+    extra_length: usize,
+
+    /// Decompressor flags, decided on the first two deflate bytes
+    inflate_flags: Option<u32>,
+
+    /// First deflate byte, kept until the second one arrives
+    first: Option<u8>,
+
+    /// Position in the window
+    position: usize,
+
+    /// Whether the deflate stream is over
+    done: bool,
+}
+
+#[allow(clippy::result_large_err)]
+impl<'a> Inflate<'a> {
+    /// Start inflating a body
+    pub fn new(inflater: &'a mut Inflater, encoding: Encoding) -> Self {
+        inflater
+            .decompressor
+            .get_or_insert_with(DecompressorOxide::new)
+            .init();
+
+        let header = if encoding == Encoding::Gzip {
+            GzipHeader::Fixed(0)
+        } else {
+            GzipHeader::Done
+        };
+
+        Self {
+            inflater,
+            encoding,
+            header,
+            flags: 0,
+            extra_length: 0,
+            inflate_flags: None,
+            first: None,
+            position: 0,
+            done: false,
+        }
+    }
+
+    /// Inflate a chunk of the body and pass the output to a callback
+    pub fn feed<F>(&mut self, mut input: &[u8], on_chunk: &mut F) -> Result<(), Error>
+    where
+        F: FnMut(&[u8]) -> Result<(), Error>,
+    {
+        if self.encoding == Encoding::Identity {
+            return on_chunk(input);
+        }
+
+        input = self.skip_header(input)?;
+        if input.is_empty() || self.done {
+            return Ok(());
+        }
+
+        let flags = match (self.inflate_flags, self.first) {
+            (Some(flags), _) => flags,
+            (None, _) if self.encoding == Encoding::Gzip => {
+                self.inflate_flags = Some(TINFL_FLAG_HAS_MORE_INPUT);
+                TINFL_FLAG_HAS_MORE_INPUT
+            }
+            // A zlib header takes two bytes, wait for the second one
+            (None, None) if input.len() < 2 => {
+                self.first = input.first().copied();
+                return Ok(());
+            }
+            (None, first) => {
+                let zlib = match first {
+                    Some(first) => is_zlib_header(&[first, input[0]]),
+                    None => is_zlib_header(input),
+                };
+                let flags = if zlib {
+                    TINFL_FLAG_HAS_MORE_INPUT | TINFL_FLAG_PARSE_ZLIB_HEADER
+                } else {
+                    TINFL_FLAG_HAS_MORE_INPUT
+                };
+                self.inflate_flags = Some(flags);
+                if let Some(first) = self.first.take() {
+                    self.inflate(&[first], flags, on_chunk)?;
+                    if self.done {
+                        return Ok(());
+                    }
+                }
+                flags
+            }
+        };
+
+        self.inflate(input, flags, on_chunk)
+    }
+
+    /// Inflate deflate data and pass the output to a callback
+    fn inflate<F>(&mut self, mut input: &[u8], flags: u32, on_chunk: &mut F) -> Result<(), Error>
+    where
+        F: FnMut(&[u8]) -> Result<(), Error>,
+    {
+        loop {
+            let decompressor = self.inflater.decompressor.get_or_insert_with(DecompressorOxide::new);
+            let (status, consumed, written) = decompress(
+                decompressor,
+                input,
+                &mut self.inflater.window,
+                self.position,
+                flags,
+            );
+
+            if written > 0 {
+                on_chunk(&self.inflater.window[self.position..self.position + written])?;
+            }
+            self.position = (self.position + written) % WINDOW_SIZE;
+            input = &input[consumed..];
+
+            match status {
+                TINFLStatus::Done => {
+                    self.done = true;
+                    return Ok(());
+                }
+                // The window is full, keep going from its beginning
+                TINFLStatus::HasMoreOutput => {}
+                TINFLStatus::NeedsMoreInput if input.is_empty() || consumed + written == 0 => return Ok(()),
+                TINFLStatus::NeedsMoreInput => {}
+                status => {
+                    debug!("Inflate failed: {status:?}");
+                    return Err(Error::Decompression);
+                }
+            }
+        }
+    }
+
+    /// Check that the whole compressed stream was received
+    pub fn finish(&self) -> Result<(), Error> {
+        if self.encoding == Encoding::Identity || self.done {
+            Ok(())
+        } else {
+            debug!("Compressed body is truncated");
+            Err(Error::Decompression)
+        }
+    }
+
+    /// Skip the gzip header at the beginning of the body
+    ///
+    /// Return the rest of the input.
+    fn skip_header<'b>(&mut self, mut input: &'b [u8]) -> Result<&'b [u8], Error> {
+        while let Some((&byte, rest)) = input.split_first() {
+            self.header = match self.header {
+                GzipHeader::Done => break,
+                GzipHeader::Fixed(offset) => {
+                    match offset {
+                        0 if byte != 0x1f => return Err(Error::Decompression),
+                        1 if byte != 0x8b => return Err(Error::Decompression),
+                        2 if byte != 8 => return Err(Error::Decompression),
+                        3 => self.flags = byte,
+                        _ => {}
+                    }
+                    if offset < 9 {
+                        GzipHeader::Fixed(offset + 1)
+                    } else {
+                        self.after(GZIP_FEXTRA)
+                    }
+                }
+                GzipHeader::ExtraLength(0) => {
+                    self.extra_length = usize::from(byte);
+                    GzipHeader::ExtraLength(1)
+                }
+                GzipHeader::ExtraLength(_) => {
+                    self.extra_length |= usize::from(byte) << 8;
+                    if self.extra_length == 0 {
+                        self.after(GZIP_FNAME)
+                    } else {
+                        GzipHeader::Extra(self.extra_length)
+                    }
+                }
+                GzipHeader::Extra(1) => self.after(GZIP_FNAME),
+                GzipHeader::Extra(left) => GzipHeader::Extra(left - 1),
+                GzipHeader::Name if byte == 0 => self.after(GZIP_FCOMMENT),
+                GzipHeader::Comment if byte == 0 => self.after(GZIP_FHCRC),
+                state @ (GzipHeader::Name | GzipHeader::Comment) => state,
+                GzipHeader::Checksum(0) => GzipHeader::Checksum(1),
+                GzipHeader::Checksum(_) => GzipHeader::Done,
+            };
+            input = rest;
+        }
+
+        Ok(input)
+    }
+
+    /// Return the first header state starting from an optional field
+    fn after(&self, field: u8) -> GzipHeader {
+        let fields = [
+            (GZIP_FEXTRA, GzipHeader::ExtraLength(0)),
+            (GZIP_FNAME, GzipHeader::Name),
+            (GZIP_FCOMMENT, GzipHeader::Comment),
+            (GZIP_FHCRC, GzipHeader::Checksum(0)),
+        ];
+
+        fields
+            .into_iter()
+            .skip_while(|(flag, _)| *flag != field)
+            .find(|(flag, _)| self.flags & flag != 0)
+            .map_or(GzipHeader::Done, |(_, state)| state)
+    }
+}
+
+/// Check whether a deflate stream starts with a zlib header
+fn is_zlib_header(input: &[u8]) -> bool {
+    match input {
+        [cmf, flg, ..] => cmf & 0x0f == 8 && (u16::from(*cmf) << 8 | u16::from(*flg)) % 31 == 0,
+        _ => false,
+    }
+}
@@ -0,0 +1,292 @@
+// Copyright Claudio Mattera 2024.
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! ES256 JSON Web Tokens
+//!
+//! Device tokens are JWTs signed with a per-device P-256 key, carrying the
+//! issue and expiration times as `iat` and `exp` claims.  This module only
+//! depends on the current time passed as Unix epoch and on a random numbers
+//! generator, so it also runs on a host.
+//!
+//! Signatures follow RFC 6979 with additional randomness, so that a weak
+//! random numbers generator never leaks the key.
+
+use core::str::from_utf8;
+
+use embassy_time::Duration;
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD as BASE64URL;
+use base64::Engine as _;
+
+use heapless::String;
+
+use p256::ecdsa::signature::RandomizedSigner as _;
+use p256::ecdsa::Signature;
+use p256::ecdsa::SigningKey;
+use p256::pkcs8::DecodePrivateKey as _;
+
+use rand_chacha::rand_core::SeedableRng as _;
+use rand_chacha::ChaCha20Rng;
+
+use rand_core::Rng;
+
+use serde::Serialize;
+
+use serde_json_core::ser::Error as JsonSerializeError;
+
+use log::debug;
+
+/// Maximal length of a token
+pub const TOKEN_SIZE: usize = 512;
+
+/// Maximal length of the serialized header or claims
+const JSON_SIZE: usize = 256;
+
+/// Maximal length of the base64url encoded header or claims
+const ENCODED_SIZE: usize = JSON_SIZE.div_ceil(3) * 4;
+
+/// Default token lifetime
+const DEFAULT_LIFETIME: Duration = Duration::from_secs(3600);
+
+/// JOSE header
+#[derive(Serialize)]
+struct Header<'a> {
+    /// Signature algorithm
+    alg: &'static str,
+
+    /// Token type
+    typ: &'static str,
+
+    /// Key identifier
+    #[serde(skip_serializing_if = "Option::is_none")]
+    kid: Option<&'a str>,
+}
+
+/// Registered claims
+#[derive(Serialize)]
+struct Claims<'a> {
+    /// Issuer
+    iss: &'a str,
+
+    /// Subject
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sub: Option<&'a str>,
+
+    /// Audience
+    #[serde(skip_serializing_if = "Option::is_none")]
+    aud: Option<&'a str>,
+
+    /// Issue time
+    iat: u64,
+
+    /// Expiration time
+    exp: u64,
+}
+
+/// A signed token
+#[derive(Clone, Debug)]
+pub struct Token {
+    /// Compact serialization of the token
+    value: String<TOKEN_SIZE>,
+
+    /// Expiration time as Unix epoch
+    expires_at: u64,
+}
+
+impl Token {
+    /// Return the compact serialization of the token
+    pub fn as_str(&self) -> &str {
+        &self.value
+    }
+
+    /// Return the expiration time as Unix epoch
+    #[allow(unused)]
+    pub fn expires_at(&self) -> u64 {
+        self.expires_at
+    }
+
+    /// Check whether the token is still valid for at least `margin`
+    pub fn is_fresh(&self, now: u64, margin: Duration) -> bool {
+        now.saturating_add(margin.as_secs()) < self.expires_at
+    }
+}
+
+/// Mint ES256 tokens
+pub struct JwtSigner<'a> {
+    /// Device key
+    key: SigningKey,
+
+    /// Issuer, usually the device identifier
+    issuer: &'a str,
+
+    /// Subject
+    subject: Option<&'a str>,
+
+    /// Audience, usually the backend
+    audience: Option<&'a str>,
+
+    /// Key identifier
+    key_id: Option<&'a str>,
+
+    /// Token lifetime
+    lifetime: Duration,
+}
+
+impl<'a> JwtSigner<'a> {
+    /// Create a new signer
+    pub fn new(key: SigningKey, issuer: &'a str) -> Self {
+        Self {
+            key,
+            issuer,
+            subject: None,
+            audience: None,
+            key_id: None,
+            lifetime: DEFAULT_LIFETIME,
+        }
+    }
+
+    /// Create a new signer from a PKCS#8 DER encoded key
+    #[allow(unused)]
+    pub fn from_pkcs8_der(der: &[u8], issuer: &'a str) -> Result<Self, Error> {
+        let key = SigningKey::from_pkcs8_der(der).map_err(|_| Error::InvalidKey)?;
+        Ok(Self::new(key, issuer))
+    }
+
+    /// Create a new signer from a raw 32 bytes key
+    #[allow(unused)]
+    pub fn from_bytes(bytes: &[u8], issuer: &'a str) -> Result<Self, Error> {
+        let key = SigningKey::from_slice(bytes).map_err(|_| Error::InvalidKey)?;
+        Ok(Self::new(key, issuer))
+    }
+
+    /// Set the subject claim
+    #[allow(unused)]
+    #[must_use]
+    pub fn with_subject(self, subject: &'a str) -> Self {
+        Self {
+            subject: Some(subject),
+            ..self
+        }
+    }
+
+    /// Set the audience claim
+    #[allow(unused)]
+    #[must_use]
+    pub fn with_audience(self, audience: &'a str) -> Self {
+        Self {
+            audience: Some(audience),
+            ..self
+        }
+    }
+
+    /// Set the key identifier header
+    #[allow(unused)]
+    #[must_use]
+    pub fn with_key_id(self, key_id: &'a str) -> Self {
+        Self {
+            key_id: Some(key_id),
+            ..self
+        }
+    }
+
+    /// Set the token lifetime
+    ///
+    /// By default tokens are valid for one hour.
+    #[allow(unused)]
+    #[must_use]
+    pub fn with_lifetime(self, lifetime: Duration) -> Self {
+        Self { lifetime, ..self }
+    }
+
+    /// Mint a token issued at `now`, a Unix epoch
+    pub fn mint(&self, now: u64, rng: &mut impl Rng) -> Result<Token, Error> {
+        let mut seed = [0_u8; 32];
+        rng.fill_bytes(&mut seed);
+        self.mint_with_seed(now, seed)
+    }
+
+    /// Mint a token issued at `now`, a Unix epoch, with signature randomness
+    /// drawn from `seed`
+    pub fn mint_with_seed(&self, now: u64, seed: [u8; 32]) -> Result<Token, Error> {
+        let expires_at = now.saturating_add(self.lifetime.as_secs());
+
+        let header = Header {
+            alg: "ES256",
+            typ: "JWT",
+            kid: self.key_id,
+        };
+        let claims = Claims {
+            iss: self.issuer,
+            sub: self.subject,
+            aud: self.audience,
+            iat: now,
+            exp: expires_at,
+        };
+
+        let mut value = String::new();
+        push_json(&mut value, &header)?;
+        value.push('.').map_err(|_| Error::TooLong)?;
+        push_json(&mut value, &claims)?;
+
+        // The signature crates use an older version of `rand_core`
+        let mut signing_rng = ChaCha20Rng::from_seed(seed);
+
+        let signature: Signature = self
+            .key
+            .try_sign_with_rng(&mut signing_rng, value.as_bytes())
+            .map_err(|_| Error::Signature)?;
+
+        value.push('.').map_err(|_| Error::TooLong)?;
+        push_base64(&mut value, &signature.to_bytes())?;
+
+        debug!("Minted token expiring at {expires_at}");
+
+        Ok(Token { value, expires_at })
+    }
+}
+
+/// Serialize a value to JSON and append it base64url encoded
+fn push_json<T>(value: &mut String<TOKEN_SIZE>, part: &T) -> Result<(), Error>
+where
+    T: Serialize,
+{
+    let mut json = [0_u8; JSON_SIZE];
+    let length = serde_json_core::to_slice(part, &mut json)?;
+    push_base64(value, &json[..length])
+}
+
+/// Append bytes base64url encoded
+fn push_base64(value: &mut String<TOKEN_SIZE>, bytes: &[u8]) -> Result<(), Error> {
+    let mut encoded = [0_u8; ENCODED_SIZE];
+    let length = BASE64URL
+        .encode_slice(bytes, &mut encoded)
+        .map_err(|_| Error::TooLong)?;
+    // Base64 is always ASCII
+    let encoded = from_utf8(&encoded[..length]).unwrap_or_default();
+    value.push_str(encoded).map_err(|_| Error::TooLong)
+}
+
+/// An error within token minting
+#[derive(Debug)]
+pub enum Error {
+    /// Token is too long
+    TooLong,
+
+    /// Key could not be decoded
+    InvalidKey,
+
+    /// Token could not be signed
+    Signature,
+
+    /// Header or claims could not be serialized
+    Json(#[allow(unused)] JsonSerializeError),
+}
+
+impl From<JsonSerializeError> for Error {
+    fn from(error: JsonSerializeError) -> Self {
+        Self::Json(error)
+    }
+}
@@ -0,0 +1,52 @@
+// Copyright Claudio Mattera 2024.
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Network clients of the firmware
+//!
+//! Nothing here depends on the chip: clients are generic over transport and
+//! random numbers generator, so that the same code runs on the embassy-net
+//! stack of the firmware and, with the `std` feature, on the std sockets of
+//! a host, where it is tested.
+
+#![no_std]
+
+#[cfg(feature = "std")]
+extern crate std;
+
+pub mod auth;
+
+pub mod body;
+
+pub mod cache;
+
+pub mod clock;
+
+pub mod date;
+
+#[cfg(feature = "std")]
+pub mod host;
+
+pub mod http;
+
+pub mod http_service;
+
+pub mod inflate;
+
+pub mod jwt;
+
+pub mod retry;
+
+pub mod sntp;
+
+pub mod sse;
+
+pub mod timesync;
+
+pub mod tls;
+
+pub mod websocket;
+
+pub mod worldtimeapi;
@@ -13,24 +13,50 @@
 #![no_std]
 #![no_main]
 
+// The firmware brings its own time driver and entry point, while the `std`
+// feature runs the library on a host
+#[cfg(feature = "std")]
+compile_error!("the firmware cannot be built with the `std` feature");
+
 use embassy_executor::Spawner;
-use embassy_time::{Duration, Timer};
+use embassy_time::{Duration, Instant, Timer};
 use esp_backtrace as _;
 
 
-//mod random;
-//use self::random::RngWrapper;
+mod random;
+use self::random::RngWrapper;
+
+use esp32c3_embassy::clock::Clock;
+
+use esp32c3_embassy::http::Client as HttpClient;
+use esp32c3_embassy::http::Request as HttpRequest;
+use esp32c3_embassy::http::TCP_BUFFER_SIZE;
+
+use esp32c3_embassy::http_service::HttpService;
+use esp32c3_embassy::http_service::Priority;
+use esp32c3_embassy::http_service::ReplySignal;
 
-//mod clock;
-//use self::clock::Clock;
-//use self::clock::Error as ClockError;
+use esp32c3_embassy::inflate::Inflater;
 
-//mod worldtimeapi;
+use esp32c3_embassy::sntp::SntpClient;
+use esp32c3_embassy::sntp::DEFAULT_SERVERS as SNTP_SERVERS;
+
+use esp32c3_embassy::timesync::TimeSync;
+
+use esp32c3_embassy::tls::Credentials as TlsCredentials;
+use esp32c3_embassy::tls::TlsBuffers;
+use esp32c3_embassy::tls::RECORD_BUFFER_SIZE;
+use esp32c3_embassy::tls::Verification;
+
+use esp32c3_embassy::websocket::connect as connect_websocket;
+use esp32c3_embassy::websocket::Error as WebSocketError;
+use esp32c3_embassy::websocket::WebSocket;
 
 use embassy_net::{
     Runner,
     StackResources,
     dns::DnsSocket,
+    udp::{PacketMetadata, UdpSocket},
     tcp::client::{TcpClient, TcpClientState},
 };
 use esp_alloc as _;
@@ -48,6 +74,20 @@ use esp_hal::{
 
 use esp_println::println;
 
+use static_cell::ConstStaticCell;
+
+use embassy_futures::select::{select, Either};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+
+use embedded_io_async::{Read, Write};
+
+use heapless::String;
+
+use serde::Serialize;
+
+use time::UtcOffset;
+
 use esp_radio::wifi::{
     Config,
     ControllerConfig,
@@ -57,11 +97,6 @@ use esp_radio::wifi::{
     sta::StationConfig,
 };
 
-use reqwless::{
-    client::HttpClient,
-    request::{Method, RequestBuilder},
-};
-
 use mpu6050_async_driver::{Address, I2cBus, Mpu6050Async};
 
 //use esp_mbedtls::Tls;
@@ -105,6 +140,63 @@ macro_rules! mk_static {
     }};
 }
 
+/// HTTP client over the embassy-net stack
+type EmbassyHttpClient = HttpClient<
+    'static,
+    TcpClient<'static, 1, TCP_BUFFER_SIZE, TCP_BUFFER_SIZE>,
+    DnsSocket<'static>,
+    RngWrapper,
+>;
+
+/// CA roots trusted for HTTPS, such as World Time API: ISRG Root X2 of
+/// Let's Encrypt and GTS Root R4 of Google Trust Services, both ECDSA
+const CA_ROOTS: &[&[u8]] = &[
+    include_bytes!("../certs/isrg-root-x2.der"),
+    include_bytes!("../certs/gts-root-r4.der"),
+];
+
+/// SHA-256 digests of the public keys the dashboard may present over `wss://`
+///
+/// The dashboard runs on the local network with its own certificate, so its
+/// key must be pinned here before switching to `wss://`, until then TLS
+/// connections to it are refused.
+const DASHBOARD_PINS: &[[u8; 32]] = &[];
+
+/// Dashboard receiving MPU-6050 samples, use `wss://` once its key is in
+/// [`DASHBOARD_PINS`]
+const DASHBOARD_URL: &str = "ws://192.168.0.10:8080/samples";
+
+/// Interval between pings to the dashboard
+const DASHBOARD_KEEP_ALIVE: Duration = Duration::from_secs(20);
+
+/// Time the dashboard has to answer a ping
+const DASHBOARD_PONG_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Maximal length of a serialized sample
+const SAMPLE_JSON_SIZE: usize = 256;
+
+/// A motion sample, streamed to the dashboard
+#[derive(Clone, Debug, Serialize)]
+struct Sample {
+    /// Acceleration in m/s2
+    accel: [f32; 3],
+
+    /// Angular velocity in degrees per second
+    gyro: [f32; 3],
+
+    /// Temperature in Celsius
+    temperature: f32,
+}
+
+/// Samples waiting to be streamed to the dashboard
+static SAMPLES: Channel<CriticalSectionRawMutex, Sample, 8> = Channel::new();
+
+/// Local time offset from UTC, in seconds
+const UTC_OFFSET_SECONDS: i32 = -3 * 3600;
+
+/// Server whose `Date` header is a last resort time source
+const DATE_URL: &str = "http://httpbin.org/";
+
 const SSID: &str = "SE28CP";
 const PASSWORD: &str = "12345678";
 //const SSID: &str = "UTFPR-SERVIDOR";
@@ -168,16 +260,15 @@ async fn i2c_worker_task(i2c: I2c<'static, Async>) {
         */
         match sensor.get_motion().await {
             Ok(motion) => {
-                println!(
-                    "accel (m/s2): x={:>7.3} y={:>7.3} z={:>7.3} | gyro (dps): x={:>7.3} y={:>7.3} z={:>7.3} | temp: {:>6.2} C",
-                    motion.accel.0,
-                    motion.accel.1,
-                    motion.accel.2,
-                    motion.gyro.0,
-                    motion.gyro.1,
-                    motion.gyro.2,
-                    motion.temperature_celsius,
-                );
+                let sample = Sample {
+                    accel: [motion.accel.0, motion.accel.1, motion.accel.2],
+                    gyro: [motion.gyro.0, motion.gyro.1, motion.gyro.2],
+                    temperature: motion.temperature_celsius,
+                };
+                // Drop samples while the dashboard is not connected
+                if SAMPLES.try_send(sample).is_err() {
+                    log::debug!("Dashboard is not keeping up, drop sample");
+                }
             }
             Err(_) => {
                 println!("failed to read MPU-6050 motion data");
@@ -189,6 +280,49 @@ async fn i2c_worker_task(i2c: I2c<'static, Async>) {
     }
 }
 
+#[embassy_executor::task]
+async fn dashboard_task(client: &'static mut EmbassyHttpClient, rng: RngWrapper) {
+    loop {
+        match connect_websocket(client, DASHBOARD_URL, &[], rng.clone()).await {
+            Ok(socket) => {
+                println!("Connected to dashboard");
+                let error = stream_samples(socket).await;
+                println!("Dashboard connection lost: {:?}", error);
+            }
+            Err(e) => println!("Failed to connect to dashboard: {:?}", e),
+        }
+        Timer::after(Duration::from_secs(5)).await;
+    }
+}
+
+/// Stream samples to the dashboard until the connection fails
+async fn stream_samples<S>(mut socket: WebSocket<S, RngWrapper>) -> WebSocketError
+where
+    S: Read + Write,
+{
+    let mut next_ping = Instant::now() + DASHBOARD_KEEP_ALIVE;
+
+    loop {
+        let result = match select(SAMPLES.receive(), Timer::at(next_ping)).await {
+            Either::First(sample) => match serde_json_core::to_string::<_, SAMPLE_JSON_SIZE>(&sample) {
+                Ok(json) => socket.send_text(&json).await,
+                Err(e) => {
+                    println!("Failed to serialize sample: {:?}", e);
+                    Ok(())
+                }
+            },
+            Either::Second(()) => {
+                next_ping = Instant::now() + DASHBOARD_KEEP_ALIVE;
+                socket.keep_alive(DASHBOARD_PONG_TIMEOUT).await
+            }
+        };
+
+        if let Err(error) = result {
+            return error;
+        }
+    }
+}
+
 #[embassy_executor::task]
 async fn run() {
     loop {
@@ -234,7 +368,7 @@ async fn main(spawner: Spawner) -> ! {
     let (stack, runner) = embassy_net::new(
         wifi_interface,
         config,
-        mk_static!(StackResources<3>, StackResources::<3>::new()),
+        mk_static!(StackResources<5>, StackResources::<5>::new()),
         seed,
     );
 
@@ -254,15 +388,74 @@ async fn main(spawner: Spawner) -> ! {
         println!("Got IP: {}", config.address);
     }
 
-    // Init HTTP client
-    let tcp_client = TcpClient::new(
-        stack,
-        mk_static!(
-            TcpClientState<1, 1500, 1500>,
-            TcpClientState::<1, 1500, 1500>::new()
-        ),
+    // Init HTTP client, owned by the HTTP service once the clock is synchronized
+    let tcp_client = mk_static!(
+        TcpClient<'static, 1, TCP_BUFFER_SIZE, TCP_BUFFER_SIZE>,
+        TcpClient::new(
+            stack,
+            mk_static!(
+                TcpClientState<1, TCP_BUFFER_SIZE, TCP_BUFFER_SIZE>,
+                TcpClientState::<1, TCP_BUFFER_SIZE, TCP_BUFFER_SIZE>::new()
+            ),
+        )
+    );
+    // TLS buffers are too large to be built on the stack, and are shared by all
+    // HTTP clients: one slot for the idle connection kept by the HTTP client,
+    // one for the dashboard connection
+    static TLS_BUFFERS: ConstStaticCell<TlsBuffers<2, RECORD_BUFFER_SIZE>> =
+        ConstStaticCell::new(TlsBuffers::new());
+    let tls_buffers: &'static TlsBuffers<2, RECORD_BUFFER_SIZE> = TLS_BUFFERS.take();
+
+    // Inflating needs a 32 KiB window, also kept in static memory
+    static INFLATER: ConstStaticCell<Inflater> = ConstStaticCell::new(Inflater::new());
+    let inflater = INFLATER.take();
+
+    let rng_wrapper = RngWrapper::from(rng);
+
+    let http_client = mk_static!(
+        EmbassyHttpClient,
+        HttpClient::new(
+            tcp_client,
+            DnsSocket::new(stack),
+            tls_buffers,
+            rng_wrapper.clone(),
+            Verification::CaRoots(CA_ROOTS),
+            TlsCredentials::None,
+        )
+        .with_keep_alive(Duration::from_secs(30))
+        .with_decompression(inflater)
     );
-    let dns_client = DnsSocket::new(stack);
+
+    // Synchronize the clock with SNTP, falling back to HTTP sources
+    let mut rx_meta = [PacketMetadata::EMPTY; 2];
+    let mut rx_buffer = [0_u8; 128];
+    let mut tx_meta = [PacketMetadata::EMPTY; 2];
+    let mut tx_buffer = [0_u8; 128];
+    let mut udp_socket = UdpSocket::new(stack, &mut rx_meta, &mut rx_buffer, &mut tx_meta, &mut tx_buffer);
+    if let Err(e) = udp_socket.bind(0) {
+        println!("Failed to bind UDP socket: {:?}", e);
+    }
+
+    let offset = UtcOffset::from_whole_seconds(UTC_OFFSET_SECONDS).unwrap_or(UtcOffset::UTC);
+    let sntp_client = SntpClient::new(DnsSocket::new(stack), SNTP_SERVERS);
+    let mut time_sync = TimeSync::new(sntp_client, DATE_URL, offset);
+    match time_sync.synchronize(&mut udp_socket, http_client).await {
+        Ok(sync) => match sync.clock.now() {
+            Ok(now) => println!("Current time: {} from {:?}, error {} ms", now, sync.source, sync.error.as_millis()),
+            Err(e) => println!("Clock error: {:?}", e),
+        },
+        Err(e) => {
+            println!("Failed to synchronize clock: {:?}", e);
+            // Any response seen so far carries a rough current time
+            if let Some(date) = http_client.server_date() {
+                match Clock::from_server_date(&date, offset).now() {
+                    Ok(now) => println!("Current time: {} from a Date header", now),
+                    Err(e) => println!("Clock error: {:?}", e),
+                }
+            }
+        }
+    }
+    drop(udp_socket);
 
     esp_println::println!("Initializing I2C Slave on I2C0...");
 
@@ -281,33 +474,62 @@ async fn main(spawner: Spawner) -> ! {
     // Dispara (spawn) a tarefa passando o driver por parâmetro
     spawner.spawn(i2c_worker_task(i2c_master).unwrap());
 
-    loop {
-        Timer::after(Duration::from_millis(1000)).await;
-
-        let mut client = HttpClient::new(&tcp_client, &dns_client);
-        let mut rx_buf = [0u8; 4096];
+    // The dashboard connection has its own socket, and shares TLS buffers
+    let dashboard_tcp_client = mk_static!(
+        TcpClient<'static, 1, TCP_BUFFER_SIZE, TCP_BUFFER_SIZE>,
+        TcpClient::new(
+            stack,
+            mk_static!(
+                TcpClientState<1, TCP_BUFFER_SIZE, TCP_BUFFER_SIZE>,
+                TcpClientState::<1, TCP_BUFFER_SIZE, TCP_BUFFER_SIZE>::new()
+            ),
+        )
+    );
+    let dashboard_client = mk_static!(
+        EmbassyHttpClient,
+        HttpClient::new(
+            dashboard_tcp_client,
+            DnsSocket::new(stack),
+            tls_buffers,
+            rng_wrapper.clone(),
+            Verification::PinnedKeys(DASHBOARD_PINS),
+            TlsCredentials::None,
+        )
+    );
+    spawner.spawn(dashboard_task(dashboard_client, rng_wrapper).unwrap());
 
-        let builder = client
-            .request(Method::GET, "http://httpbin.org/get?hello=Hello+esp-hal")
-            .await
-            .unwrap();
+    // From now on, all tasks send HTTP requests through the service
+    static HTTP_SERVICE: HttpService = HttpService::new();
+    spawner.spawn(http_service_task(&HTTP_SERVICE, http_client).unwrap());
 
-        let mut builder = builder.headers(&[("Host", "httpbin.org"), ("Connection", "close")]);
+    static REPLY: ReplySignal = ReplySignal::new();
 
-        let response = builder.send(&mut rx_buf).await.unwrap();
+    loop {
+        Timer::after(Duration::from_millis(1000)).await;
 
-        match response.body().read_to_end().await {
-            Ok(data) => {
-                if let Ok(st) = core::str::from_utf8(data) {
+        let request = HttpRequest::get("http://httpbin.org/get?hello=Hello+esp-hal")
+            .with_timeout(Duration::from_secs(10));
+        match HTTP_SERVICE
+            .request(Priority::Low, request, &REPLY)
+            .await
+            .and_then(|response| response.error_for_status())
+        {
+            Ok(response) => {
+                if let Ok(st) = core::str::from_utf8(&response.body) {
                     println!("Body: {}", st);
                 }
             }
-            Err(e) => println!("Body error: {:?}", e),
+            Err(e) => println!("Request error: {:?}", e),
         }
         Timer::after(Duration::from_millis(3000)).await;
     }
 }
 
+#[embassy_executor::task]
+async fn http_service_task(service: &'static HttpService, client: &'static mut EmbassyHttpClient) {
+    service.run(client).await
+}
+
 
 #[embassy_executor::task]
 async fn connection(mut controller: WifiController<'static>) {
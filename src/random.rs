@@ -6,8 +6,10 @@
 
 //! Random numbers generator
 
-use rand_core::CryptoRng;
-use rand_core::RngCore;
+use core::convert::Infallible;
+
+use rand_core::TryCryptoRng;
+use rand_core::TryRng;
 
 use esp_hal::rng::Rng;
 
@@ -22,24 +24,27 @@ impl From<Rng> for RngWrapper {
     }
 }
 
-impl RngCore for RngWrapper {
-    fn next_u32(&mut self) -> u32 {
-        self.0.random()
+impl TryRng for RngWrapper {
+    type Error = Infallible;
+
+    fn try_next_u32(&mut self) -> Result<u32, Self::Error> {
+        Ok(self.0.random())
     }
 
-    fn next_u64(&mut self) -> u64 {
-        u32_pair_to_u64(self.next_u32(), self.next_u32())
+    fn try_next_u64(&mut self) -> Result<u64, Self::Error> {
+        Ok(u32_pair_to_u64(self.0.random(), self.0.random()))
     }
 
-    fn fill_bytes(&mut self, dest: &mut [u8]) {
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), Self::Error> {
         for value in dest.iter_mut() {
-            let [random_value, _, _, _] = self.next_u32().to_be_bytes();
+            let [random_value, _, _, _] = self.0.random().to_be_bytes();
             *value = random_value;
         }
+        Ok(())
     }
 }
 
-impl CryptoRng for RngWrapper {}
+impl TryCryptoRng for RngWrapper {}
 
 /// Join a pair of `u32` into a `u64`
 #[allow(clippy::many_single_char_names, clippy::min_ident_chars)]
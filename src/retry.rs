@@ -0,0 +1,261 @@
+// Copyright Claudio Mattera 2024.
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Retry policy for HTTP requests
+
+use embassy_time::Duration;
+
+use enumset::EnumSet;
+use enumset::EnumSetType;
+
+use rand_core::Rng;
+
+use reqwless::request::Method;
+
+use crate::date;
+use crate::http::Error as HttpError;
+use crate::http::Headers;
+use crate::http::Response;
+
+/// Conditions under which a request is retried
+#[derive(Debug, EnumSetType)]
+pub enum RetryOn {
+    /// The request timed out
+    Timeout,
+
+    /// DNS resolution, TCP connection or TCP stream failed
+    Network,
+
+    /// Server responded with a 5xx status
+    ServerError,
+
+    /// Server responded with 429 Too Many Requests
+    TooManyRequests,
+}
+
+/// A retry policy with exponential backoff and jitter
+///
+/// The delay before the n-th retry is `base_delay * 2^(n - 1)`, capped to
+/// `max_delay`, of which a random half is added as jitter.  When a 429 or
+/// 503 response carries a `Retry-After` header, the delay is at least that
+/// long, and the request is not retried if it is longer than `max_delay`.
+///
+/// Requests with methods that are not idempotent, such as `POST` and
+/// `PATCH`, may have been processed by a server that timed out or failed,
+/// so they are retried after a timeout or a 5xx status only if enabled with
+/// [`RetryPolicy::with_non_idempotent_retries`].
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+    /// Maximal number of attempts, including the first one
+    max_attempts: u32,
+
+    /// Delay before the first retry
+    base_delay: Duration,
+
+    /// Maximal delay between attempts
+    max_delay: Duration,
+
+    /// Conditions under which a request is retried
+    retry_on: EnumSet<RetryOn>,
+
+    /// Whether requests with non-idempotent methods are retried after a
+    /// timeout or a 5xx status
+    non_idempotent: bool,
+}
+
+impl Default for RetryPolicy {
+    /// Three attempts, one second apart at first, on any transient error
+    fn default() -> Self {
+        Self::new(3, Duration::from_secs(1), Duration::from_secs(30))
+    }
+}
+
+impl RetryPolicy {
+    /// Create a new policy retrying on any transient error
+    pub const fn new(max_attempts: u32, base_delay: Duration, max_delay: Duration) -> Self {
+        Self {
+            max_attempts,
+            base_delay,
+            max_delay,
+            retry_on: EnumSet::all(),
+            non_idempotent: false,
+        }
+    }
+
+    /// Create a policy that never retries
+    #[allow(unused)]
+    pub const fn none() -> Self {
+        Self::new(1, Duration::from_secs(0), Duration::from_secs(0))
+    }
+
+    /// Set the conditions under which a request is retried
+    #[allow(unused)]
+    #[must_use]
+    pub fn with_retry_on(self, retry_on: EnumSet<RetryOn>) -> Self {
+        Self { retry_on, ..self }
+    }
+
+    /// Retry requests with non-idempotent methods after a timeout or a 5xx
+    /// status too
+    ///
+    /// Only for servers known to ignore repeated requests, a request that
+    /// timed out may have been processed.
+    #[allow(unused)]
+    #[must_use]
+    pub fn with_non_idempotent_retries(self) -> Self {
+        Self {
+            non_idempotent: true,
+            ..self
+        }
+    }
+
+    /// Return the maximal number of attempts
+    pub fn max_attempts(&self) -> u32 {
+        self.max_attempts
+    }
+
+    /// Check whether a failed request should be retried
+    pub fn should_retry_error(&self, method: Method, error: &HttpError) -> bool {
+        let reason = match error {
+            HttpError::Status(status, _) => status_reason(*status),
+            HttpError::Time(_) => Some(RetryOn::Timeout),
+            error if error.is_transient() => Some(RetryOn::Network),
+            _ => None,
+        };
+
+        reason.is_some_and(|reason| self.allows(method, reason))
+    }
+
+    /// Check whether a request should be retried after a response status
+    pub fn should_retry_status(&self, method: Method, status: u16) -> bool {
+        status_reason(status).is_some_and(|reason| self.allows(method, reason))
+    }
+
+    /// Check whether a request is retried for a reason
+    fn allows(&self, method: Method, reason: RetryOn) -> bool {
+        let repeatable = self.non_idempotent
+            || is_idempotent(method)
+            || !matches!(reason, RetryOn::Timeout | RetryOn::ServerError);
+        repeatable && self.retry_on.contains(reason)
+    }
+
+    /// Compute the delay before retrying after an attempt
+    ///
+    /// The `Retry-After` header of a 429 or 503 response is honored, and
+    /// `None` is returned if it asks to wait longer than the maximal delay.
+    pub fn delay(&self, attempt: u32, response: Option<&Response>, rng: &mut impl Rng) -> Option<Duration> {
+        let backoff = self.backoff(attempt, rng);
+
+        let retry_after = response
+            .filter(|response| matches!(response.status, 429 | 503))
+            .and_then(|response| retry_after(&response.headers));
+        match retry_after {
+            Some(retry_after) if retry_after > self.max_delay => None,
+            Some(retry_after) => Some(retry_after.max(backoff)),
+            None => Some(backoff),
+        }
+    }
+
+    /// Compute the exponential backoff after an attempt, with jitter
+    fn backoff(&self, attempt: u32, rng: &mut impl Rng) -> Duration {
+        let exponent = attempt.saturating_sub(1).min(16);
+        let delay = self
+            .base_delay
+            .checked_mul(1 << exponent)
+            .unwrap_or(self.max_delay)
+            .min(self.max_delay);
+
+        let half = delay.as_millis() / 2;
+        let jitter = u64::from(rng.next_u32()) % (half + 1);
+        Duration::from_millis(half + jitter)
+    }
+}
+
+/// Check whether sending a request twice has the same effect as once
+fn is_idempotent(method: Method) -> bool {
+    matches!(
+        method,
+        Method::GET | Method::HEAD | Method::PUT | Method::DELETE | Method::OPTIONS
+    )
+}
+
+/// Return the retry condition matching a response status
+fn status_reason(status: u16) -> Option<RetryOn> {
+    match status {
+        429 => Some(RetryOn::TooManyRequests),
+        500..=599 => Some(RetryOn::ServerError),
+        _ => None,
+    }
+}
+
+/// Parse a `Retry-After` header, given in seconds or as an HTTP date
+///
+/// A date is compared to the `Date` header of the same response, since
+/// both come from the server clock, and is ignored without one.  Dates in
+/// the past mean no delay.
+pub fn retry_after(headers: &Headers) -> Option<Duration> {
+    let value = headers.get("Retry-After")?;
+    if let Ok(seconds) = value.parse() {
+        return Some(Duration::from_secs(seconds));
+    }
+
+    let at = date::parse(value).ok()?;
+    let now = date::parse(headers.get("Date")?).ok()?;
+    let seconds = (at - now).whole_seconds();
+    Some(Duration::from_secs(u64::try_from(seconds).unwrap_or(0)))
+}
+
+/// Tests of `Retry-After` parsing
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Build headers from names and values
+    fn headers(pairs: &[(&str, &str)]) -> Headers {
+        let mut headers = Headers::default();
+        for (name, value) in pairs {
+            headers.push(name, value.as_bytes()).unwrap();
+        }
+        headers
+    }
+
+    #[test]
+    fn seconds() {
+        let headers = headers(&[("Retry-After", "120")]);
+        assert_eq!(retry_after(&headers), Some(Duration::from_secs(120)));
+    }
+
+    #[test]
+    fn date_is_relative_to_response_date() {
+        let headers = headers(&[
+            ("Date", "Sun, 06 Nov 1994 08:49:37 GMT"),
+            ("Retry-After", "Sun, 06 Nov 1994 08:50:07 GMT"),
+        ]);
+        assert_eq!(retry_after(&headers), Some(Duration::from_secs(30)));
+    }
+
+    #[test]
+    fn past_date_means_no_delay() {
+        let headers = headers(&[
+            ("Date", "Sun, 06 Nov 1994 08:49:37 GMT"),
+            ("Retry-After", "Sun, 06 Nov 1994 08:00:00 GMT"),
+        ]);
+        assert_eq!(retry_after(&headers), Some(Duration::from_secs(0)));
+    }
+
+    #[test]
+    fn date_without_response_date_is_ignored() {
+        let headers = headers(&[("Retry-After", "Sun, 06 Nov 1994 08:50:07 GMT")]);
+        assert_eq!(retry_after(&headers), None);
+    }
+
+    #[test]
+    fn missing_or_invalid_value_is_ignored() {
+        assert_eq!(retry_after(&headers(&[])), None);
+        assert_eq!(retry_after(&headers(&[("Retry-After", "soon")])), None);
+        assert_eq!(retry_after(&headers(&[("Retry-After", "-5")])), None);
+    }
+}
@@ -0,0 +1,541 @@
+// Copyright Claudio Mattera 2024.
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! SNTPv4 client
+//!
+//! The client asks a list of servers for the current time over UDP, as
+//! described by RFC 4330, and measures the offset between Unix time and the
+//! time since boot from the four timestamps of an exchange.  Servers are
+//! tried in order until one answers.
+//!
+//! Servers may answer with a kiss-o'-death packet: `DENY` and `RSTR` stop all
+//! further queries to that server, `RATE` makes the client wait longer and
+//! longer before asking it again.
+//!
+//! The packet codec only depends on byte slices, and the transport on the
+//! [`NtpSocket`] trait, so that the client also runs on a host.
+
+use core::net::IpAddr;
+use core::net::SocketAddr;
+
+use embassy_net::udp::UdpSocket;
+use embassy_net::IpAddress;
+use embassy_net::IpEndpoint;
+use embassy_time::Duration;
+use embassy_time::Instant;
+use embassy_time::WithTimeout as _;
+
+use embedded_nal_async::AddrType;
+use embedded_nal_async::Dns;
+
+use heapless::Vec;
+
+use log::debug;
+use log::warn;
+
+use crate::http::ErrorClass;
+
+/// Length of an SNTP packet without extensions
+pub const PACKET_SIZE: usize = 48;
+
+/// UDP port of NTP servers
+pub const NTP_PORT: u16 = 123;
+
+/// Default servers
+#[allow(unused)]
+pub const DEFAULT_SERVERS: &[&str] = &["pool.ntp.org", "time.google.com", "time.cloudflare.com"];
+
+/// Maximal number of servers
+const MAX_SERVERS: usize = 4;
+
+/// Seconds between the NTP epoch, 1900, and the Unix epoch, 1970
+const NTP_UNIX_OFFSET: u64 = 2_208_988_800;
+
+/// Protocol version sent in requests
+const VERSION: u8 = 4;
+
+/// Mode of client requests
+const MODE_CLIENT: u8 = 3;
+
+/// Mode of server responses
+const MODE_SERVER: u8 = 4;
+
+/// Mode of broadcast responses, accepted as answers to SNTP requests
+const MODE_BROADCAST: u8 = 5;
+
+/// Leap indicator of unsynchronized servers
+const LEAP_ALARM: u8 = 3;
+
+/// Highest valid stratum
+const MAX_STRATUM: u8 = 15;
+
+/// Time to wait for an answer, unless set otherwise
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// First delay before querying again a server that asked to slow down
+const RATE_BACKOFF: Duration = Duration::from_secs(64);
+
+/// Longest delay before querying again a server that asked to slow down
+const MAX_RATE_BACKOFF: Duration = Duration::from_secs(1024);
+
+/// A UDP socket able to exchange SNTP packets
+pub trait NtpSocket {
+    /// Send a datagram
+    #[allow(async_fn_in_trait)]
+    async fn send_to(&mut self, buffer: &[u8], remote: SocketAddr) -> Result<(), Error>;
+
+    /// Receive a datagram
+    ///
+    /// Return its length and sender.
+    #[allow(async_fn_in_trait)]
+    async fn receive_from(&mut self, buffer: &mut [u8]) -> Result<(usize, SocketAddr), Error>;
+}
+
+impl NtpSocket for UdpSocket<'_> {
+    async fn send_to(&mut self, buffer: &[u8], remote: SocketAddr) -> Result<(), Error> {
+        let IpAddr::V4(address) = remote.ip() else {
+            return Err(Error::Io);
+        };
+        let endpoint = IpEndpoint::new(IpAddress::Ipv4(address), remote.port());
+
+        UdpSocket::send_to(self, buffer, endpoint).await.map_err(|error| {
+            warn!("Failed to send SNTP request: {error:?}");
+            Error::Io
+        })
+    }
+
+    async fn receive_from(&mut self, buffer: &mut [u8]) -> Result<(usize, SocketAddr), Error> {
+        let (length, metadata) = self.recv_from(buffer).await.map_err(|error| {
+            warn!("Failed to receive SNTP response: {error:?}");
+            Error::Io
+        })?;
+
+        #[allow(unreachable_patterns)]
+        let address = match metadata.endpoint.addr {
+            IpAddress::Ipv4(address) => IpAddr::V4(address),
+            _ => return Err(Error::Io),
+        };
+
+        Ok((length, SocketAddr::new(address, metadata.endpoint.port)))
+    }
+}
+
+/// An NTP timestamp, seconds and fraction as 32.32 fixed point
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Timestamp(pub u64);
+
+impl Timestamp {
+    /// Convert microseconds since an arbitrary origin
+    pub fn from_micros(micros: u64) -> Self {
+        let seconds = micros / 1_000_000;
+        let fraction = ((micros % 1_000_000) << 32) / 1_000_000;
+        Self(seconds << 32 | fraction)
+    }
+
+    /// Convert to microseconds since the Unix epoch
+    ///
+    /// Seconds in the lower half of the range are taken from the era
+    /// starting in 2036.
+    pub fn to_unix_micros(self) -> i64 {
+        let mut seconds = self.0 >> 32;
+        if seconds < 1 << 31 {
+            seconds += 1 << 32;
+        }
+        let fraction = ((self.0 & 0xffff_ffff) * 1_000_000) >> 32;
+
+        // Times between 1968 and 1970 are before the Unix epoch
+        #[allow(clippy::cast_possible_wrap)]
+        {
+            (seconds as i64 - NTP_UNIX_OFFSET as i64) * 1_000_000 + fraction as i64
+        }
+    }
+}
+
+/// Fields of an SNTP packet
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Packet {
+    /// Leap indicator
+    pub leap: u8,
+
+    /// Protocol version
+    pub version: u8,
+
+    /// Association mode
+    pub mode: u8,
+
+    /// Distance from the reference clock, zero for kiss-o'-death packets
+    pub stratum: u8,
+
+    /// Reference identifier, or kiss code
+    pub reference_id: [u8; 4],
+
+    /// Time the request left the client, as sent by the client
+    pub origin: Timestamp,
+
+    /// Time the request reached the server
+    pub receive: Timestamp,
+
+    /// Time the response left the server
+    pub transmit: Timestamp,
+}
+
+impl Packet {
+    /// Create a client request
+    pub fn request(transmit: Timestamp) -> Self {
+        Self {
+            version: VERSION,
+            mode: MODE_CLIENT,
+            transmit,
+            ..Self::default()
+        }
+    }
+
+    /// Encode a packet
+    ///
+    /// Fields not stored in [`Packet`] are sent as zero, as clients do.
+    pub fn encode(&self) -> [u8; PACKET_SIZE] {
+        let mut bytes = [0_u8; PACKET_SIZE];
+        bytes[0] = (self.leap & 0x3) << 6 | (self.version & 0x7) << 3 | self.mode & 0x7;
+        bytes[1] = self.stratum;
+        bytes[12..16].copy_from_slice(&self.reference_id);
+        bytes[24..32].copy_from_slice(&self.origin.0.to_be_bytes());
+        bytes[32..40].copy_from_slice(&self.receive.0.to_be_bytes());
+        bytes[40..48].copy_from_slice(&self.transmit.0.to_be_bytes());
+        bytes
+    }
+
+    /// Decode a packet, ignoring extensions
+    pub fn decode(bytes: &[u8]) -> Result<Self, Error> {
+        let bytes = bytes.get(..PACKET_SIZE).ok_or(Error::Malformed)?;
+        let timestamp = |offset: usize| {
+            let mut value = [0_u8; 8];
+            value.copy_from_slice(&bytes[offset..offset + 8]);
+            Timestamp(u64::from_be_bytes(value))
+        };
+
+        let mut reference_id = [0_u8; 4];
+        reference_id.copy_from_slice(&bytes[12..16]);
+
+        Ok(Self {
+            leap: bytes[0] >> 6,
+            version: (bytes[0] >> 3) & 0x7,
+            mode: bytes[0] & 0x7,
+            stratum: bytes[1],
+            reference_id,
+            origin: timestamp(24),
+            receive: timestamp(32),
+            transmit: timestamp(40),
+        })
+    }
+
+    /// Check that this packet answers a request
+    ///
+    /// Kiss-o'-death packets are reported as errors.
+    pub fn validate(&self, request: &Self) -> Result<(), Error> {
+        if !matches!(self.mode, MODE_SERVER | MODE_BROADCAST) || !(1..=VERSION).contains(&self.version) {
+            return Err(Error::Malformed);
+        }
+        if self.origin != request.transmit {
+            return Err(Error::UnexpectedResponse);
+        }
+        if self.stratum == 0 {
+            return Err(Error::KissOfDeath(KissCode(self.reference_id)));
+        }
+        if self.leap == LEAP_ALARM || self.stratum > MAX_STRATUM || self.transmit.0 == 0 {
+            return Err(Error::Unsynchronized);
+        }
+        Ok(())
+    }
+}
+
+/// Code of a kiss-o'-death packet
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct KissCode(pub [u8; 4]);
+
+impl KissCode {
+    /// Check whether the server asks to stop querying it
+    pub fn is_denied(self) -> bool {
+        matches!(&self.0, b"DENY" | b"RSTR")
+    }
+
+    /// Check whether the server asks to slow down
+    pub fn is_rate(self) -> bool {
+        &self.0 == b"RATE"
+    }
+}
+
+/// Result of an exchange with a server
+#[derive(Clone, Copy, Debug)]
+pub struct Measurement {
+    /// Unix time minus time since boot, in microseconds
+    pub offset: i64,
+
+    /// Round-trip delay, excluding time spent within the server
+    pub delay: Duration,
+
+    /// Stratum of the server
+    pub stratum: u8,
+}
+
+impl Measurement {
+    /// Compute a measurement from the timestamps of an exchange
+    ///
+    /// Times of the client, when the request left and when the response
+    /// arrived, are in microseconds since boot.
+    pub fn from_timestamps(sent: u64, response: &Packet, received: u64) -> Self {
+        #[allow(clippy::cast_possible_wrap)]
+        let (sent, received) = (sent as i64, received as i64);
+        let server_received = response.receive.to_unix_micros();
+        let server_sent = response.transmit.to_unix_micros();
+
+        let offset = ((server_received - sent) + (server_sent - received)) / 2;
+        let delay = (received - sent) - (server_sent - server_received);
+
+        #[allow(clippy::cast_sign_loss)]
+        let delay = Duration::from_micros(delay.max(0) as u64);
+
+        Self {
+            offset,
+            delay,
+            stratum: response.stratum,
+        }
+    }
+
+    /// Return the Unix time in microseconds at an instant
+    pub fn unix_micros_at(&self, instant: Instant) -> i64 {
+        #[allow(clippy::cast_possible_wrap)]
+        let since_boot = instant.as_micros() as i64;
+        since_boot + self.offset
+    }
+}
+
+/// What the client knows about a server
+#[derive(Clone, Copy, Debug)]
+struct ServerState {
+    /// Whether the server asked to stop querying it
+    denied: bool,
+
+    /// When the server may be queried again
+    not_before: Instant,
+
+    /// Delay applied after the next `RATE` kiss
+    backoff: Duration,
+}
+
+/// SNTP client
+pub struct SntpClient<'a, D> {
+    /// DNS resolver
+    dns: D,
+
+    /// Server host names or addresses, in order of preference
+    servers: &'a [&'a str],
+
+    /// State of each server
+    states: Vec<ServerState, MAX_SERVERS>,
+
+    /// Time to wait for an answer
+    timeout: Duration,
+}
+
+impl<'a, D> SntpClient<'a, D>
+where
+    D: Dns,
+{
+    /// Create a new client
+    ///
+    /// Only the first [`MAX_SERVERS`] servers are queried.
+    pub fn new(dns: D, servers: &'a [&'a str]) -> Self {
+        let servers = &servers[..servers.len().min(MAX_SERVERS)];
+        let state = ServerState {
+            denied: false,
+            not_before: Instant::MIN,
+            backoff: RATE_BACKOFF,
+        };
+        let states = servers.iter().map(|_| state).collect();
+
+        Self {
+            dns,
+            servers,
+            states,
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+
+    /// Set the time to wait for an answer from each server
+    #[allow(unused)]
+    #[must_use]
+    pub fn with_timeout(self, timeout: Duration) -> Self {
+        Self { timeout, ..self }
+    }
+
+    /// Query servers in order until one answers
+    ///
+    /// Servers that sent kiss-o'-death packets are skipped, for good or
+    /// until their backoff is over.  Fail with the error of the last server
+    /// queried.
+    pub async fn query(&mut self, socket: &mut impl NtpSocket) -> Result<Measurement, Error> {
+        let mut last_error = Error::NoServer;
+
+        for (server, state) in self.servers.iter().zip(self.states.iter_mut()) {
+            if state.denied || state.not_before > Instant::now() {
+                debug!("Skip SNTP server {server}");
+                continue;
+            }
+
+            let result = query_server(&self.dns, socket, server)
+                .with_timeout(self.timeout)
+                .await
+                .unwrap_or(Err(Error::Timeout));
+
+            match result {
+                Ok(measurement) => {
+                    debug!(
+                        "SNTP server {server} at stratum {}: offset {} us, delay {} us",
+                        measurement.stratum,
+                        measurement.offset,
+                        measurement.delay.as_micros(),
+                    );
+                    state.backoff = RATE_BACKOFF;
+                    return Ok(measurement);
+                }
+                Err(Error::KissOfDeath(code)) if code.is_denied() => {
+                    warn!("SNTP server {server} denied access");
+                    state.denied = true;
+                    last_error = Error::KissOfDeath(code);
+                }
+                Err(Error::KissOfDeath(code)) if code.is_rate() => {
+                    warn!("SNTP server {server} asks to slow down");
+                    state.not_before = Instant::now() + state.backoff;
+                    state.backoff = (state.backoff * 2).min(MAX_RATE_BACKOFF);
+                    last_error = Error::KissOfDeath(code);
+                }
+                Err(error) => {
+                    warn!("SNTP server {server} failed: {error:?}");
+                    last_error = error;
+                }
+            }
+        }
+
+        Err(last_error)
+    }
+}
+
+/// Exchange a request and a response with a server
+///
+/// Datagrams from other addresses or answering other requests are ignored.
+async fn query_server<D>(dns: &D, socket: &mut impl NtpSocket, server: &str) -> Result<Measurement, Error>
+where
+    D: Dns,
+{
+    let address = dns.get_host_by_name(server, AddrType::IPv4).await.map_err(|error| {
+        warn!("Failed to resolve {server}: {error:?}");
+        Error::Dns
+    })?;
+    let remote = SocketAddr::new(address, NTP_PORT);
+
+    let sent = Instant::now().as_micros();
+    let request = Packet::request(Timestamp::from_micros(sent));
+    socket.send_to(&request.encode(), remote).await?;
+
+    let mut buffer = [0_u8; PACKET_SIZE];
+    loop {
+        let (length, sender) = socket.receive_from(&mut buffer).await?;
+        let received = Instant::now().as_micros();
+
+        if sender != remote {
+            debug!("Ignore datagram from {sender}");
+            continue;
+        }
+
+        let response = Packet::decode(&buffer[..length])?;
+        match response.validate(&request) {
+            Ok(()) => return Ok(Measurement::from_timestamps(sent, &response, received)),
+            Err(Error::UnexpectedResponse) => debug!("Ignore response to another request"),
+            Err(error) => return Err(error),
+        }
+    }
+}
+
+/// An error within an SNTP query
+#[derive(Clone, Copy, Debug)]
+pub enum Error {
+    /// Server name could not be resolved
+    Dns,
+
+    /// Error within the UDP socket
+    Io,
+
+    /// Server did not answer in time
+    Timeout,
+
+    /// Response is not a valid SNTP packet
+    Malformed,
+
+    /// Response does not answer the request
+    UnexpectedResponse,
+
+    /// Server is not synchronized
+    Unsynchronized,
+
+    /// Server sent a kiss-o'-death packet
+    KissOfDeath(#[allow(unused)] KissCode),
+
+    /// No server may be queried now
+    NoServer,
+}
+
+impl Error {
+    /// Classify this error as transient or permanent
+    pub fn class(&self) -> ErrorClass {
+        match self {
+            Self::KissOfDeath(code) if code.is_denied() => ErrorClass::Permanent,
+            Self::Malformed => ErrorClass::Permanent,
+            Self::Dns
+            | Self::Io
+            | Self::NoServer
+            | Self::Timeout
+            | Self::UnexpectedResponse
+            | Self::Unsynchronized
+            | Self::KissOfDeath(_) => ErrorClass::Transient,
+        }
+    }
+}
+
+/// Tests of timestamp conversions
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unix_epoch() {
+        assert_eq!(Timestamp(NTP_UNIX_OFFSET << 32).to_unix_micros(), 0);
+    }
+
+    #[test]
+    fn fraction_is_converted_to_micros() {
+        let timestamp = Timestamp(NTP_UNIX_OFFSET << 32 | 0x8000_0000);
+        assert_eq!(timestamp.to_unix_micros(), 500_000);
+    }
+
+    #[test]
+    fn lower_half_is_in_the_next_era() {
+        // 2036-02-07 06:28:16 UTC
+        assert_eq!(Timestamp(0).to_unix_micros(), 2_085_978_496_000_000);
+        assert_eq!(Timestamp(1 << 32).to_unix_micros(), 2_085_978_497_000_000);
+    }
+
+    #[test]
+    fn upper_half_before_epoch_is_negative() {
+        // 1968-01-20 03:14:08 UTC
+        assert_eq!(Timestamp(0x8000_0000 << 32).to_unix_micros(), -61_505_152_000_000);
+    }
+
+    #[test]
+    fn micros_are_converted_to_fixed_point() {
+        assert_eq!(Timestamp::from_micros(1_500_000), Timestamp(1 << 32 | 0x8000_0000));
+        assert_eq!(Timestamp::from_micros(0), Timestamp(0));
+    }
+}
@@ -0,0 +1,366 @@
+// Copyright Claudio Mattera 2024.
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Server-Sent Events client
+//!
+//! An [`SseClient`] keeps a `text/event-stream` response open and sends the
+//! events it receives to a channel, from which any task can receive them.
+//! When the connection ends it reconnects after the delay requested by the
+//! server, sending the identifier of the last event in `Last-Event-ID` so that
+//! the server can replay the events sent in the meantime.
+//!
+//! If the channel is full, the connection is closed and reopened later, so
+//! that events are replayed rather than silently dropped.
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+use embassy_sync::channel::Sender;
+use embassy_time::Duration;
+use embassy_time::Timer;
+
+use heapless::String;
+use heapless::Vec;
+
+use log::debug;
+use log::warn;
+
+use crate::auth::Authenticator;
+use crate::http::ClientTrait;
+use crate::http::Error;
+use crate::http::Request;
+
+/// Maximal length of a line
+const LINE_SIZE: usize = 512;
+
+/// Maximal length of an event type
+const EVENT_SIZE: usize = 32;
+
+/// Maximal length of event data
+const DATA_SIZE: usize = 512;
+
+/// Maximal length of an event identifier
+const ID_SIZE: usize = 64;
+
+/// Delay before reconnecting, unless the server sets one
+const DEFAULT_RETRY: Duration = Duration::from_secs(3);
+
+/// Time after which a connection is closed and reopened
+const DEFAULT_CONNECTION_TIME: Duration = Duration::from_secs(600);
+
+/// A channel of events
+#[allow(unused)]
+pub type EventChannel<const N: usize> = Channel<CriticalSectionRawMutex, Event, N>;
+
+/// An event
+#[derive(Clone, Debug, Default)]
+pub struct Event {
+    /// Event type, `message` unless set by the server
+    pub event: String<EVENT_SIZE>,
+
+    /// Event data, lines joined by line feeds
+    pub data: String<DATA_SIZE>,
+
+    /// Last event identifier set by the server, if any
+    pub id: String<ID_SIZE>,
+}
+
+/// Parser of an event stream
+///
+/// Events are parsed as specified by the WHATWG HTML standard.  Lines longer
+/// than [`LINE_SIZE`] are skipped, and so are events with too long fields.
+#[derive(Debug, Default)]
+pub struct EventParser {
+    /// Current line
+    line: Vec<u8, LINE_SIZE>,
+
+    /// Whether the current line is too long
+    line_overflow: bool,
+
+    /// Whether the last byte was a carriage return
+    after_cr: bool,
+
+    /// Type of the current event
+    event: String<EVENT_SIZE>,
+
+    /// Data of the current event
+    data: String<DATA_SIZE>,
+
+    /// Whether a field of the current event is too long
+    event_overflow: bool,
+
+    /// Identifier set by the last `id` field
+    id: String<ID_SIZE>,
+
+    /// Identifier of the last dispatched event
+    last_event_id: String<ID_SIZE>,
+
+    /// Reconnection delay set by the server
+    retry: Option<Duration>,
+}
+
+#[allow(clippy::result_large_err)]
+impl EventParser {
+    /// Create a new parser resuming after an event
+    pub fn new(last_event_id: String<ID_SIZE>) -> Self {
+        Self {
+            id: last_event_id.clone(),
+            last_event_id,
+            ..Self::default()
+        }
+    }
+
+    /// Return the identifier of the last dispatched event
+    pub fn last_event_id(&self) -> &str {
+        &self.last_event_id
+    }
+
+    /// Return the reconnection delay set by the server, if any
+    pub fn retry(&self) -> Option<Duration> {
+        self.retry
+    }
+
+    /// Parse a chunk of the stream and pass complete events to a callback
+    ///
+    /// If the callback fails, the event is not considered dispatched.
+    pub fn feed<F>(&mut self, chunk: &[u8], on_event: &mut F) -> Result<(), Error>
+    where
+        F: FnMut(Event) -> Result<(), Error>,
+    {
+        for &byte in chunk {
+            let after_cr = self.after_cr;
+            self.after_cr = byte == b'\r';
+
+            match byte {
+                b'\n' if after_cr => {}
+                b'\r' | b'\n' => self.end_line(on_event)?,
+                _ => {
+                    if self.line.push(byte).is_err() {
+                        self.line_overflow = true;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Process a complete line
+    fn end_line<F>(&mut self, on_event: &mut F) -> Result<(), Error>
+    where
+        F: FnMut(Event) -> Result<(), Error>,
+    {
+        let line = core::mem::take(&mut self.line);
+        if core::mem::take(&mut self.line_overflow) {
+            warn!("Skip line too long");
+            self.event_overflow = true;
+            return Ok(());
+        }
+
+        let Ok(line) = core::str::from_utf8(&line) else {
+            warn!("Skip line not valid UTF-8");
+            return Ok(());
+        };
+
+        if line.is_empty() {
+            return self.dispatch(on_event);
+        }
+
+        if line.starts_with(':') {
+            // Comment, usually a heartbeat
+            return Ok(());
+        }
+
+        let (field, value) = line.split_once(':').unwrap_or((line, ""));
+        let value = value.strip_prefix(' ').unwrap_or(value);
+
+        match field {
+            "event" => {
+                self.event.clear();
+                if self.event.push_str(value).is_err() {
+                    self.event_overflow = true;
+                }
+            }
+            "data" => {
+                if self.data.push_str(value).is_err() || self.data.push('\n').is_err() {
+                    self.event_overflow = true;
+                }
+            }
+            "id" if !value.contains('\0') => {
+                self.id.clear();
+                if self.id.push_str(value).is_err() {
+                    warn!("Skip event identifier too long");
+                    self.id.clear();
+                }
+            }
+            "retry" => {
+                if let Ok(milliseconds) = value.parse() {
+                    self.retry = Some(Duration::from_millis(milliseconds));
+                }
+            }
+            _ => debug!("Skip unknown field {field}"),
+        }
+
+        Ok(())
+    }
+
+    /// Dispatch the current event
+    fn dispatch<F>(&mut self, on_event: &mut F) -> Result<(), Error>
+    where
+        F: FnMut(Event) -> Result<(), Error>,
+    {
+        let mut event = core::mem::take(&mut self.event);
+        let mut data = core::mem::take(&mut self.data);
+
+        if core::mem::take(&mut self.event_overflow) {
+            warn!("Skip event too long");
+            return Ok(());
+        }
+
+        if data.is_empty() {
+            self.last_event_id.clone_from(&self.id);
+            return Ok(());
+        }
+
+        if data.ends_with('\n') {
+            data.pop();
+        }
+        if event.is_empty() {
+            // "message" always fits
+            let _ = event.push_str("message");
+        }
+
+        on_event(Event {
+            event,
+            data,
+            id: self.id.clone(),
+        })?;
+
+        self.last_event_id.clone_from(&self.id);
+        Ok(())
+    }
+}
+
+/// Server-Sent Events client
+pub struct SseClient<'a> {
+    /// Event stream URL
+    url: &'a str,
+
+    /// Authenticator for requests
+    authenticator: Option<&'a dyn Authenticator>,
+
+    /// Time after which a connection is closed and reopened
+    connection_time: Duration,
+
+    /// Identifier of the last received event
+    last_event_id: String<ID_SIZE>,
+
+    /// Delay before reconnecting
+    retry: Duration,
+}
+
+#[allow(clippy::result_large_err)]
+impl<'a> SseClient<'a> {
+    /// Create a new client
+    #[allow(unused)]
+    pub fn new(url: &'a str) -> Self {
+        Self {
+            url,
+            authenticator: None,
+            connection_time: DEFAULT_CONNECTION_TIME,
+            last_event_id: String::new(),
+            retry: DEFAULT_RETRY,
+        }
+    }
+
+    /// Authenticate requests
+    #[allow(unused)]
+    #[must_use]
+    pub fn with_authenticator(self, authenticator: &'a dyn Authenticator) -> Self {
+        Self {
+            authenticator: Some(authenticator),
+            ..self
+        }
+    }
+
+    /// Set the time after which a connection is closed and reopened
+    ///
+    /// Requests have an end-to-end timeout, so connections are renewed
+    /// periodically, by default every ten minutes.  This also detects
+    /// connections that silently died.
+    #[allow(unused)]
+    #[must_use]
+    pub fn with_connection_time(self, connection_time: Duration) -> Self {
+        Self {
+            connection_time,
+            ..self
+        }
+    }
+
+    /// Receive events and send them to a channel, reconnecting as needed
+    ///
+    /// Return when the server responds 204 No Content, which asks clients to
+    /// stop reconnecting, or fail on permanent errors.
+    #[allow(unused)]
+    pub async fn run<C, const N: usize>(
+        &mut self,
+        client: &mut C,
+        events: Sender<'_, CriticalSectionRawMutex, Event, N>,
+    ) -> Result<(), Error>
+    where
+        C: ClientTrait,
+    {
+        loop {
+            let mut parser = EventParser::new(self.last_event_id.clone());
+            let result = {
+                let mut headers: Vec<(&str, &str), 3> = Vec::new();
+                let _ = headers.push(("Accept", "text/event-stream"));
+                let _ = headers.push(("Cache-Control", "no-cache"));
+                if !self.last_event_id.is_empty() {
+                    let _ = headers.push(("Last-Event-ID", self.last_event_id.as_str()));
+                }
+
+                // Error pages must not be parsed as events
+                let mut request = Request::get(self.url)
+                    .with_headers(&headers)
+                    .with_timeout(self.connection_time)
+                    .with_success_only();
+                if let Some(authenticator) = self.authenticator {
+                    request = request.with_authenticator(authenticator);
+                }
+
+                debug!("Open event stream {}", self.url);
+                client
+                    .request_stream(&request, |chunk| {
+                        parser.feed(chunk, &mut |event| events.try_send(event).map_err(|_| Error::Overflow))
+                    })
+                    .await
+            };
+
+            self.last_event_id = String::try_from(parser.last_event_id()).unwrap_or_default();
+            if let Some(retry) = parser.retry() {
+                self.retry = retry;
+            }
+
+            let delay = match result.and_then(|head| head.error_for_status()) {
+                Ok(head) if head.status == 204 => {
+                    debug!("Event stream {} closed by server", self.url);
+                    return Ok(());
+                }
+                Ok(_) => self.retry,
+                // The connection lasted as long as allowed
+                Err(Error::Time(_)) => Duration::from_secs(0),
+                Err(error) if error.is_transient() => {
+                    warn!("Event stream {} failed: {error:?}", self.url);
+                    self.retry
+                }
+                Err(error) => return Err(error),
+            };
+
+            debug!("Reconnect to event stream in {} ms", delay.as_millis());
+            Timer::after(delay).await;
+        }
+    }
+}
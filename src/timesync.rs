@@ -0,0 +1,274 @@
+// Copyright Claudio Mattera 2024.
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Time synchronization from several sources
+//!
+//! A [`TimeSync`] queries an ordered list of time sources and keeps the
+//! result with the smallest estimated error, so that a single unreachable
+//! service does not leave the device without wall-clock time.  Sources are
+//! queried until one is accurate enough, the following ones are skipped.
+//!
+//! The estimated error of a result grows with time, since the local clock
+//! drifts, so a later result may replace an earlier and more accurate one.
+
+use embassy_time::Duration;
+use embassy_time::Instant;
+
+use embedded_nal_async::Dns;
+
+use log::debug;
+use log::warn;
+
+use reqwless::request::Method;
+
+use time::UtcOffset;
+
+use crate::clock::Clock;
+use crate::clock::Error;
+use crate::date;
+use crate::http::Request;
+use crate::sntp::NtpSocket;
+use crate::sntp::SntpClient;
+use crate::worldtimeapi::WorldTimeApiClient;
+
+/// Sources, unless set otherwise
+#[allow(unused)]
+pub const DEFAULT_SOURCES: &[Source] = &[Source::Sntp, Source::WorldTimeApi, Source::HttpDate];
+
+/// Error below which no further source is queried
+const GOOD_ENOUGH: Duration = Duration::from_millis(100);
+
+/// Worst drift of the local clock, in parts per million
+const DRIFT_PPM: u64 = 50;
+
+/// Timeout of requests to HTTP sources
+const HTTP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Samples taken from World Time API, which rate-limits clients
+const WORLD_TIME_API_SAMPLES: u8 = 2;
+
+/// Resolution of sources reporting whole seconds
+const SECOND: Duration = Duration::from_secs(1);
+
+/// Age below which the `Date` of an earlier response is used instead of
+/// sending a request
+const DATE_MAX_AGE: Duration = Duration::from_secs(300);
+
+/// Estimated error of the `Date` of an earlier response, whose request
+/// duration is not known
+const EARLIER_DATE_ERROR: Duration = Duration::from_secs(2);
+
+/// A source of time
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Source {
+    /// SNTP servers
+    Sntp,
+
+    /// World Time API
+    WorldTimeApi,
+
+    /// `Date` header of an HTTP response
+    HttpDate,
+}
+
+/// Result of a synchronization
+#[derive(Clone, Debug)]
+pub struct Synchronization {
+    /// Synchronized clock
+    pub clock: Clock,
+
+    /// Source that provided the time
+    pub source: Source,
+
+    /// Estimated error of the time measurement
+    pub error: Duration,
+
+    /// When the time was measured
+    pub at: Instant,
+}
+
+impl Synchronization {
+    /// Return the estimated error, including the drift since measured
+    pub fn error_now(&self) -> Duration {
+        let drift = Duration::from_micros(self.at.elapsed().as_micros() * DRIFT_PPM / 1_000_000);
+        self.error + drift
+    }
+}
+
+/// Time synchronization manager
+pub struct TimeSync<'a, D> {
+    /// Sources, in order of preference
+    sources: &'a [Source],
+
+    /// SNTP client
+    sntp_client: SntpClient<'a, D>,
+
+    /// URL requested for its `Date` header
+    date_url: &'a str,
+
+    /// Local time offset
+    offset: UtcOffset,
+
+    /// Best result so far
+    best: Option<Synchronization>,
+}
+
+#[allow(clippy::result_large_err)]
+impl<'a, D> TimeSync<'a, D>
+where
+    D: Dns,
+{
+    /// Create a new manager
+    ///
+    /// The `Date` header is taken from a `HEAD` request to `date_url`, any
+    /// reachable server will do.
+    pub fn new(sntp_client: SntpClient<'a, D>, date_url: &'a str, offset: UtcOffset) -> Self {
+        Self {
+            sources: DEFAULT_SOURCES,
+            sntp_client,
+            date_url,
+            offset,
+            best: None,
+        }
+    }
+
+    /// Set the sources, in order of preference
+    #[allow(unused)]
+    #[must_use]
+    pub fn with_sources(self, sources: &'a [Source]) -> Self {
+        Self { sources, ..self }
+    }
+
+    /// Return the best result so far
+    #[allow(unused)]
+    pub fn best(&self) -> Option<&Synchronization> {
+        self.best.as_ref()
+    }
+
+    /// Query sources and keep the best result
+    ///
+    /// Fail only if no source ever succeeded, with the error of the last
+    /// source queried.
+    pub async fn synchronize(
+        &mut self,
+        socket: &mut impl NtpSocket,
+        http_client: &mut impl WorldTimeApiClient,
+    ) -> Result<&Synchronization, Error> {
+        let mut last_error = Error::NoSource;
+
+        let sources = self.sources;
+        for &source in sources {
+            let result = match source {
+                Source::Sntp => self.query_sntp(socket).await,
+                Source::WorldTimeApi => self.query_world_time_api(http_client).await,
+                Source::HttpDate => self.query_http_date(http_client).await,
+            };
+
+            let candidate = match result {
+                Ok(candidate) => candidate,
+                Err(error) => {
+                    warn!("Time source {source:?} failed: {error:?}");
+                    last_error = error;
+                    continue;
+                }
+            };
+
+            debug!("Time source {source:?} has error {} ms", candidate.error.as_millis());
+            let good_enough = candidate.error <= GOOD_ENOUGH;
+            let better = self
+                .best
+                .as_ref()
+                .is_none_or(|best| candidate.error < best.error_now());
+            if better {
+                self.best = Some(candidate);
+            }
+            if good_enough {
+                break;
+            }
+        }
+
+        match &self.best {
+            Some(best) => {
+                debug!("Clock synchronized from {:?}", best.source);
+                Ok(best)
+            }
+            None => Err(last_error),
+        }
+    }
+
+    /// Synchronize with SNTP servers
+    async fn query_sntp(&mut self, socket: &mut impl NtpSocket) -> Result<Synchronization, Error> {
+        let measurement = self.sntp_client.query(socket).await?;
+        let at = Instant::now();
+
+        #[allow(clippy::cast_sign_loss)]
+        let current_time = measurement.unix_micros_at(at) as u64;
+
+        Ok(Synchronization {
+            clock: Clock::from_unix_micros(current_time, at, self.offset),
+            source: Source::Sntp,
+            error: measurement.delay / 2,
+            at,
+        })
+    }
+
+    /// Synchronize with World Time API
+    async fn query_world_time_api(
+        &mut self,
+        http_client: &mut impl WorldTimeApiClient,
+    ) -> Result<Synchronization, Error> {
+        let synchronized =
+            Clock::from_server(http_client, HTTP_TIMEOUT, WORLD_TIME_API_SAMPLES).await?;
+
+        Ok(Synchronization {
+            clock: synchronized.clock,
+            source: Source::WorldTimeApi,
+            error: synchronized.error_bound,
+            at: Instant::now(),
+        })
+    }
+
+    /// Synchronize with the `Date` header of a response
+    ///
+    /// The time is reported in whole seconds, sometime during the request.
+    /// The `Date` recorded by the client is used if recent enough, and no
+    /// request is sent then.
+    async fn query_http_date(
+        &mut self,
+        http_client: &mut impl WorldTimeApiClient,
+    ) -> Result<Synchronization, Error> {
+        let recent = http_client
+            .server_date()
+            .filter(|date| date.received_at.elapsed() <= DATE_MAX_AGE);
+        if let Some(date) = recent {
+            debug!("Use Date received {} s ago", date.received_at.elapsed().as_secs());
+            return Ok(Synchronization {
+                clock: Clock::from_server_date(&date, self.offset),
+                source: Source::HttpDate,
+                error: EARLIER_DATE_ERROR,
+                at: date.received_at,
+            });
+        }
+
+        let start = Instant::now();
+        let request = Request::new(Method::HEAD, self.date_url).with_timeout(HTTP_TIMEOUT);
+        let response = http_client.request(&request).await?;
+        let at = Instant::now();
+
+        let date = response.headers.get("Date").ok_or(date::Error::Missing)?;
+        let now = date::parse(date)?;
+
+        #[allow(clippy::cast_sign_loss)]
+        let current_time = now.unix_timestamp() as u64;
+
+        Ok(Synchronization {
+            clock: Clock::new(current_time, self.offset),
+            source: Source::HttpDate,
+            error: (at - start) + SECOND,
+            at,
+        })
+    }
+}
@@ -0,0 +1,853 @@
+// Copyright Claudio Mattera 2024.
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! TLS connections with server certificate verification
+//!
+//! `reqwless` can only open TLS sessions with [`TlsVerify::None`] or a PSK,
+//! so every HTTPS server would be accepted.  This module instead opens the
+//! TLS session itself on top of a TCP connection, and requests are written
+//! to the resulting stream as if it were a plain socket.
+//!
+//! The server certificate chain is checked against CA roots or pinned public
+//! keys compiled into the image, and the leaf certificate must be valid for
+//! the requested host name.
+//!
+//! Clients can also authenticate themselves with a pre-shared key or with an
+//! X.509 certificate (mutual TLS), see [`Credentials`].
+//!
+//! Validity periods are not checked, because the device has no trusted
+//! wall-clock time until it has synchronized its clock, which itself
+//! requires HTTPS.
+//!
+//! [`TlsVerify::None`]: reqwless::client::TlsVerify::None
+
+use core::cell::Cell;
+use core::cell::UnsafeCell;
+use core::net::IpAddr;
+
+use const_oid::db::rfc5912::ECDSA_WITH_SHA_256;
+use const_oid::db::rfc5912::ECDSA_WITH_SHA_384;
+use const_oid::db::rfc5912::ID_EC_PUBLIC_KEY;
+use const_oid::db::rfc5912::SECP_256_R_1;
+use const_oid::db::rfc5912::SECP_384_R_1;
+use const_oid::ObjectIdentifier;
+
+use der::Decode as _;
+use der::Encode as _;
+
+use embedded_io_async::ErrorType;
+use embedded_io_async::Read;
+use embedded_io_async::Write;
+
+use embedded_tls::CertificateEntryRef;
+use embedded_tls::CertificateRef;
+use embedded_tls::CertificateVerifyRef;
+use embedded_tls::Aes128GcmSha256;
+use embedded_tls::Certificate;
+use embedded_tls::CryptoProvider;
+use embedded_tls::MaxFragmentLength;
+use embedded_tls::SignatureScheme;
+use embedded_tls::TlsConfig;
+use embedded_tls::TlsConnection;
+use embedded_tls::TlsContext;
+use embedded_tls::TlsError;
+use embedded_tls::TlsVerifier;
+
+use heapless::String;
+use heapless::Vec;
+
+use log::debug;
+use log::warn;
+
+use p256::ecdsa::DerSignature as P256DerSignature;
+use p256::ecdsa::Signature as P256Signature;
+use p256::ecdsa::SigningKey as P256SigningKey;
+use p256::ecdsa::VerifyingKey as P256VerifyingKey;
+use p256::pkcs8::DecodePrivateKey as _;
+use p384::ecdsa::Signature as P384Signature;
+use p384::ecdsa::VerifyingKey as P384VerifyingKey;
+
+use rand_chacha::rand_core::SeedableRng as _;
+use rand_chacha::ChaCha8Rng;
+
+use sha2::Digest as _;
+use sha2::Sha256;
+use sha2::Sha384;
+
+use signature::hazmat::PrehashVerifier as _;
+use signature::SignerMut;
+
+use x509_cert::ext::pkix::name::GeneralName;
+use x509_cert::ext::pkix::BasicConstraints;
+use x509_cert::ext::pkix::KeyUsage;
+use x509_cert::ext::pkix::SubjectAltName;
+use x509_cert::spki::SubjectPublicKeyInfoOwned;
+use x509_cert::Certificate as X509Certificate;
+
+/// Cipher suite used for all TLS sessions
+type CipherSuite = Aes128GcmSha256;
+
+/// Maximal length of a host name used for verification
+const HOSTNAME_SIZE: usize = 64;
+
+/// Maximal number of certificates in a chain sent by a server
+const MAX_CHAIN_LENGTH: usize = 4;
+
+/// Maximal length of a digest used in signatures
+const DIGEST_SIZE: usize = 48;
+
+/// Context string signed by a TLS 1.3 server in `CertificateVerify`
+const SERVER_CONTEXT: &[u8] = b"TLS 1.3, server CertificateVerify";
+
+/// Size of the message signed by a TLS 1.3 server in `CertificateVerify`
+const SIGNED_MESSAGE_SIZE: usize = 64 + SERVER_CONTEXT.len() + 1 + DIGEST_SIZE;
+
+/// How to verify the server certificate
+#[derive(Clone, Copy, Debug)]
+pub enum Verification {
+    /// Accept any server
+    ///
+    /// Only meant for testing, traffic is encrypted but not authenticated.
+    None,
+
+    /// Verify the certificate chain against trusted CA roots
+    ///
+    /// Roots are DER encoded certificates, typically compiled in with
+    /// `include_bytes!`.
+    CaRoots(&'static [&'static [u8]]),
+
+    /// Accept chains signed up to one of these public keys
+    ///
+    /// The pinned key may also be the one of the leaf certificate.
+    ///
+    /// Pins are SHA-256 digests of the DER encoded `SubjectPublicKeyInfo`,
+    /// the same format used by `openssl x509 -pubkey | openssl pkey -pubin
+    /// -outform der | openssl dgst -sha256`.
+    PinnedKeys(&'static [[u8; 32]]),
+}
+
+/// How the client authenticates itself to the server
+#[derive(Clone, Copy, Debug)]
+pub enum Credentials {
+    /// No client authentication
+    None,
+
+    /// TLS 1.3 pre-shared key
+    ///
+    /// The server certificate is not sent, so no verification takes place.
+    Psk {
+        /// PSK identity
+        identity: &'static [u8],
+
+        /// Pre-shared key
+        key: &'static [u8],
+    },
+
+    /// X.509 client certificate (mutual TLS)
+    ///
+    /// Both are DER encoded, and the private key must be a P-256 key in
+    /// PKCS#8 format, as produced by `openssl pkcs8 -topk8 -nocrypt -outform
+    /// der`.
+    Certificate {
+        /// Client certificate
+        certificate: &'static [u8],
+
+        /// Client private key
+        private_key: &'static [u8],
+    },
+}
+
+/// Size of TLS record buffers that fit any record
+///
+/// A full TLS record is 16 KiB of plaintext plus encryption overhead.
+pub const RECORD_BUFFER_SIZE: usize = 16640;
+
+/// Space needed in a record buffer beyond the record plaintext
+const RECORD_OVERHEAD: usize = 256;
+
+/// A pool of TLS record buffers
+///
+/// This trait is implemented by [`TlsBuffers`], and exists so that clients
+/// do not depend on the number and size of buffers in the pool.
+pub trait TlsBufferPool {
+    /// Borrow a pair of buffers, unless all are lent to sessions
+    fn lease(&self) -> Option<LeasedBuffers<'_>>;
+}
+
+/// A pair of TLS record buffers lent by a pool
+pub struct LeasedBuffers<'a> {
+    /// Buffer for received TLS records
+    read: &'a mut [u8],
+
+    /// Buffer for transmitted TLS records
+    write: &'a mut [u8],
+
+    /// Lease returning the buffers to the pool
+    lease: Lease<'a>,
+}
+
+/// Statically allocated pool of TLS record buffers
+///
+/// The pool holds `N` pairs of buffers of `SIZE` bytes each, so up to `N`
+/// TLS sessions can be open at the same time, from any number of clients.
+/// Buffers are lent to a session and returned when it is dropped, which lets
+/// a session outlive the request that opened it.
+///
+/// Buffers smaller than [`RECORD_BUFFER_SIZE`] only fit shorter records,
+/// so sessions negotiate a maximal fragment length with the server.  Servers
+/// that ignore this extension will fail with records too large.
+///
+/// The pool is large and should be placed in a `static`, for instance with
+/// [`static_cell::ConstStaticCell`], rather than built on the stack.
+pub struct TlsBuffers<const N: usize, const SIZE: usize> {
+    /// Pairs of buffers
+    slots: [Slot<SIZE>; N],
+}
+
+impl<const N: usize, const SIZE: usize> TlsBuffers<N, SIZE> {
+    /// Create a new pool
+    pub const fn new() -> Self {
+        Self {
+            slots: [const { Slot::new() }; N],
+        }
+    }
+}
+
+impl<const N: usize, const SIZE: usize> Default for TlsBuffers<N, SIZE> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize, const SIZE: usize> TlsBufferPool for TlsBuffers<N, SIZE> {
+    fn lease(&self) -> Option<LeasedBuffers<'_>> {
+        self.slots.iter().find_map(Slot::lease)
+    }
+}
+
+/// A pair of TLS record buffers
+struct Slot<const SIZE: usize> {
+    /// Buffer for received TLS records
+    read: UnsafeCell<[u8; SIZE]>,
+
+    /// Buffer for transmitted TLS records
+    write: UnsafeCell<[u8; SIZE]>,
+
+    /// Whether the buffers are lent to a session
+    in_use: Cell<bool>,
+}
+
+impl<const SIZE: usize> Slot<SIZE> {
+    /// Create a new pair of buffers
+    const fn new() -> Self {
+        Self {
+            read: UnsafeCell::new([0_u8; SIZE]),
+            write: UnsafeCell::new([0_u8; SIZE]),
+            in_use: Cell::new(false),
+        }
+    }
+
+    /// Borrow the buffers, unless they are already lent to a session
+    fn lease(&self) -> Option<LeasedBuffers<'_>> {
+        if self.in_use.replace(true) {
+            return None;
+        }
+
+        // SAFETY:
+        // The flag guarantees that the buffers are only borrowed once until
+        // the lease is dropped, and `TlsStream` drops its session before its
+        // lease.
+        let (read, write) = unsafe { (&mut *self.read.get(), &mut *self.write.get()) };
+
+        Some(LeasedBuffers {
+            read,
+            write,
+            lease: Lease { in_use: &self.in_use },
+        })
+    }
+}
+
+/// Choose the maximal fragment length fitting in record buffers
+///
+/// Return `None` if buffers fit any record, so that the extension is not
+/// needed.
+fn max_fragment_length(buffer_size: usize) -> Option<MaxFragmentLength> {
+    let fits = |length: usize| length + RECORD_OVERHEAD <= buffer_size;
+
+    if fits(16384) {
+        None
+    } else if fits(4096) {
+        Some(MaxFragmentLength::Bits12)
+    } else if fits(2048) {
+        Some(MaxFragmentLength::Bits11)
+    } else if fits(1024) {
+        Some(MaxFragmentLength::Bits10)
+    } else {
+        Some(MaxFragmentLength::Bits9)
+    }
+}
+
+/// A lease on TLS record buffers, which returns them when dropped
+struct Lease<'a> {
+    /// Flag to clear when the buffers are returned
+    in_use: &'a Cell<bool>,
+}
+
+impl Drop for Lease<'_> {
+    fn drop(&mut self) {
+        self.in_use.set(false);
+    }
+}
+
+/// A TLS session over a socket
+pub struct TlsStream<'a, S>
+where
+    S: Read + Write,
+{
+    /// TLS session
+    ///
+    /// It must be declared before the lease, so that it is dropped first.
+    connection: TlsConnection<'a, S, CipherSuite>,
+
+    /// Lease on the record buffers used by the session
+    _lease: Lease<'a>,
+}
+
+impl<S> ErrorType for TlsStream<'_, S>
+where
+    S: Read + Write,
+{
+    type Error = TlsError;
+}
+
+impl<S> Read for TlsStream<'_, S>
+where
+    S: Read + Write,
+{
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        self.connection.read(buf).await
+    }
+}
+
+impl<S> Write for TlsStream<'_, S>
+where
+    S: Read + Write,
+{
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        self.connection.write(buf).await
+    }
+
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        self.connection.flush().await
+    }
+}
+
+/// Open a TLS session over a socket
+///
+/// The server certificate must be valid for `server_name` and is checked
+/// according to `verification`, and the client authenticates itself with
+/// `credentials`.
+pub async fn open<'a, S>(
+    socket: S,
+    server_name: &str,
+    verification: Verification,
+    credentials: Credentials,
+    seed: u64,
+    buffers: &'a dyn TlsBufferPool,
+) -> Result<TlsStream<'a, S>, Error>
+where
+    S: Read + Write + 'a,
+{
+    let LeasedBuffers { read, write, lease } = buffers.lease().ok_or(Error::BuffersInUse)?;
+
+    debug!("Open TLS session with {server_name}");
+    let mut config = TlsConfig::new().with_server_name(server_name);
+    if let Some(length) = max_fragment_length(read.len().min(write.len())) {
+        debug!("Request maximal fragment length {length:?}");
+        config = config.with_max_fragment_length(length);
+    }
+    let config = match credentials {
+        Credentials::None => config,
+        Credentials::Psk { identity, key } => {
+            debug!("Authenticate with pre-shared key");
+            config.with_psk(key, &[identity])
+        }
+        Credentials::Certificate { .. } => {
+            debug!("Authenticate with client certificate");
+            config
+        }
+    };
+    let mut connection = TlsConnection::new(socket, read, write);
+
+    let failure = Cell::new(None);
+    let provider = VerifyingProvider {
+        rng: ChaCha8Rng::seed_from_u64(seed),
+        verifier: Verifier::new(verification, &failure),
+        credentials,
+    };
+
+    if let Err(error) = connection.open(TlsContext::new(&config, provider)).await {
+        return Err(match failure.get() {
+            Some(failure) => Error::Verification(failure),
+            None => Error::Tls(error),
+        });
+    }
+
+    Ok(TlsStream {
+        connection,
+        _lease: lease,
+    })
+}
+
+/// A crypto provider that verifies server certificates
+///
+/// It also signs the handshake when authenticating with a client
+/// certificate.
+struct VerifyingProvider<'a> {
+    /// Random numbers generator
+    rng: ChaCha8Rng,
+
+    /// Certificate verifier
+    verifier: Verifier<'a>,
+
+    /// Client credentials
+    credentials: Credentials,
+}
+
+impl CryptoProvider for VerifyingProvider<'_> {
+    type CipherSuite = CipherSuite;
+    type Signature = P256DerSignature;
+
+    fn rng(&mut self) -> impl embedded_tls::CryptoRngCore {
+        &mut self.rng
+    }
+
+    fn verifier(&mut self) -> Result<&mut impl TlsVerifier<Self::CipherSuite>, TlsError> {
+        Ok(&mut self.verifier)
+    }
+
+    fn signer(&mut self) -> Result<(impl SignerMut<Self::Signature>, SignatureScheme), TlsError> {
+        let Credentials::Certificate { private_key, .. } = self.credentials else {
+            return Err(TlsError::InvalidPrivateKey);
+        };
+        let key = P256SigningKey::from_pkcs8_der(private_key).map_err(|_| TlsError::InvalidPrivateKey)?;
+        Ok((key, SignatureScheme::EcdsaSecp256r1Sha256))
+    }
+
+    fn client_cert(&mut self) -> Option<Certificate<impl AsRef<[u8]>>> {
+        match self.credentials {
+            Credentials::Certificate { certificate, .. } => Some(Certificate::X509(certificate)),
+            _ => None,
+        }
+    }
+}
+
+/// A verifier for server certificates
+struct Verifier<'a> {
+    /// Verification mode
+    verification: Verification,
+
+    /// Host name the leaf certificate must be valid for
+    hostname: String<HOSTNAME_SIZE>,
+
+    /// Public key of the leaf certificate
+    server_key: Option<SubjectPublicKeyInfoOwned>,
+
+    /// Transcript hash up to the server certificate
+    transcript_hash: Vec<u8, DIGEST_SIZE>,
+
+    /// Where to report verification failures
+    failure: &'a Cell<Option<VerificationError>>,
+}
+
+impl<'a> Verifier<'a> {
+    /// Create a new verifier
+    fn new(verification: Verification, failure: &'a Cell<Option<VerificationError>>) -> Self {
+        failure.set(None);
+
+        Self {
+            verification,
+            hostname: String::new(),
+            server_key: None,
+            transcript_hash: Vec::new(),
+            failure,
+        }
+    }
+
+    /// Record a verification failure and turn it into a TLS error
+    fn fail(&self, error: VerificationError) -> TlsError {
+        warn!("Server certificate verification failed: {error:?}");
+        self.failure.set(Some(error));
+        TlsError::InvalidCertificate
+    }
+
+    /// Verify a certificate chain sent by the server
+    fn verify_chain(&self, chain: &[X509Certificate]) -> Result<(), VerificationError> {
+        let leaf = chain.first().ok_or(VerificationError::NoCertificate)?;
+
+        if !matches_hostname(leaf, &self.hostname)? {
+            return Err(VerificationError::HostnameMismatch);
+        }
+
+        match self.verification {
+            Verification::None => Ok(()),
+            Verification::CaRoots(roots) => verify_against_roots(chain, roots),
+            Verification::PinnedKeys(pins) => verify_against_pins(chain, pins),
+        }
+    }
+}
+
+impl TlsVerifier<CipherSuite> for Verifier<'_> {
+    fn set_hostname_verification(&mut self, hostname: &str) -> Result<(), TlsError> {
+        self.hostname = String::try_from(hostname)
+            .map_err(|_| self.fail(VerificationError::HostnameTooLong))?;
+        Ok(())
+    }
+
+    fn verify_certificate(
+        &mut self,
+        transcript: &<CipherSuite as embedded_tls::TlsCipherSuite>::Hash,
+        cert: CertificateRef,
+    ) -> Result<(), TlsError> {
+        if let Verification::None = self.verification {
+            return Ok(());
+        }
+
+        let mut chain: Vec<X509Certificate, MAX_CHAIN_LENGTH> = Vec::new();
+        for entry in &cert.entries {
+            if let CertificateEntryRef::X509(der) = entry {
+                let certificate = X509Certificate::from_der(der)
+                    .map_err(|_| self.fail(VerificationError::Malformed))?;
+                chain
+                    .push(certificate)
+                    .map_err(|_| self.fail(VerificationError::ChainTooLong))?;
+            }
+        }
+
+        self.verify_chain(&chain).map_err(|error| self.fail(error))?;
+
+        self.server_key = chain
+            .into_iter()
+            .next()
+            .map(|leaf| leaf.tbs_certificate.subject_public_key_info);
+        self.transcript_hash = Vec::from_slice(&transcript.clone().finalize())
+            .map_err(|_| self.fail(VerificationError::UnsupportedAlgorithm))?;
+
+        debug!("Server certificate chain verified");
+        Ok(())
+    }
+
+    fn verify_signature(&mut self, verify: CertificateVerifyRef) -> Result<(), TlsError> {
+        if let Verification::None = self.verification {
+            return Ok(());
+        }
+
+        let key = self
+            .server_key
+            .as_ref()
+            .ok_or_else(|| self.fail(VerificationError::NoCertificate))?;
+
+        let algorithm = match verify.signature_scheme {
+            SignatureScheme::EcdsaSecp256r1Sha256 => ECDSA_WITH_SHA_256,
+            SignatureScheme::EcdsaSecp384r1Sha384 => ECDSA_WITH_SHA_384,
+            _ => return Err(self.fail(VerificationError::UnsupportedAlgorithm)),
+        };
+
+        let mut message: Vec<u8, SIGNED_MESSAGE_SIZE> = Vec::new();
+        message.resize(64, b' ').ok();
+        message.extend_from_slice(SERVER_CONTEXT).ok();
+        message.push(0).ok();
+        message.extend_from_slice(&self.transcript_hash).ok();
+
+        verify_signature(key, algorithm, &message, verify.signature)
+            .map_err(|error| self.fail(error))?;
+
+        debug!("Server handshake signature verified");
+        Ok(())
+    }
+}
+
+/// Check whether a certificate is valid for a host name
+///
+/// Only subject alternative names are considered: `dNSName` entries, with
+/// wildcards matching a single leftmost label, for host names, and
+/// `iPAddress` entries for IP literals.
+fn matches_hostname(certificate: &X509Certificate, hostname: &str) -> Result<bool, VerificationError> {
+    let Some((_, names)) = certificate
+        .tbs_certificate
+        .get::<SubjectAltName>()
+        .map_err(|_| VerificationError::Malformed)?
+    else {
+        return Ok(false);
+    };
+
+    let address: Option<IpAddr> = hostname
+        .strip_prefix('[')
+        .and_then(|hostname| hostname.strip_suffix(']'))
+        .unwrap_or(hostname)
+        .parse()
+        .ok();
+
+    let matches = names.0.iter().any(|name| match (name, address) {
+        (GeneralName::DnsName(pattern), None) => matches_pattern(pattern.as_str(), hostname),
+        (GeneralName::IpAddress(octets), Some(address)) => matches_address(octets.as_bytes(), address),
+        _ => false,
+    });
+
+    Ok(matches)
+}
+
+/// Match an IP address against the octets of an `iPAddress` entry
+fn matches_address(octets: &[u8], address: IpAddr) -> bool {
+    match address {
+        IpAddr::V4(address) => octets == address.octets(),
+        IpAddr::V6(address) => octets == address.octets(),
+    }
+}
+
+/// Match a host name against a `dNSName` pattern
+fn matches_pattern(pattern: &str, hostname: &str) -> bool {
+    if let Some(suffix) = pattern.strip_prefix("*.") {
+        match hostname.split_once('.') {
+            Some((label, rest)) => !label.is_empty() && rest.eq_ignore_ascii_case(suffix),
+            None => false,
+        }
+    } else {
+        pattern.eq_ignore_ascii_case(hostname)
+    }
+}
+
+/// Verify that a chain is signed up to one of the trusted roots
+///
+/// Every certificate must be issued by the next one in the chain, up to one
+/// issued by a root.  Certificates past that one are ignored, servers may
+/// send a root cross-signed by an older one for the sake of old clients.
+fn verify_against_roots(chain: &[X509Certificate], roots: &[&[u8]]) -> Result<(), VerificationError> {
+    for (index, certificate) in chain.iter().enumerate() {
+        if let Some(subject) = index.checked_sub(1).and_then(|previous| chain.get(previous)) {
+            verify_issued_by(subject, certificate, index - 1)?;
+        }
+
+        for root in roots {
+            let root = X509Certificate::from_der(root).map_err(|_| VerificationError::Malformed)?;
+            if root.tbs_certificate.subject != certificate.tbs_certificate.issuer {
+                continue;
+            }
+            if verify_issued_by(certificate, &root, index).is_ok() {
+                return Ok(());
+            }
+        }
+    }
+
+    Err(VerificationError::UntrustedChain)
+}
+
+/// Verify that a chain is signed up to one of the pinned public keys
+///
+/// The leaf itself may be pinned.  Otherwise every certificate up to the
+/// pinned one must be issued by the next one in the chain, so that a pinned
+/// certificate merely sent along with an unrelated leaf is not enough.
+fn verify_against_pins(chain: &[X509Certificate], pins: &[[u8; 32]]) -> Result<(), VerificationError> {
+    for (index, certificate) in chain.iter().enumerate() {
+        if let Some(subject) = index.checked_sub(1).and_then(|previous| chain.get(previous)) {
+            verify_issued_by(subject, certificate, index - 1)?;
+        }
+
+        let key = certificate
+            .tbs_certificate
+            .subject_public_key_info
+            .to_der()
+            .map_err(|_| VerificationError::Malformed)?;
+        let digest: [u8; 32] = Sha256::digest(&key).into();
+        if pins.contains(&digest) {
+            return Ok(());
+        }
+    }
+
+    Err(VerificationError::PinMismatch)
+}
+
+/// Verify that a certificate was issued by another one
+///
+/// The issuer must be named as such in the certificate, be a CA allowed to
+/// sign certificates, and allow `intermediates` CA certificates between
+/// itself and the leaf.  Finally, its key must verify the signature.
+fn verify_issued_by(
+    certificate: &X509Certificate,
+    issuer: &X509Certificate,
+    intermediates: usize,
+) -> Result<(), VerificationError> {
+    if certificate.tbs_certificate.issuer != issuer.tbs_certificate.subject {
+        return Err(VerificationError::IssuerMismatch);
+    }
+
+    let constraints = issuer
+        .tbs_certificate
+        .get::<BasicConstraints>()
+        .map_err(|_| VerificationError::Malformed)?
+        .map(|(_, constraints)| constraints);
+    let Some(constraints) = constraints.filter(|constraints| constraints.ca) else {
+        return Err(VerificationError::NotCa);
+    };
+    if constraints
+        .path_len_constraint
+        .is_some_and(|length| usize::from(length) < intermediates)
+    {
+        return Err(VerificationError::PathTooLong);
+    }
+
+    let usage = issuer
+        .tbs_certificate
+        .get::<KeyUsage>()
+        .map_err(|_| VerificationError::Malformed)?;
+    if usage.is_some_and(|(_, usage)| !usage.key_cert_sign()) {
+        return Err(VerificationError::NotCa);
+    }
+
+    verify_certificate_signature(certificate, &issuer.tbs_certificate.subject_public_key_info)
+}
+
+/// Verify that a certificate was signed with an issuer key
+fn verify_certificate_signature(
+    certificate: &X509Certificate,
+    issuer: &SubjectPublicKeyInfoOwned,
+) -> Result<(), VerificationError> {
+    let message = certificate
+        .tbs_certificate
+        .to_der()
+        .map_err(|_| VerificationError::Malformed)?;
+    let signature = certificate
+        .signature
+        .as_bytes()
+        .ok_or(VerificationError::Malformed)?;
+
+    verify_signature(issuer, certificate.signature_algorithm.oid, &message, signature)
+}
+
+/// Verify a DER encoded ECDSA signature
+///
+/// Only ECDSA over P-256 and P-384 with SHA-256 or SHA-384 is supported.
+fn verify_signature(
+    key: &SubjectPublicKeyInfoOwned,
+    algorithm: ObjectIdentifier,
+    message: &[u8],
+    signature: &[u8],
+) -> Result<(), VerificationError> {
+    if key.algorithm.oid != ID_EC_PUBLIC_KEY {
+        return Err(VerificationError::UnsupportedAlgorithm);
+    }
+
+    let digest: Vec<u8, DIGEST_SIZE> = match algorithm {
+        ECDSA_WITH_SHA_256 => Vec::from_slice(&Sha256::digest(message)),
+        ECDSA_WITH_SHA_384 => Vec::from_slice(&Sha384::digest(message)),
+        _ => return Err(VerificationError::UnsupportedAlgorithm),
+    }
+    .map_err(|_| VerificationError::UnsupportedAlgorithm)?;
+
+    let curve: ObjectIdentifier = key
+        .algorithm
+        .parameters
+        .as_ref()
+        .ok_or(VerificationError::UnsupportedAlgorithm)?
+        .decode_as()
+        .map_err(|_| VerificationError::UnsupportedAlgorithm)?;
+    let point = key.subject_public_key.raw_bytes();
+
+    let verified = match curve {
+        SECP_256_R_1 => {
+            let key = P256VerifyingKey::from_sec1_bytes(point).map_err(|_| VerificationError::Malformed)?;
+            let signature = P256Signature::from_der(signature).map_err(|_| VerificationError::Malformed)?;
+            key.verify_prehash(&digest, &signature).is_ok()
+        }
+        SECP_384_R_1 => {
+            let key = P384VerifyingKey::from_sec1_bytes(point).map_err(|_| VerificationError::Malformed)?;
+            let signature = P384Signature::from_der(signature).map_err(|_| VerificationError::Malformed)?;
+            key.verify_prehash(&digest, &signature).is_ok()
+        }
+        _ => return Err(VerificationError::UnsupportedAlgorithm),
+    };
+
+    if verified {
+        Ok(())
+    } else {
+        Err(VerificationError::InvalidSignature)
+    }
+}
+
+/// An error while opening a TLS session
+#[derive(Debug)]
+pub enum Error {
+    /// Record buffers are lent to another session
+    BuffersInUse,
+
+    /// Error within the TLS session
+    Tls(#[allow(unused)] TlsError),
+
+    /// Server certificate was rejected
+    Verification(#[allow(unused)] VerificationError),
+}
+
+/// Reason why a server certificate was rejected
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VerificationError {
+    /// Server did not send a certificate
+    NoCertificate,
+
+    /// A certificate could not be parsed
+    Malformed,
+
+    /// A key or signature algorithm is not supported
+    UnsupportedAlgorithm,
+
+    /// Host name is too long to be verified
+    HostnameTooLong,
+
+    /// Leaf certificate is not valid for the host name
+    HostnameMismatch,
+
+    /// Chain does not lead to a trusted root
+    UntrustedChain,
+
+    /// Chain has more than [`MAX_CHAIN_LENGTH`] certificates
+    ChainTooLong,
+
+    /// A certificate does not name the next one in the chain as its issuer
+    IssuerMismatch,
+
+    /// A certificate in the chain issues others without being a CA
+    NotCa,
+
+    /// A CA certificate is followed by more intermediate CAs than it allows
+    PathTooLong,
+
+    /// Chain does not contain any pinned public key
+    PinMismatch,
+
+    /// A signature in the chain or handshake is invalid
+    InvalidSignature,
+}
+
+/// Tests of host name patterns
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exact_names_ignore_case() {
+        assert!(matches_pattern("example.com", "example.com"));
+        assert!(matches_pattern("Example.COM", "example.com"));
+        assert!(!matches_pattern("example.com", "www.example.com"));
+    }
+
+    #[test]
+    fn wildcards_match_a_single_label() {
+        assert!(matches_pattern("*.example.com", "www.example.com"));
+        assert!(matches_pattern("*.example.com", "WWW.Example.com"));
+        assert!(!matches_pattern("*.example.com", "example.com"));
+        assert!(!matches_pattern("*.example.com", "a.b.example.com"));
+        assert!(!matches_pattern("*.example.com", ".example.com"));
+    }
+}
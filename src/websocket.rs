@@ -0,0 +1,617 @@
+// Copyright Claudio Mattera 2024.
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! WebSocket client
+//!
+//! Connections are opened by an HTTP [`Client`], which sends the RFC 6455
+//! opening handshake over a new plain TCP or TLS connection and hands it over
+//! once the server switched protocols.  `ws` and `wss` URLs map to `http`
+//! and `https`.
+//!
+//! Frames sent by the client are masked with keys drawn from a random
+//! numbers generator, as the protocol requires.  Pings from the server are
+//! answered while receiving, and fragmented messages are reassembled in the
+//! caller's buffer.
+
+use core::fmt::Write as _;
+use core::str::from_utf8;
+
+use embassy_time::Duration;
+use embassy_time::WithTimeout as _;
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine as _;
+
+use embedded_io_async::Error as _;
+use embedded_io_async::ErrorKind;
+use embedded_io_async::Read;
+use embedded_io_async::ReadExactError;
+use embedded_io_async::Write;
+
+use embedded_nal_async::Dns;
+use embedded_nal_async::TcpConnect;
+
+use heapless::String;
+use heapless::Vec;
+
+use log::debug;
+use log::warn;
+
+use rand_core::Rng;
+
+use sha1::Digest as _;
+use sha1::Sha1;
+
+use crate::http::Client;
+use crate::http::Connection;
+use crate::http::Error as HttpError;
+use crate::http::Request;
+
+/// GUID appended to the handshake key, as defined by RFC 6455
+const HANDSHAKE_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Size of the random handshake key
+const KEY_SIZE: usize = 16;
+
+/// Length of the base64 encoded handshake key
+const ENCODED_KEY_SIZE: usize = KEY_SIZE.div_ceil(3) * 4;
+
+/// Length of the base64 encoded SHA-1 digest accepting the handshake
+const ACCEPT_SIZE: usize = 28;
+
+/// Maximal length of a URL
+const URL_SIZE: usize = 256;
+
+/// Maximal number of handshake headers, including additional ones
+const MAX_HEADERS: usize = 12;
+
+/// Timeout of the opening handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Maximal payload length of control frames
+const CONTROL_SIZE: usize = 125;
+
+/// Size of the buffer used to mask payloads, a multiple of four
+const MASK_CHUNK_SIZE: usize = 128;
+
+/// Opcode of continuation frames
+const OPCODE_CONTINUATION: u8 = 0x0;
+
+/// Opcode of text frames
+const OPCODE_TEXT: u8 = 0x1;
+
+/// Opcode of binary frames
+const OPCODE_BINARY: u8 = 0x2;
+
+/// Opcode of close frames
+const OPCODE_CLOSE: u8 = 0x8;
+
+/// Opcode of ping frames
+const OPCODE_PING: u8 = 0x9;
+
+/// Opcode of pong frames
+const OPCODE_PONG: u8 = 0xa;
+
+/// Status code of a normal closure
+#[allow(unused)]
+pub const CLOSE_NORMAL: u16 = 1000;
+
+/// Kind of a data message
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MessageKind {
+    /// UTF-8 text
+    Text,
+
+    /// Binary data
+    Binary,
+}
+
+impl MessageKind {
+    /// Return the opcode of the first frame of a message
+    fn opcode(self) -> u8 {
+        match self {
+            Self::Text => OPCODE_TEXT,
+            Self::Binary => OPCODE_BINARY,
+        }
+    }
+}
+
+/// A message received from the server
+#[derive(Debug)]
+pub enum Message<'a> {
+    /// Text message
+    Text(&'a str),
+
+    /// Binary message
+    Binary(&'a [u8]),
+
+    /// Answer to a ping
+    Pong,
+
+    /// The server closed the connection, with a status code if any
+    Close(Option<u16>),
+}
+
+/// Header of a received frame
+#[derive(Clone, Copy, Debug)]
+struct FrameHeader {
+    /// Whether this is the last frame of a message
+    fin: bool,
+
+    /// Opcode
+    opcode: u8,
+
+    /// Payload length
+    length: u64,
+}
+
+/// Open a WebSocket connection
+///
+/// Additional `headers`, such as `Sec-WebSocket-Protocol` or
+/// `Authorization`, are sent with the handshake.  Frames are masked with keys
+/// drawn from `rng`.
+#[allow(unused)]
+#[allow(clippy::result_large_err)]
+pub async fn connect<'a, T, D, R, M>(
+    client: &mut Client<'a, T, D, R>,
+    url: &str,
+    headers: &[(&str, &str)],
+    mut rng: M,
+) -> Result<WebSocket<Connection<'a, T::Connection<'a>>, M>, Error>
+where
+    T: TcpConnect + 'a,
+    D: Dns,
+    R: Rng,
+    M: Rng,
+{
+    let http_url = http_url(url)?;
+
+    let mut key = [0_u8; KEY_SIZE];
+    rng.fill_bytes(&mut key);
+    let mut encoded_key = [0_u8; ENCODED_KEY_SIZE];
+    let length = BASE64.encode_slice(key, &mut encoded_key).map_err(|_| Error::Handshake)?;
+    // Base64 is always ASCII
+    let encoded_key = from_utf8(&encoded_key[..length]).unwrap_or_default();
+
+    let mut all_headers: Vec<(&str, &str), MAX_HEADERS> = Vec::new();
+    all_headers
+        .extend_from_slice(&[
+            ("Upgrade", "websocket"),
+            ("Connection", "Upgrade"),
+            ("Sec-WebSocket-Key", encoded_key),
+            ("Sec-WebSocket-Version", "13"),
+        ])
+        .and_then(|()| all_headers.extend_from_slice(headers))
+        .map_err(|_| HttpError::TooManyHeaders)?;
+
+    debug!("Open WebSocket to {url}");
+    let request = Request::get(&http_url)
+        .with_headers(&all_headers)
+        .with_timeout(HANDSHAKE_TIMEOUT);
+    let (head, connection) = client.upgrade(&request).await?;
+
+    let upgraded = head
+        .headers
+        .get("Upgrade")
+        .is_some_and(|value| value.eq_ignore_ascii_case("websocket"));
+    let accepted = head.headers.get("Sec-WebSocket-Accept") == Some(accept_key(encoded_key)?.as_str());
+    if !upgraded || !accepted {
+        warn!("Server did not accept WebSocket handshake");
+        return Err(Error::Handshake);
+    }
+
+    Ok(WebSocket {
+        connection,
+        rng,
+        sending: false,
+        close_sent: false,
+        close_received: false,
+    })
+}
+
+/// Map a `ws` or `wss` URL to the matching HTTP URL
+#[allow(clippy::result_large_err)]
+fn http_url(url: &str) -> Result<String<URL_SIZE>, Error> {
+    let (scheme, rest) = url.split_once("://").ok_or(Error::InvalidUrl)?;
+    let scheme = if scheme.eq_ignore_ascii_case("ws") || scheme.eq_ignore_ascii_case("http") {
+        "http"
+    } else if scheme.eq_ignore_ascii_case("wss") || scheme.eq_ignore_ascii_case("https") {
+        "https"
+    } else {
+        return Err(Error::InvalidUrl);
+    };
+
+    let mut http_url = String::new();
+    write!(http_url, "{scheme}://{rest}").map_err(|_| Error::InvalidUrl)?;
+    Ok(http_url)
+}
+
+/// Compute the `Sec-WebSocket-Accept` value expected for a key
+#[allow(clippy::result_large_err)]
+fn accept_key(encoded_key: &str) -> Result<String<ACCEPT_SIZE>, Error> {
+    let digest = Sha1::new()
+        .chain_update(encoded_key)
+        .chain_update(HANDSHAKE_GUID)
+        .finalize();
+
+    let mut accept = [0_u8; ACCEPT_SIZE];
+    let length = BASE64.encode_slice(digest, &mut accept).map_err(|_| Error::Handshake)?;
+    // Base64 is always ASCII
+    let accept = from_utf8(&accept[..length]).unwrap_or_default();
+    String::try_from(accept).map_err(|_| Error::Handshake)
+}
+
+/// A WebSocket connection
+pub struct WebSocket<S, R> {
+    /// Underlying connection
+    connection: S,
+
+    /// Random numbers generator for masking keys
+    rng: R,
+
+    /// Whether a fragmented message is being sent
+    sending: bool,
+
+    /// Whether a close frame was sent
+    close_sent: bool,
+
+    /// Whether a close frame was received
+    close_received: bool,
+}
+
+#[allow(clippy::result_large_err)]
+impl<S, R> WebSocket<S, R>
+where
+    S: Read + Write,
+    R: Rng,
+{
+    /// Send a text message
+    #[allow(unused)]
+    pub async fn send_text(&mut self, text: &str) -> Result<(), Error> {
+        self.send_fragment(MessageKind::Text, text.as_bytes(), true).await
+    }
+
+    /// Send a binary message
+    #[allow(unused)]
+    pub async fn send_binary(&mut self, data: &[u8]) -> Result<(), Error> {
+        self.send_fragment(MessageKind::Binary, data, true).await
+    }
+
+    /// Send a fragment of a message
+    ///
+    /// The kind of the message is taken from its first fragment, and `last`
+    /// marks its final one.  Text messages may be split anywhere, even within
+    /// a character, since the server only decodes them once reassembled.
+    #[allow(unused)]
+    pub async fn send_fragment(&mut self, kind: MessageKind, payload: &[u8], last: bool) -> Result<(), Error> {
+        let opcode = if self.sending { OPCODE_CONTINUATION } else { kind.opcode() };
+        self.send_frame(opcode, last, payload).await?;
+        self.sending = !last;
+        Ok(())
+    }
+
+    /// Send a ping
+    ///
+    /// The server answers with a pong, reported by [`Self::receive`].
+    #[allow(unused)]
+    pub async fn ping(&mut self, payload: &[u8]) -> Result<(), Error> {
+        if payload.len() > CONTROL_SIZE {
+            return Err(Error::MessageTooLarge);
+        }
+        self.send_frame(OPCODE_PING, true, payload).await
+    }
+
+    /// Check that the server is still there
+    ///
+    /// Send a ping and wait for its pong.  Messages received in the meantime
+    /// are discarded, so this is meant for connections where the server does
+    /// not send messages, such as dashboards fed by the device.
+    #[allow(unused)]
+    pub async fn keep_alive(&mut self, timeout: Duration) -> Result<(), Error> {
+        self.ping(&[]).await?;
+
+        let wait_pong = async {
+            let mut buffer = [0_u8; CONTROL_SIZE];
+            loop {
+                match self.receive(&mut buffer).await {
+                    Ok(Message::Pong) => return Ok(()),
+                    Ok(Message::Close(code)) => {
+                        debug!("WebSocket closed by server: {code:?}");
+                        return Err(Error::Closed);
+                    }
+                    Ok(Message::Text(_) | Message::Binary(_)) | Err(Error::MessageTooLarge) => {
+                        debug!("Discard WebSocket message");
+                    }
+                    Err(error) => return Err(error),
+                }
+            }
+        };
+
+        wait_pong.with_timeout(timeout).await.map_err(|_| Error::Timeout)?
+    }
+
+    /// Receive the next message into a buffer
+    ///
+    /// Pings are answered and fragments are reassembled.  When the server
+    /// closes the connection, its close frame is echoed before returning
+    /// [`Message::Close`].
+    ///
+    /// A message that does not fit the buffer is read to its end and
+    /// discarded, and [`Error::MessageTooLarge`] is returned.  The next call
+    /// receives the following message.
+    #[allow(unused)]
+    pub async fn receive<'b>(&mut self, buffer: &'b mut [u8]) -> Result<Message<'b>, Error> {
+        if self.close_received {
+            return Err(Error::Closed);
+        }
+
+        let mut kind: Option<MessageKind> = None;
+        let mut length = 0;
+        let mut too_large = false;
+
+        loop {
+            let header = self.read_header().await?;
+
+            match header.opcode {
+                OPCODE_CONTINUATION | OPCODE_TEXT | OPCODE_BINARY => {
+                    let first = header.opcode != OPCODE_CONTINUATION;
+                    if first == kind.is_some() {
+                        warn!("Unexpected WebSocket frame {}", header.opcode);
+                        return Err(Error::Protocol);
+                    }
+                    if header.opcode == OPCODE_TEXT {
+                        kind = Some(MessageKind::Text);
+                    } else if header.opcode == OPCODE_BINARY {
+                        kind = Some(MessageKind::Binary);
+                    }
+
+                    let size = usize::try_from(header.length)
+                        .ok()
+                        .filter(|size| !too_large && *size <= buffer.len() - length);
+                    if let Some(size) = size {
+                        self.read_exact(&mut buffer[length..length + size]).await?;
+                        length += size;
+                    } else {
+                        // The rest of the message is skipped, so that the
+                        // next frame header is read where it starts
+                        too_large = true;
+                        self.skip(header.length).await?;
+                    }
+
+                    if header.fin {
+                        break;
+                    }
+                }
+                OPCODE_CLOSE | OPCODE_PING | OPCODE_PONG => {
+                    let size = usize::try_from(header.length).unwrap_or(usize::MAX);
+                    if !header.fin || size > CONTROL_SIZE {
+                        warn!("Invalid WebSocket control frame");
+                        return Err(Error::Protocol);
+                    }
+
+                    let mut payload = [0_u8; CONTROL_SIZE];
+                    let payload = &mut payload[..size];
+                    self.read_exact(payload).await?;
+
+                    match header.opcode {
+                        OPCODE_PING => self.send_frame(OPCODE_PONG, true, payload).await?,
+                        OPCODE_PONG if kind.is_none() => return Ok(Message::Pong),
+                        OPCODE_PONG => {}
+                        _ => return self.closed_by_server(payload).await,
+                    }
+                }
+                opcode => {
+                    warn!("Unknown WebSocket opcode {opcode}");
+                    return Err(Error::Protocol);
+                }
+            }
+        }
+
+        if too_large {
+            warn!("Discard WebSocket message too large for buffer");
+            return Err(Error::MessageTooLarge);
+        }
+
+        let buffer: &'b [u8] = buffer;
+        let message = &buffer[..length];
+        match kind {
+            Some(MessageKind::Text) => from_utf8(message).map(Message::Text).map_err(|_| Error::InvalidUtf8),
+            _ => Ok(Message::Binary(message)),
+        }
+    }
+
+    /// Close the connection
+    ///
+    /// Send a close frame with a status code, and wait for the server to
+    /// answer with its own.  Frames received in the meantime are discarded,
+    /// pings included, since nothing can be sent after a close frame.
+    #[allow(unused)]
+    pub async fn close(mut self, code: u16) -> Result<(), Error> {
+        if !self.close_sent {
+            self.send_frame(OPCODE_CLOSE, true, &code.to_be_bytes()).await?;
+            self.close_sent = true;
+        }
+
+        while !self.close_received {
+            let header = self.read_header().await?;
+            if header.opcode == OPCODE_CLOSE {
+                if !header.fin || header.length > CONTROL_SIZE as u64 {
+                    warn!("Invalid WebSocket control frame");
+                    return Err(Error::Protocol);
+                }
+                self.close_received = true;
+            }
+            self.skip(header.length).await?;
+        }
+
+        debug!("WebSocket closed");
+        Ok(())
+    }
+
+    /// Answer a close frame from the server
+    async fn closed_by_server(&mut self, payload: &[u8]) -> Result<Message<'static>, Error> {
+        self.close_received = true;
+
+        let code = match payload {
+            [high, low, ..] => Some(u16::from_be_bytes([*high, *low])),
+            _ => None,
+        };
+
+        if !self.close_sent {
+            // Echo the status code, as the protocol expects
+            let echo = payload.get(..2).unwrap_or_default();
+            self.send_frame(OPCODE_CLOSE, true, echo).await?;
+            self.close_sent = true;
+        }
+
+        Ok(Message::Close(code))
+    }
+
+    /// Read the header of the next frame
+    async fn read_header(&mut self) -> Result<FrameHeader, Error> {
+        let mut bytes = [0_u8; 2];
+        self.read_exact(&mut bytes).await?;
+        let [first, second] = bytes;
+
+        if first & 0x70 != 0 {
+            warn!("WebSocket extensions are not supported");
+            return Err(Error::Protocol);
+        }
+        if second & 0x80 != 0 {
+            warn!("WebSocket server frames must not be masked");
+            return Err(Error::Protocol);
+        }
+
+        let length = match second & 0x7f {
+            126 => {
+                let mut length = [0_u8; 2];
+                self.read_exact(&mut length).await?;
+                u64::from(u16::from_be_bytes(length))
+            }
+            127 => {
+                let mut length = [0_u8; 8];
+                self.read_exact(&mut length).await?;
+                u64::from_be_bytes(length)
+            }
+            length => u64::from(length),
+        };
+
+        Ok(FrameHeader {
+            fin: first & 0x80 != 0,
+            opcode: first & 0x0f,
+            length,
+        })
+    }
+
+    /// Send a frame with a masked payload
+    async fn send_frame(&mut self, opcode: u8, fin: bool, payload: &[u8]) -> Result<(), Error> {
+        if self.close_sent {
+            return Err(Error::Closed);
+        }
+
+        let mut header: Vec<u8, 14> = Vec::new();
+        let fin = if fin { 0x80 } else { 0 };
+        let _ = header.push(fin | opcode);
+
+        // Lengths always fit the header
+        let length = payload.len();
+        if let Ok(length @ 0..=125) = u8::try_from(length) {
+            let _ = header.push(0x80 | length);
+        } else if let Ok(length) = u16::try_from(length) {
+            let _ = header.push(0x80 | 126);
+            let _ = header.extend_from_slice(&length.to_be_bytes());
+        } else {
+            let _ = header.push(0x80 | 127);
+            let _ = header.extend_from_slice(&(length as u64).to_be_bytes());
+        }
+
+        let mut mask = [0_u8; 4];
+        self.rng.fill_bytes(&mut mask);
+        let _ = header.extend_from_slice(&mask);
+
+        self.write_all(&header).await?;
+
+        let mut chunk = [0_u8; MASK_CHUNK_SIZE];
+        for piece in payload.chunks(MASK_CHUNK_SIZE) {
+            for (index, (masked, byte)) in chunk.iter_mut().zip(piece).enumerate() {
+                *masked = byte ^ mask[index % 4];
+            }
+            self.write_all(&chunk[..piece.len()]).await?;
+        }
+
+        self.connection
+            .flush()
+            .await
+            .map_err(|error| Error::Io(error.kind()))
+    }
+
+    /// Fill a buffer from the connection
+    async fn read_exact(&mut self, buffer: &mut [u8]) -> Result<(), Error> {
+        self.connection.read_exact(buffer).await.map_err(|error| match error {
+            ReadExactError::UnexpectedEof => Error::ConnectionClosed,
+            ReadExactError::Other(error) => Error::Io(error.kind()),
+        })
+    }
+
+    /// Read and discard bytes from the connection
+    async fn skip(&mut self, mut count: u64) -> Result<(), Error> {
+        let mut discarded = [0_u8; CONTROL_SIZE];
+        while count > 0 {
+            let size = usize::try_from(count).map_or(discarded.len(), |count| count.min(discarded.len()));
+            self.read_exact(&mut discarded[..size]).await?;
+            count -= size as u64;
+        }
+        Ok(())
+    }
+
+    /// Write all bytes to the connection
+    async fn write_all(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        self.connection
+            .write_all(bytes)
+            .await
+            .map_err(|error| Error::Io(error.kind()))
+    }
+}
+
+/// An error within a WebSocket connection
+#[derive(Debug)]
+pub enum Error {
+    /// Error within the opening handshake request
+    Http(#[allow(unused)] HttpError),
+
+    /// URL is not a WebSocket URL
+    InvalidUrl,
+
+    /// Server did not accept the opening handshake
+    Handshake,
+
+    /// Error within the connection
+    Io(#[allow(unused)] ErrorKind),
+
+    /// Connection ended without closing handshake
+    ConnectionClosed,
+
+    /// Connection was closed by a closing handshake
+    Closed,
+
+    /// Server violated the protocol
+    Protocol,
+
+    /// Message does not fit the buffer
+    MessageTooLarge,
+
+    /// Text message is not valid UTF-8
+    InvalidUtf8,
+
+    /// Server did not answer a ping in time
+    Timeout,
+}
+
+impl From<HttpError> for Error {
+    fn from(error: HttpError) -> Self {
+        Self::Http(error)
+    }
+}
@@ -6,13 +6,17 @@
 
 //! Client for World Time API
 
-use core::num::ParseIntError;
-use core::str::from_utf8;
-use core::str::Utf8Error;
-
 use embassy_time::Duration;
 use log::debug;
-use log::trace;
+
+use embedded_nal_async::Dns;
+use embedded_nal_async::TcpConnect;
+
+use rand_core::Rng;
+
+use serde::de::Error as _;
+use serde::Deserialize;
+use serde::Deserializer;
 
 use time::error::ComponentRange as TimeComponentRangeError;
 use time::OffsetDateTime;
@@ -21,55 +25,82 @@ use time::UtcOffset;
 use crate::http::Client as HttpClient;
 use crate::http::ClientTrait as HttpClientTrait;
 use crate::http::Error as HttpError;
+use crate::http::ErrorClass as HttpErrorClass;
 
 /// Extend an HTTP client for querying World Time API
 pub trait WorldTimeApiClient: HttpClientTrait {
     /// Fetch the current time
     #[allow(async_fn_in_trait)]
     async fn fetch_current_time(&mut self, timeout: Duration) -> Result<OffsetDateTime, Error> {
-        let url = "https://worldtimeapi.org/api/timezone/America/Sao_Paulo.txt";
-
-        let response = self.get_request(url, timeout).await?;
-
-        let text = from_utf8(&response)?;
-        let mut timestamp: Option<u64> = None;
-        let mut offset: Option<i32> = None;
-        for line in text.lines() {
-            trace!("Line: \"{line}\"");
-            if let Some(timestamp_string) = line.strip_prefix("unixtime: ") {
-                debug!("Parse line \"{line}\"");
-                let timestamp_: u64 = timestamp_string.parse()?;
-
-                debug!("Current time is {timestamp_}");
-                timestamp = Some(timestamp_);
-            }
-            if let Some(offset_string) = line.strip_prefix("raw_offset: ") {
-                debug!("Parse line \"{line}\"");
-                let offset_: i32 = offset_string.parse()?;
-
-                debug!("Current offset is {offset_}");
-                offset = Some(offset_);
-            }
-        }
+        let url = "https://worldtimeapi.org/api/timezone/America/Sao_Paulo";
 
-        if let (Some(timestamp), Some(offset)) = (timestamp, offset) {
-            let offset = UtcOffset::from_whole_seconds(offset)?;
+        let response: TimeResponse = self.get_json(url, timeout).await?;
+        debug!("Current time is {}.{:06}", response.unixtime, response.micros);
+        debug!("Current offset is {}", response.raw_offset);
 
-            #[allow(clippy::cast_possible_wrap)]
-            let timestamp = timestamp as i64;
+        let offset = UtcOffset::from_whole_seconds(response.raw_offset)?;
 
-            let utc = OffsetDateTime::from_unix_timestamp(timestamp)?;
-            let local = utc
-                .checked_to_offset(offset)
-                .ok_or(Error::InvalidInOffset)?;
-            Ok(local)
-        } else {
-            Err(Error::Unknown)
-        }
+        let nanos = i128::from(response.unixtime) * 1_000_000_000 + i128::from(response.micros) * 1_000;
+        let utc = OffsetDateTime::from_unix_timestamp_nanos(nanos)?;
+        let local = utc
+            .checked_to_offset(offset)
+            .ok_or(Error::InvalidInOffset)?;
+        Ok(local)
     }
 }
 
-impl WorldTimeApiClient for HttpClient<'static> {}
+/// Fields of interest in a World Time API response
+#[derive(Debug, Deserialize)]
+struct TimeResponse {
+    /// Current time in Unix epoch, truncated to whole seconds
+    unixtime: u64,
+
+    /// Microseconds within the current second, taken from the ISO 8601
+    /// current time
+    #[serde(rename = "datetime", deserialize_with = "deserialize_micros")]
+    micros: u32,
+
+    /// Offset from UTC in seconds, excluding daylight saving time
+    raw_offset: i32,
+}
+
+/// Deserialize the microseconds of an ISO 8601 date and time
+///
+/// The fraction of `2024-05-01T12:34:56.123456-03:00` is taken, padded or
+/// truncated to six digits.
+fn deserialize_micros<'de, D>(deserializer: D) -> Result<u32, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let datetime = <&str>::deserialize(deserializer)?;
+    parse_micros(datetime).ok_or_else(|| D::Error::custom("datetime without fraction of second"))
+}
+
+/// Return the microseconds of an ISO 8601 date and time
+fn parse_micros(datetime: &str) -> Option<u32> {
+    let (_, time) = datetime.split_once('T')?;
+    let (_, fraction) = time.split_once('.')?;
+    let digits = fraction.bytes().take_while(u8::is_ascii_digit);
+
+    let mut micros = 0;
+    let mut count = 0;
+    for digit in digits.take(6) {
+        micros = micros * 10 + u32::from(digit - b'0');
+        count += 1;
+    }
+    if count == 0 {
+        return None;
+    }
+    Some(micros * 10_u32.pow(6 - count))
+}
+
+impl<T, D, R> WorldTimeApiClient for HttpClient<'static, T, D, R>
+where
+    T: TcpConnect + 'static,
+    D: Dns,
+    R: Rng,
+{
+}
 
 /// An error within a request to World Time API
 #[derive(Debug)]
@@ -77,20 +108,24 @@ pub enum Error {
     /// Current timestamp is invalid in this offset
     InvalidInOffset,
 
-    /// Current time could not be fetched
-    Unknown,
-
     /// A time component is out of range
     TimeComponentRange(#[allow(unused)] TimeComponentRangeError),
 
     /// Error from HTTP client
     Http(#[allow(unused)] HttpError),
+}
 
-    /// An integer valued returned by the server could not be parsed
-    ParseInt(#[allow(unused)] ParseIntError),
-
-    /// Text returned by the server is not valid UTF-8
-    Utf8(#[allow(unused)] Utf8Error),
+impl Error {
+    /// Classify this error as transient or permanent
+    ///
+    /// Only HTTP errors can be transient, a response that could not be
+    /// parsed will not be parsed any better later.
+    pub fn class(&self) -> HttpErrorClass {
+        match self {
+            Self::Http(error) => error.class(),
+            _ => HttpErrorClass::Permanent,
+        }
+    }
 }
 
 impl From<TimeComponentRangeError> for Error {
@@ -104,15 +139,3 @@ impl From<HttpError> for Error {
         Self::Http(error)
     }
 }
-
-impl From<ParseIntError> for Error {
-    fn from(error: ParseIntError) -> Self {
-        Self::ParseInt(error)
-    }
-}
-
-impl From<Utf8Error> for Error {
-    fn from(error: Utf8Error) -> Self {
-        Self::Utf8(error)
-    }
-}
\ No newline at end of file
@@ -0,0 +1,74 @@
+// Copyright Claudio Mattera 2024.
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Clock synchronization with World Time API
+
+#![allow(clippy::result_large_err)]
+
+use embassy_futures::block_on;
+use embassy_time::Duration;
+use embassy_time::Instant;
+
+use esp32c3_embassy::clock::Clock;
+use esp32c3_embassy::http::ClientTrait;
+use esp32c3_embassy::http::Error;
+use esp32c3_embassy::http::Headers;
+use esp32c3_embassy::http::Request;
+use esp32c3_embassy::http::ResponseHead;
+use esp32c3_embassy::worldtimeapi::WorldTimeApiClient;
+
+/// A client answering every request with the same World Time API body
+struct WorldTimeApi(&'static str);
+
+impl ClientTrait for WorldTimeApi {
+    async fn request_stream<F>(&mut self, _request: &Request<'_>, mut on_chunk: F) -> Result<ResponseHead, Error>
+    where
+        F: FnMut(&[u8]) -> Result<(), Error>,
+    {
+        on_chunk(self.0.as_bytes())?;
+        Ok(ResponseHead {
+            status: 200,
+            headers: Headers::default(),
+            received_at: Instant::now(),
+        })
+    }
+}
+
+impl WorldTimeApiClient for WorldTimeApi {}
+
+#[test]
+fn fraction_of_second_is_kept() {
+    let mut client = WorldTimeApi(
+        r#"{"datetime":"2023-11-14T19:13:20.250000-03:00","raw_offset":-10800,"unixtime":1700000000}"#,
+    );
+
+    let synchronized = block_on(Clock::from_server(&mut client, Duration::from_secs(1), 1)).unwrap();
+    assert!(synchronized.error_bound < Duration::from_millis(100));
+
+    let micros = synchronized.clock.now_as_epoch_micros();
+    assert!((1_700_000_000_250_000..1_700_000_000_350_000).contains(&micros));
+}
+
+#[test]
+fn fraction_is_padded_to_microseconds() {
+    let mut client = WorldTimeApi(
+        r#"{"datetime":"2023-11-14T19:13:20.5-03:00","raw_offset":-10800,"unixtime":1700000000}"#,
+    );
+
+    let synchronized = block_on(Clock::from_server(&mut client, Duration::from_secs(1), 1)).unwrap();
+    let micros = synchronized.clock.now_as_epoch_micros();
+    assert!((1_700_000_000_500_000..1_700_000_000_600_000).contains(&micros));
+}
+
+#[test]
+fn datetime_without_fraction_is_rejected() {
+    let mut client = WorldTimeApi(
+        r#"{"datetime":"2023-11-14T19:13:20-03:00","raw_offset":-10800,"unixtime":1700000000}"#,
+    );
+
+    let result = block_on(client.fetch_current_time(Duration::from_secs(1)));
+    assert!(result.is_err());
+}
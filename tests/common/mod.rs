@@ -0,0 +1,252 @@
+// Copyright Claudio Mattera 2024.
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Helpers shared by host tests: a deterministic random numbers generator,
+//! clients over std sockets and a stand-in HTTP server
+
+#![allow(dead_code)]
+
+use std::io::Read as _;
+use std::io::Write as _;
+use std::net::SocketAddr;
+use std::net::TcpListener;
+use std::net::TcpStream;
+use std::thread::JoinHandle;
+
+use core::convert::Infallible;
+
+use rand_core::TryCryptoRng;
+use rand_core::TryRng;
+
+use esp32c3_embassy::host::StdDns;
+use esp32c3_embassy::host::StdTcpClient;
+use esp32c3_embassy::http::Client;
+use esp32c3_embassy::retry::RetryPolicy;
+use esp32c3_embassy::tls::Credentials;
+use esp32c3_embassy::tls::TlsBuffers;
+use esp32c3_embassy::tls::Verification;
+use esp32c3_embassy::tls::RECORD_BUFFER_SIZE;
+
+/// HTTP client over std sockets
+pub type TestClient = Client<'static, StdTcpClient, StdDns, TestRng>;
+
+/// A xorshift random numbers generator, deterministic so that tests are
+/// reproducible
+#[derive(Clone, Debug)]
+pub struct TestRng(u64);
+
+impl Default for TestRng {
+    fn default() -> Self {
+        Self(0x2545_f491_4f6c_dd1d)
+    }
+}
+
+impl TryRng for TestRng {
+    type Error = Infallible;
+
+    fn try_next_u32(&mut self) -> Result<u32, Self::Error> {
+        Ok((self.try_next_u64()? >> 32) as u32)
+    }
+
+    fn try_next_u64(&mut self) -> Result<u64, Self::Error> {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        Ok(self.0)
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), Self::Error> {
+        for chunk in dest.chunks_mut(8) {
+            let bytes = self.try_next_u64()?.to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+        Ok(())
+    }
+}
+
+impl TryCryptoRng for TestRng {}
+
+/// Create a client that never retries and does not verify certificates
+pub fn client() -> TestClient {
+    verifying_client(Verification::None)
+}
+
+/// Create a client that never retries and verifies certificates
+pub fn verifying_client(verification: Verification) -> TestClient {
+    let tls_buffers: &'static TlsBuffers<1, RECORD_BUFFER_SIZE> = Box::leak(Box::default());
+    Client::new(
+        &StdTcpClient,
+        StdDns,
+        tls_buffers,
+        TestRng::default(),
+        verification,
+        Credentials::None,
+    )
+    .with_retry_policy(RetryPolicy::none())
+}
+
+/// A request received by the stand-in server
+#[derive(Clone, Debug)]
+pub struct Received {
+    /// Request line and headers
+    pub head: String,
+
+    /// Request body, with chunked transfer encoding removed
+    pub body: Vec<u8>,
+
+    /// Index of the connection it arrived on
+    pub connection: usize,
+}
+
+impl Received {
+    /// Return the request line
+    pub fn request_line(&self) -> &str {
+        self.head.lines().next().unwrap_or_default()
+    }
+
+    /// Return the value of a header, ignoring case in its name
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.head.lines().skip(1).find_map(|line| {
+            let (key, value) = line.split_once(':')?;
+            key.eq_ignore_ascii_case(name).then(|| value.trim())
+        })
+    }
+}
+
+/// A stand-in HTTP server answering canned responses in order
+///
+/// Each response answers one request.  Connections are kept open as long as
+/// the client keeps them, so that keep-alive can be checked through
+/// [`Received::connection`].  A response of `None` closes the connection
+/// without answering.
+pub struct Server {
+    /// Address the server listens on
+    address: SocketAddr,
+
+    /// Thread serving the responses and returning the received requests
+    thread: JoinHandle<Vec<Received>>,
+}
+
+impl Server {
+    /// Start a server on a local port
+    pub fn start(responses: Vec<Option<Vec<u8>>>) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("bind");
+        let address = listener.local_addr().expect("address");
+
+        let thread = std::thread::spawn(move || {
+            let mut received = Vec::new();
+            let mut responses = responses.into_iter();
+            let mut connection = 0;
+
+            'connections: while responses.len() > 0 {
+                let (mut stream, _) = listener.accept().expect("accept");
+                connection += 1;
+
+                while let Some((head, body)) = read_request(&mut stream) {
+                    received.push(Received {
+                        head,
+                        body,
+                        connection,
+                    });
+
+                    match responses.next() {
+                        Some(Some(response)) => stream.write_all(&response).expect("write"),
+                        Some(None) => continue 'connections,
+                        None => break 'connections,
+                    }
+                    if responses.len() == 0 {
+                        // Let the client read everything before closing
+                        let mut rest = Vec::new();
+                        let _ = stream.read_to_end(&mut rest);
+                        break 'connections;
+                    }
+                }
+            }
+
+            received
+        });
+
+        Self { address, thread }
+    }
+
+    /// Return the URL of a path on the server
+    pub fn url(&self, path: &str) -> String {
+        format!("http://{}{path}", self.address)
+    }
+
+    /// Wait for the server to answer all responses, and return the requests
+    /// it received
+    pub fn finish(self, client: &mut TestClient) -> Vec<Received> {
+        // The server waits for the client to close its connection
+        client.close();
+        self.thread.join().expect("server thread")
+    }
+}
+
+/// Build a response with a `Content-Length` body
+pub fn response(status: &str, headers: &[(&str, &str)], body: &[u8]) -> Option<Vec<u8>> {
+    let mut response = format!("HTTP/1.1 {status}\r\nContent-Length: {}\r\n", body.len());
+    for (name, value) in headers {
+        response.push_str(&format!("{name}: {value}\r\n"));
+    }
+    response.push_str("\r\n");
+
+    let mut response = response.into_bytes();
+    response.extend_from_slice(body);
+    Some(response)
+}
+
+/// Read a request, or return `None` once the client closed the connection
+fn read_request(stream: &mut TcpStream) -> Option<(String, Vec<u8>)> {
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        let mut byte = [0_u8];
+        match stream.read(&mut byte) {
+            Ok(1) => head.push(byte[0]),
+            _ => return None,
+        }
+    }
+    let head = String::from_utf8(head).expect("request head");
+
+    let header = |name: &str| {
+        head.lines().skip(1).find_map(|line| {
+            let (key, value) = line.split_once(':')?;
+            key.eq_ignore_ascii_case(name).then(|| value.trim().to_owned())
+        })
+    };
+
+    let mut body = Vec::new();
+    if let Some(length) = header("Content-Length") {
+        body.resize(length.parse().expect("Content-Length"), 0);
+        stream.read_exact(&mut body).ok()?;
+    } else if header("Transfer-Encoding").is_some_and(|value| value.eq_ignore_ascii_case("chunked")) {
+        loop {
+            let size = read_line(stream)?;
+            let size = usize::from_str_radix(size.trim(), 16).expect("chunk size");
+            let mut chunk = vec![0_u8; size + 2];
+            stream.read_exact(&mut chunk).ok()?;
+            if size == 0 {
+                break;
+            }
+            body.extend_from_slice(&chunk[..size]);
+        }
+    }
+
+    Some((head, body))
+}
+
+/// Read a line terminated by CRLF
+fn read_line(stream: &mut TcpStream) -> Option<String> {
+    let mut line = Vec::new();
+    while !line.ends_with(b"\r\n") {
+        let mut byte = [0_u8];
+        match stream.read(&mut byte) {
+            Ok(1) => line.push(byte[0]),
+            _ => return None,
+        }
+    }
+    String::from_utf8(line).ok()
+}
@@ -0,0 +1,622 @@
+// Copyright Claudio Mattera 2024.
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! HTTP client against a stand-in server
+
+// Callbacks return the large HTTP error of the library
+#![allow(clippy::result_large_err)]
+
+mod common;
+
+use embassy_futures::block_on;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+use embassy_time::Duration;
+
+use miniz_oxide::deflate::compress_to_vec;
+use miniz_oxide::deflate::compress_to_vec_zlib;
+
+use rand_core::Rng as _;
+
+use reqwless::headers::ContentType;
+use reqwless::request::Method;
+
+use esp32c3_embassy::auth::Bearer;
+use esp32c3_embassy::body::BodySource;
+use esp32c3_embassy::http::ClientTrait as _;
+use esp32c3_embassy::http::Error;
+use esp32c3_embassy::http::Request;
+use esp32c3_embassy::inflate::Inflater;
+use esp32c3_embassy::retry::RetryPolicy;
+use esp32c3_embassy::sse::Event;
+use esp32c3_embassy::sse::SseClient;
+
+use self::common::client;
+use self::common::response;
+use self::common::Server;
+use self::common::TestRng;
+
+/// Timeout of test requests
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+#[test]
+fn content_length_body() {
+    let server = Server::start(vec![response("200 OK", &[], b"Hello, world")]);
+    let mut client = client();
+
+    let body = block_on(client.get_request(&server.url("/hello?x=1"), TIMEOUT)).unwrap();
+    assert_eq!(&body[..], b"Hello, world");
+
+    let received = server.finish(&mut client);
+    assert_eq!(received.len(), 1);
+    assert_eq!(received[0].request_line(), "GET /hello?x=1 HTTP/1.1");
+    assert!(received[0].header("Host").is_some());
+}
+
+#[test]
+fn chunked_body() {
+    let chunked = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nHello\r\n7\r\n, world\r\n0\r\n\r\n";
+    let server = Server::start(vec![Some(chunked.to_vec())]);
+    let mut client = client();
+
+    let body = block_on(client.get_request(&server.url("/"), TIMEOUT)).unwrap();
+    assert_eq!(&body[..], b"Hello, world");
+
+    server.finish(&mut client);
+}
+
+#[test]
+fn streamed_body_arrives_in_chunks() {
+    let content = vec![b'x'; 3000];
+    let server = Server::start(vec![response("200 OK", &[], &content)]);
+    let mut client = client();
+
+    let mut chunks = Vec::new();
+    let total = block_on(client.get_stream(&server.url("/"), TIMEOUT, |chunk| {
+        chunks.push(chunk.len());
+        Ok(())
+    }))
+    .unwrap();
+    assert_eq!(total, 3000);
+    assert_eq!(chunks.iter().sum::<usize>(), 3000);
+    assert!(chunks.iter().all(|length| *length <= 512));
+
+    server.finish(&mut client);
+}
+
+#[test]
+fn status_error_keeps_body_excerpt() {
+    let server = Server::start(vec![response("404 Not Found", &[], b"no such thing")]);
+    let mut client = client();
+
+    let error = block_on(client.get_request(&server.url("/missing"), TIMEOUT)).unwrap_err();
+    match error {
+        Error::Status(404, excerpt) => assert_eq!(&excerpt[..], b"no such thing"),
+        error => panic!("unexpected error {error:?}"),
+    }
+
+    server.finish(&mut client);
+}
+
+#[test]
+fn error_bodies_are_not_streamed() {
+    let server = Server::start(vec![
+        response("500 Internal Server Error", &[], b"something broke"),
+        response("200 OK", &[], b"fine"),
+    ]);
+    let mut client = client().with_keep_alive(Duration::from_secs(30));
+
+    let mut streamed = Vec::new();
+    let error = block_on(client.get_stream(&server.url("/"), TIMEOUT, |chunk| {
+        streamed.extend_from_slice(chunk);
+        Ok(())
+    }))
+    .unwrap_err();
+    match error {
+        Error::Status(500, excerpt) => assert_eq!(&excerpt[..], b"something broke"),
+        error => panic!("unexpected error {error:?}"),
+    }
+    assert!(streamed.is_empty());
+
+    // The error body was drained, so the connection is still usable
+    let body = block_on(client.get_request(&server.url("/"), TIMEOUT)).unwrap();
+    assert_eq!(&body[..], b"fine");
+
+    let received = server.finish(&mut client);
+    assert_eq!(received[0].connection, received[1].connection);
+}
+
+#[test]
+fn error_pages_are_not_parsed_as_events() {
+    let server = Server::start(vec![response("404 Not Found", &[], b"data: not an event\n\n")]);
+    let mut client = client();
+
+    let url = server.url("/events");
+    let events: Channel<CriticalSectionRawMutex, Event, 4> = Channel::new();
+    let error = block_on(SseClient::new(&url).run(&mut client, events.sender())).unwrap_err();
+    assert!(matches!(error, Error::Status(404, _)));
+    assert!(events.try_receive().is_err());
+
+    server.finish(&mut client);
+}
+
+#[test]
+fn post_sends_body_and_content_type() {
+    let server = Server::start(vec![response("201 Created", &[], b"{}")]);
+    let mut client = client();
+
+    block_on(client.post_request(&server.url("/items"), ContentType::ApplicationJson, br#"{"a":1}"#, TIMEOUT)).unwrap();
+
+    let received = server.finish(&mut client);
+    assert_eq!(received[0].request_line(), "POST /items HTTP/1.1");
+    assert_eq!(received[0].header("Content-Type"), Some("application/json"));
+    assert_eq!(received[0].body, br#"{"a":1}"#);
+}
+
+/// A body source producing a fixed text at most four bytes at a time
+struct Pieces(&'static [u8]);
+
+impl BodySource for Pieces {
+    fn read_at(&self, offset: usize, buffer: &mut [u8]) -> Result<usize, Error> {
+        let rest = self.0.get(offset..).unwrap_or_default();
+        let length = rest.len().min(buffer.len()).min(4);
+        buffer[..length].copy_from_slice(&rest[..length]);
+        Ok(length)
+    }
+}
+
+#[test]
+fn upload_is_chunked() {
+    let server = Server::start(vec![response("200 OK", &[], b"")]);
+    let mut client = client();
+
+    let source = Pieces(b"streamed upload");
+    block_on(client.upload(&server.url("/upload"), ContentType::TextPlain, &source, TIMEOUT)).unwrap();
+
+    let received = server.finish(&mut client);
+    assert_eq!(received[0].header("Transfer-Encoding"), Some("chunked"));
+    assert_eq!(received[0].body, b"streamed upload");
+}
+
+#[test]
+fn keep_alive_reuses_connection() {
+    let server = Server::start(vec![response("200 OK", &[], b"one"), response("200 OK", &[], b"two")]);
+    let mut client = client().with_keep_alive(Duration::from_secs(30));
+
+    let first = block_on(client.get_request(&server.url("/1"), TIMEOUT)).unwrap();
+    let second = block_on(client.get_request(&server.url("/2"), TIMEOUT)).unwrap();
+    assert_eq!(&first[..], b"one");
+    assert_eq!(&second[..], b"two");
+
+    let received = server.finish(&mut client);
+    assert_eq!(received[0].connection, received[1].connection);
+}
+
+#[test]
+fn keep_alive_reconnects_when_server_closed() {
+    let server = Server::start(vec![
+        response("200 OK", &[], b"one"),
+        None,
+        response("200 OK", &[], b"two"),
+    ]);
+    let mut client = client().with_keep_alive(Duration::from_secs(30));
+
+    block_on(client.get_request(&server.url("/1"), TIMEOUT)).unwrap();
+    let second = block_on(client.get_request(&server.url("/2"), TIMEOUT)).unwrap();
+    assert_eq!(&second[..], b"two");
+
+    let received = server.finish(&mut client);
+    assert_eq!(received.len(), 3);
+    assert_ne!(received[0].connection, received[2].connection);
+}
+
+#[test]
+fn redirects_are_followed() {
+    let server = Server::start(vec![
+        response("302 Found", &[("Location", "/b/target")], b"moved"),
+        response("303 See Other", &[("Location", "other")], b""),
+        response("200 OK", &[], b"done"),
+    ]);
+    let mut client = client().with_redirects(2);
+
+    let url = server.url("/a/start");
+    let request = Request::post(&url)
+        .with_body(ContentType::TextPlain, b"payload")
+        .with_timeout(TIMEOUT);
+    let response = block_on(client.request(&request)).unwrap();
+    assert_eq!(&response.body[..], b"done");
+
+    let received = server.finish(&mut client);
+    assert_eq!(received[0].request_line(), "POST /a/start HTTP/1.1");
+    assert_eq!(received[1].request_line(), "GET /b/target HTTP/1.1");
+    assert_eq!(received[2].request_line(), "GET /b/other HTTP/1.1");
+    assert!(received[2].body.is_empty());
+}
+
+#[test]
+fn client_authenticator_is_used_unless_request_has_one() {
+    let server = Server::start(vec![
+        response("200 OK", &[], b""),
+        response("200 OK", &[], b""),
+    ]);
+    let client_token = Box::leak(Box::new(Bearer::new("client-token")));
+    let request_token = Bearer::new("request-token");
+    let mut client = client().with_authenticator(client_token);
+
+    let url = server.url("/");
+    block_on(client.get_request(&url, TIMEOUT)).unwrap();
+    let request = Request::get(&url)
+        .with_authenticator(&request_token)
+        .with_timeout(TIMEOUT);
+    block_on(client.request(&request)).unwrap();
+
+    let received = server.finish(&mut client);
+    assert_eq!(received[0].header("Authorization"), Some("Bearer client-token"));
+    assert_eq!(received[1].header("Authorization"), Some("Bearer request-token"));
+}
+
+#[test]
+fn put_is_kept_unless_redirected_with_see_other() {
+    let server = Server::start(vec![
+        response("302 Found", &[("Location", "/moved")], b""),
+        response("303 See Other", &[("Location", "/status")], b""),
+        response("200 OK", &[], b"done"),
+    ]);
+    let mut client = client().with_redirects(2);
+
+    let url = server.url("/item");
+    let request = Request::new(Method::PUT, &url)
+        .with_body(ContentType::TextPlain, b"payload")
+        .with_timeout(TIMEOUT);
+    block_on(client.request(&request)).unwrap();
+
+    let received = server.finish(&mut client);
+    assert_eq!(received[1].request_line(), "PUT /moved HTTP/1.1");
+    assert_eq!(received[1].body, b"payload");
+    assert_eq!(received[2].request_line(), "GET /status HTTP/1.1");
+    assert!(received[2].body.is_empty());
+}
+
+#[test]
+fn redirect_locations_are_resolved() {
+    let target = Server::start(vec![response("200 OK", &[], b"done")]);
+    let absolute = target.url("/final#fragment").replacen("http", "HTTP", 1);
+
+    let server = Server::start(vec![
+        response("302 Found", &[("Location", "../c/./next")], b""),
+        response("302 Found", &[("Location", "?page=2")], b""),
+        response("302 Found", &[("Location", "/r?to=http://example.com/")], b""),
+        response("302 Found", &[("Location", &absolute)], b""),
+    ]);
+    let mut client = client().with_redirects(4);
+
+    let body = block_on(client.get_request(&server.url("/a/b/start"), TIMEOUT)).unwrap();
+    assert_eq!(&body[..], b"done");
+
+    let received = server.finish(&mut client);
+    assert_eq!(received[1].request_line(), "GET /a/c/next HTTP/1.1");
+    assert_eq!(received[2].request_line(), "GET /a/c/next?page=2 HTTP/1.1");
+    assert_eq!(received[3].request_line(), "GET /r?to=http://example.com/ HTTP/1.1");
+
+    let received = target.finish(&mut client);
+    assert_eq!(received[0].request_line(), "GET /final HTTP/1.1");
+}
+
+#[test]
+fn redirect_to_another_scheme_fails() {
+    let server = Server::start(vec![response("302 Found", &[("Location", "ftp://example.com/file")], b"")]);
+    let mut client = client().with_redirects(1);
+
+    let error = block_on(client.get_request(&server.url("/"), TIMEOUT)).unwrap_err();
+    assert!(matches!(error, Error::Reqless(_)));
+
+    server.finish(&mut client);
+}
+
+#[test]
+fn too_many_redirects() {
+    let server = Server::start(vec![
+        response("307 Temporary Redirect", &[("Location", "/again")], b""),
+        response("307 Temporary Redirect", &[("Location", "/again")], b""),
+    ]);
+    let mut client = client().with_redirects(1);
+
+    let error = block_on(client.get_request(&server.url("/"), TIMEOUT)).unwrap_err();
+    assert!(matches!(error, Error::TooManyRedirects));
+
+    server.finish(&mut client);
+}
+
+#[test]
+fn server_date_is_recorded() {
+    let server = Server::start(vec![response(
+        "200 OK",
+        &[("Date", "Sun, 06 Nov 1994 08:49:37 GMT")],
+        b"",
+    )]);
+    let mut client = client();
+    assert!(client.server_date().is_none());
+
+    block_on(client.get_request(&server.url("/"), TIMEOUT)).unwrap();
+    let date = client.server_date().unwrap();
+    assert_eq!(date.date.unix_timestamp(), 784_111_777);
+
+    server.finish(&mut client);
+}
+
+#[test]
+fn important_headers_are_kept() {
+    let names: Vec<String> = (0..20).map(|index| format!("X-Filler-{index}")).collect();
+    let mut headers: Vec<(&str, &str)> = names.iter().map(|name| (name.as_str(), "filler")).collect();
+    headers.push(("Date", "Sun, 06 Nov 1994 08:49:37 GMT"));
+    let server = Server::start(vec![response("200 OK", &headers, b"")]);
+    let mut client = client();
+
+    block_on(client.get_request(&server.url("/"), TIMEOUT)).unwrap();
+    let date = client.server_date().unwrap();
+    assert_eq!(date.date.unix_timestamp(), 784_111_777);
+
+    server.finish(&mut client);
+}
+
+#[test]
+fn important_headers_too_long_fail() {
+    let etag = format!("\"{}\"", "x".repeat(200));
+    let server = Server::start(vec![response("200 OK", &[("ETag", &etag)], b"")]);
+    let mut client = client();
+
+    let error = block_on(client.get_request(&server.url("/"), TIMEOUT)).unwrap_err();
+    assert!(matches!(error, Error::HeaderNotStorable));
+
+    server.finish(&mut client);
+}
+
+#[test]
+fn long_locations_are_followed() {
+    let location = format!("/{}", "x".repeat(200));
+    let server = Server::start(vec![
+        response("302 Found", &[("Location", &location)], b""),
+        response("200 OK", &[], b"done"),
+    ]);
+    let mut client = client().with_redirects(1);
+
+    let body = block_on(client.get_request(&server.url("/"), TIMEOUT)).unwrap();
+    assert_eq!(&body[..], b"done");
+
+    let received = server.finish(&mut client);
+    assert_eq!(received[1].request_line(), format!("GET {location} HTTP/1.1"));
+}
+
+#[test]
+fn too_long_location_fails_only_when_followed() {
+    let location = format!("/{}", "x".repeat(300));
+
+    let server = Server::start(vec![response("201 Created", &[("Location", &location)], b"made")]);
+    let mut following = client().with_redirects(1);
+    let body = block_on(following.get_request(&server.url("/"), TIMEOUT)).unwrap();
+    assert_eq!(&body[..], b"made");
+    server.finish(&mut following);
+
+    let server = Server::start(vec![response("302 Found", &[("Location", &location)], b"")]);
+    let mut not_following = client();
+    let url = server.url("/");
+    let request = Request::get(&url).with_timeout(TIMEOUT);
+    let redirect = block_on(not_following.request(&request)).unwrap();
+    assert_eq!(redirect.status, 302);
+    assert!(redirect.headers.get("Location").is_none());
+    server.finish(&mut not_following);
+
+    let server = Server::start(vec![response("302 Found", &[("Location", &location)], b"")]);
+    let mut following = client().with_redirects(1);
+    let error = block_on(following.get_request(&server.url("/"), TIMEOUT)).unwrap_err();
+    assert!(matches!(error, Error::UrlTooLong));
+    server.finish(&mut following);
+}
+
+#[test]
+fn retry_after_date_is_honored() {
+    let server = Server::start(vec![
+        response(
+            "503 Service Unavailable",
+            &[
+                ("Date", "Sun, 06 Nov 1994 08:49:37 GMT"),
+                ("Retry-After", "Sun, 06 Nov 1994 08:49:38 GMT"),
+            ],
+            b"",
+        ),
+        response("200 OK", &[], b"done"),
+    ]);
+    let policy = RetryPolicy::new(2, Duration::from_millis(1), Duration::from_secs(5));
+    let mut client = client().with_retry_policy(policy);
+
+    let start = std::time::Instant::now();
+    let body = block_on(client.get_request(&server.url("/"), TIMEOUT)).unwrap();
+    assert_eq!(&body[..], b"done");
+    assert!(start.elapsed() >= std::time::Duration::from_secs(1));
+
+    server.finish(&mut client);
+}
+
+#[test]
+fn post_is_retried_only_when_enabled() {
+    let policy = RetryPolicy::new(2, Duration::from_millis(1), Duration::from_secs(5));
+
+    let (result, attempts) = post_after("500 Internal Server Error", policy, false);
+    assert!(matches!(result, Err(Error::Status(500, _))));
+    assert_eq!(attempts, 1);
+
+    let (result, attempts) = post_after("429 Too Many Requests", policy, true);
+    assert_eq!(&result.unwrap()[..], b"done");
+    assert_eq!(attempts, 2);
+
+    let (result, attempts) = post_after("500 Internal Server Error", policy.with_non_idempotent_retries(), true);
+    assert_eq!(&result.unwrap()[..], b"done");
+    assert_eq!(attempts, 2);
+}
+
+/// Send a POST request answered by a status, and then by a success if
+/// `retried`, and return the result with the number of attempts
+fn post_after(status: &str, policy: RetryPolicy, retried: bool) -> (Result<Vec<u8>, Error>, usize) {
+    let mut responses = vec![response(status, &[], b"")];
+    if retried {
+        responses.push(response("200 OK", &[], b"done"));
+    }
+    let server = Server::start(responses);
+    let mut client = client().with_retry_policy(policy);
+
+    let result = block_on(client.post_request(&server.url("/ingest"), ContentType::TextPlain, b"sample", TIMEOUT));
+    let received = server.finish(&mut client);
+    (result.map(|body| body.to_vec()), received.len())
+}
+
+#[test]
+fn retry_after_only_applies_to_429_and_503() {
+    let server = Server::start(vec![
+        response("500 Internal Server Error", &[("Retry-After", "120")], b""),
+        response("503 Service Unavailable", &[("Retry-After", "120")], b""),
+    ]);
+    let policy = RetryPolicy::new(3, Duration::from_millis(1), Duration::from_secs(5));
+    let mut client = client().with_retry_policy(policy);
+
+    let error = block_on(client.get_request(&server.url("/"), TIMEOUT)).unwrap_err();
+    assert!(matches!(error, Error::Status(503, _)));
+
+    let received = server.finish(&mut client);
+    assert_eq!(received.len(), 2);
+}
+
+#[test]
+fn gzip_body_is_inflated() {
+    let content = b"Compressed content, stored as is";
+    let server = Server::start(vec![response("200 OK", &[("Content-Encoding", "gzip")], &gzip_stored(content))]);
+    let inflater: &'static mut Inflater = Box::leak(Box::default());
+    let mut client = client().with_decompression(inflater);
+
+    let body = block_on(client.get_request(&server.url("/"), TIMEOUT)).unwrap();
+    assert_eq!(&body[..], content);
+
+    let received = server.finish(&mut client);
+    assert_eq!(received[0].header("Accept-Encoding"), Some("gzip, deflate"));
+}
+
+#[test]
+fn large_gzip_body_is_inflated() {
+    let content = words(100_000);
+    let mut gzip = vec![0x1f, 0x8b, 0x08, 0x00, 0, 0, 0, 0, 0x00, 0xff];
+    gzip.extend(compress_to_vec(&content, 6));
+    gzip.extend_from_slice(&crc32(&content).to_le_bytes());
+    gzip.extend_from_slice(&u32::try_from(content.len()).unwrap().to_le_bytes());
+    assert!(gzip.len() < content.len() / 2);
+    let server = Server::start(vec![response("200 OK", &[("Content-Encoding", "gzip")], &gzip)]);
+    let inflater: &'static mut Inflater = Box::leak(Box::default());
+    let mut client = client().with_decompression(inflater);
+
+    let mut body = Vec::new();
+    block_on(client.get_stream(&server.url("/"), TIMEOUT, |chunk| {
+        body.extend_from_slice(chunk);
+        Ok(())
+    }))
+    .unwrap();
+    assert!(body == content);
+
+    server.finish(&mut client);
+}
+
+#[test]
+fn large_zlib_body_is_inflated_from_split_header() {
+    let content = words(100_000);
+    let zlib = compress_to_vec_zlib(&content, 6);
+    assert!(zlib.len() < content.len() / 2);
+
+    // The zlib header arrives in two chunks
+    let mut chunked = b"HTTP/1.1 200 OK\r\nContent-Encoding: deflate\r\nTransfer-Encoding: chunked\r\n\r\n".to_vec();
+    for chunk in std::iter::once(&zlib[..1]).chain(zlib[1..].chunks(4096)) {
+        chunked.extend_from_slice(format!("{:x}\r\n", chunk.len()).as_bytes());
+        chunked.extend_from_slice(chunk);
+        chunked.extend_from_slice(b"\r\n");
+    }
+    chunked.extend_from_slice(b"0\r\n\r\n");
+    let server = Server::start(vec![Some(chunked)]);
+    let inflater: &'static mut Inflater = Box::leak(Box::default());
+    let mut client = client().with_decompression(inflater);
+
+    let mut body = Vec::new();
+    block_on(client.get_stream(&server.url("/"), TIMEOUT, |chunk| {
+        body.extend_from_slice(chunk);
+        Ok(())
+    }))
+    .unwrap();
+    assert!(body == content);
+
+    server.finish(&mut client);
+}
+
+#[test]
+fn bodiless_responses_are_not_inflated() {
+    let head = b"HTTP/1.1 200 OK\r\nContent-Encoding: gzip\r\nContent-Length: 1234\r\nDate: Sun, 06 Nov 1994 08:49:37 GMT\r\n\r\n";
+    let server = Server::start(vec![
+        Some(head.to_vec()),
+        response("304 Not Modified", &[("Content-Encoding", "gzip")], b""),
+    ]);
+    let inflater: &'static mut Inflater = Box::leak(Box::default());
+    let mut client = client().with_decompression(inflater);
+
+    let url = server.url("/");
+    let request = Request::new(Method::HEAD, &url).with_timeout(TIMEOUT);
+    let response = block_on(client.request(&request)).unwrap();
+    assert_eq!(response.status, 200);
+    assert!(response.body.is_empty());
+    assert!(client.server_date().is_some());
+
+    let request = Request::get(&url).with_timeout(TIMEOUT);
+    let response = block_on(client.request(&request)).unwrap();
+    assert_eq!(response.status, 304);
+    assert!(response.body.is_empty());
+
+    server.finish(&mut client);
+}
+
+/// Wrap bytes in a gzip member made of a single stored block
+fn gzip_stored(content: &[u8]) -> Vec<u8> {
+    let length = u16::try_from(content.len()).unwrap();
+
+    let mut gzip = vec![0x1f, 0x8b, 0x08, 0x00, 0, 0, 0, 0, 0x00, 0xff];
+    gzip.push(0x01);
+    gzip.extend_from_slice(&length.to_le_bytes());
+    gzip.extend_from_slice(&(!length).to_le_bytes());
+    gzip.extend_from_slice(content);
+    gzip.extend_from_slice(&crc32(content).to_le_bytes());
+    gzip.extend_from_slice(&u32::from(length).to_le_bytes());
+    gzip
+}
+
+/// Return text of random words, longer than the inflate window
+///
+/// The words repeat at all distances, so it compresses with Huffman codes
+/// and back references rather than stored blocks.
+fn words(length: usize) -> Vec<u8> {
+    const WORDS: [&[u8]; 8] = [b"alpha ", b"bravo ", b"charlie ", b"delta ", b"echo ", b"foxtrot ", b"golf ", b"hotel "];
+
+    let mut rng = TestRng::default();
+    let mut text = Vec::with_capacity(length);
+    while text.len() < length {
+        text.extend_from_slice(WORDS[rng.next_u32() as usize % WORDS.len()]);
+    }
+    text.truncate(length);
+    text
+}
+
+/// Compute the CRC-32 of the gzip trailer
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0_u32;
+    for byte in bytes {
+        crc ^= u32::from(*byte);
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
@@ -0,0 +1,143 @@
+// Copyright Claudio Mattera 2024.
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! HTTP service with abandoned requests
+
+#![allow(clippy::result_large_err)]
+
+use core::pin::pin;
+
+use embassy_futures::block_on;
+use embassy_futures::poll_once;
+use embassy_futures::select::select;
+use embassy_futures::select::Either;
+use embassy_futures::yield_now;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+use embassy_time::Instant;
+
+use esp32c3_embassy::http::ClientTrait;
+use esp32c3_embassy::http::Error;
+use esp32c3_embassy::http::Headers;
+use esp32c3_embassy::http::Request;
+use esp32c3_embassy::http::ResponseHead;
+use esp32c3_embassy::http_service::HttpService;
+use esp32c3_embassy::http_service::Priority;
+use esp32c3_embassy::http_service::ReplySignal;
+
+/// A client answering each request with its index, in the order served
+struct CountingClient<'a> {
+    /// Number of requests served so far
+    served: usize,
+
+    /// Index of a request held until `gate` is signaled
+    held: Option<usize>,
+
+    /// Signal releasing the held request
+    gate: &'a Signal<CriticalSectionRawMutex, ()>,
+}
+
+impl<'a> CountingClient<'a> {
+    /// Create a client holding one request until `gate` is signaled
+    fn new(held: Option<usize>, gate: &'a Signal<CriticalSectionRawMutex, ()>) -> Self {
+        Self { served: 0, held, gate }
+    }
+}
+
+impl ClientTrait for CountingClient<'_> {
+    async fn request_stream<F>(&mut self, _request: &Request<'_>, mut on_chunk: F) -> Result<ResponseHead, Error>
+    where
+        F: FnMut(&[u8]) -> Result<(), Error>,
+    {
+        let index = self.served;
+        self.served += 1;
+        if self.held == Some(index) {
+            self.gate.wait().await;
+        }
+        // Like a real client waiting for the network
+        yield_now().await;
+
+        on_chunk(index.to_string().as_bytes())?;
+        Ok(ResponseHead {
+            status: 200,
+            headers: Headers::default(),
+            received_at: Instant::now(),
+        })
+    }
+}
+
+#[test]
+fn queued_request_is_skipped_once_abandoned() {
+    static REPLY: ReplySignal = ReplySignal::new();
+    let service = HttpService::new();
+    let gate = Signal::new();
+    let mut client = CountingClient::new(None, &gate);
+
+    let caller = async {
+        // Queued, then dropped before the service runs
+        let abandoned = service.request(Priority::Low, Request::get("http://test/abandoned"), &REPLY);
+        assert!(poll_once(pin!(abandoned)).is_pending());
+
+        service
+            .request(Priority::Low, Request::get("http://test/next"), &REPLY)
+            .await
+    };
+
+    let Either::Second(response) = block_on(select(service.run(&mut client), caller));
+    assert_eq!(&response.unwrap().body[..], b"0");
+    assert_eq!(client.served, 1);
+}
+
+#[test]
+fn response_to_abandoned_request_is_discarded() {
+    static REPLY: ReplySignal = ReplySignal::new();
+    let service = HttpService::new();
+    let gate = Signal::new();
+    let mut client = CountingClient::new(Some(0), &gate);
+
+    let caller = async {
+        // Dropped while the service is serving it
+        let abandoned = service.request(Priority::Low, Request::get("http://test/abandoned"), &REPLY);
+        let waited = select(abandoned, async {
+            for _ in 0..4 {
+                yield_now().await;
+            }
+        })
+        .await;
+        assert!(matches!(waited, Either::Second(())));
+        gate.signal(());
+
+        service
+            .request(Priority::High, Request::get("http://test/next"), &REPLY)
+            .await
+    };
+
+    let Either::Second(response) = block_on(select(service.run(&mut client), caller));
+    assert_eq!(&response.unwrap().body[..], b"1");
+    assert_eq!(client.served, 2);
+}
+
+#[test]
+fn requests_are_served_by_priority() {
+    static LOW: ReplySignal = ReplySignal::new();
+    static HIGH: ReplySignal = ReplySignal::new();
+    let service = HttpService::new();
+    let gate = Signal::new();
+    let mut client = CountingClient::new(None, &gate);
+
+    let callers = async {
+        let mut low = pin!(service.request(Priority::Low, Request::get("http://test/low"), &LOW));
+        let mut high = pin!(service.request(Priority::High, Request::get("http://test/high"), &HIGH));
+        assert!(poll_once(low.as_mut()).is_pending());
+        assert!(poll_once(high.as_mut()).is_pending());
+        (low.await, high.await)
+    };
+
+    let Either::Second((low, high)) = block_on(select(service.run(&mut client), callers));
+    assert_eq!(&low.unwrap().body[..], b"1");
+    assert_eq!(&high.unwrap().body[..], b"0");
+}
+
@@ -0,0 +1,143 @@
+// Copyright Claudio Mattera 2024.
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! ES256 tokens, checked against the example of RFC 7515 appendix A.3
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD as BASE64URL;
+use base64::Engine as _;
+
+use embassy_time::Duration;
+
+use p256::ecdsa::signature::Verifier as _;
+use p256::ecdsa::Signature;
+use p256::ecdsa::SigningKey;
+use p256::ecdsa::VerifyingKey;
+
+use esp32c3_embassy::jwt::Error;
+use esp32c3_embassy::jwt::JwtSigner;
+
+/// Public key of the example, x coordinate
+const X: &str = "f83OJ3D2xF1Bg8vub9tLe1gHMzV76e8Tus9uPHvRVEU";
+
+/// Public key of the example, y coordinate
+const Y: &str = "x_FEzRu9m36HLN_tue659LNpXW6pCyStikYjKIWI5a0";
+
+/// Private key of the example
+const D: &str = "jpsQnnGQmL-YBIffH1136cspYG6-0iY7X1fCE9-E9LI";
+
+/// Signing input of the example, header and payload
+const SIGNING_INPUT: &str = "eyJhbGciOiJFUzI1NiJ9.eyJpc3MiOiJqb2UiLA0KICJleHAiOjEzMDA4MTkzODAsDQogImh0dHA6Ly9leGFtcGxlLmNvbS9pc19yb290Ijp0cnVlfQ";
+
+/// Signature of the example
+const SIGNATURE: &str = "DtEhU3ljbEg8L38VWAfUAqOyKAM6-Xx-F4GawxaepmXFCgfTjDxw5djxLa8ISlSApmWQxfKTUJqPP3-Kg6NU1Q";
+
+/// Issue time of minted tokens
+const NOW: u64 = 1_700_000_000;
+
+/// Decode a base64url string
+fn decode(encoded: &str) -> Vec<u8> {
+    let mut decoded = [0_u8; 256];
+    let length = BASE64URL.decode_slice(encoded, &mut decoded).unwrap();
+    decoded[..length].to_vec()
+}
+
+/// Return the public key of the example
+fn verifying_key() -> VerifyingKey {
+    let mut point = vec![0x04];
+    point.extend(decode(X));
+    point.extend(decode(Y));
+    VerifyingKey::from_sec1_bytes(&point).unwrap()
+}
+
+/// Verify the signature of a compact token, and return its decoded header
+/// and claims
+fn verify(token: &str) -> (String, String) {
+    let (signing_input, signature) = token.rsplit_once('.').unwrap();
+    let signature = Signature::from_slice(&decode(signature)).unwrap();
+    verifying_key()
+        .verify(signing_input.as_bytes(), &signature)
+        .unwrap();
+
+    let (header, claims) = signing_input.split_once('.').unwrap();
+    (
+        String::from_utf8(decode(header)).unwrap(),
+        String::from_utf8(decode(claims)).unwrap(),
+    )
+}
+
+#[test]
+fn example_signature_verifies() {
+    let signature = Signature::from_slice(&decode(SIGNATURE)).unwrap();
+    verifying_key()
+        .verify(SIGNING_INPUT.as_bytes(), &signature)
+        .unwrap();
+}
+
+#[test]
+fn example_keys_match() {
+    let key = SigningKey::from_slice(&decode(D)).unwrap();
+    assert_eq!(*key.verifying_key(), verifying_key());
+}
+
+#[test]
+fn token_carries_header_and_claims() {
+    let signer = JwtSigner::from_bytes(&decode(D), "device-1")
+        .unwrap()
+        .with_subject("sensor")
+        .with_audience("backend")
+        .with_key_id("key-1")
+        .with_lifetime(Duration::from_secs(600));
+
+    let token = signer.mint_with_seed(NOW, [7; 32]).unwrap();
+    assert_eq!(token.expires_at(), NOW + 600);
+
+    let (header, claims) = verify(token.as_str());
+    assert_eq!(header, r#"{"alg":"ES256","typ":"JWT","kid":"key-1"}"#);
+    assert_eq!(
+        claims,
+        r#"{"iss":"device-1","sub":"sensor","aud":"backend","iat":1700000000,"exp":1700000600}"#
+    );
+}
+
+#[test]
+fn optional_claims_are_omitted() {
+    let signer = JwtSigner::from_bytes(&decode(D), "device-1").unwrap();
+
+    let token = signer.mint_with_seed(NOW, [7; 32]).unwrap();
+
+    let (header, claims) = verify(token.as_str());
+    assert_eq!(header, r#"{"alg":"ES256","typ":"JWT"}"#);
+    assert_eq!(claims, r#"{"iss":"device-1","iat":1700000000,"exp":1700003600}"#);
+}
+
+#[test]
+fn signatures_depend_on_the_seed() {
+    let signer = JwtSigner::from_bytes(&decode(D), "device-1").unwrap();
+
+    let first = signer.mint_with_seed(NOW, [1; 32]).unwrap();
+    let again = signer.mint_with_seed(NOW, [1; 32]).unwrap();
+    let other = signer.mint_with_seed(NOW, [2; 32]).unwrap();
+    assert_eq!(first.as_str(), again.as_str());
+    assert_ne!(first.as_str(), other.as_str());
+    verify(other.as_str());
+}
+
+#[test]
+fn freshness_keeps_a_margin() {
+    let signer = JwtSigner::from_bytes(&decode(D), "device-1").unwrap();
+    let token = signer.mint_with_seed(NOW, [7; 32]).unwrap();
+
+    let margin = Duration::from_secs(60);
+    assert!(token.is_fresh(NOW, margin));
+    assert!(token.is_fresh(NOW + 3600 - 61, margin));
+    assert!(!token.is_fresh(NOW + 3600 - 60, margin));
+}
+
+#[test]
+fn invalid_key_is_rejected() {
+    let result = JwtSigner::from_bytes(&[0; 32], "device-1");
+    assert!(matches!(result, Err(Error::InvalidKey)));
+}
@@ -0,0 +1,340 @@
+// Copyright Claudio Mattera 2024.
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! SNTP packets, measurements and kiss-o'-death handling
+
+use std::collections::VecDeque;
+use std::net::IpAddr;
+use std::net::SocketAddr;
+
+use embassy_futures::block_on;
+use embassy_time::Duration;
+use embassy_time::Instant;
+
+use esp32c3_embassy::host::StdDns;
+use esp32c3_embassy::http::ErrorClass;
+use esp32c3_embassy::sntp::Error;
+use esp32c3_embassy::sntp::KissCode;
+use esp32c3_embassy::sntp::Measurement;
+use esp32c3_embassy::sntp::NtpSocket;
+use esp32c3_embassy::sntp::Packet;
+use esp32c3_embassy::sntp::SntpClient;
+use esp32c3_embassy::sntp::Timestamp;
+use esp32c3_embassy::sntp::NTP_PORT;
+use esp32c3_embassy::sntp::PACKET_SIZE;
+
+/// Seconds between the NTP epoch, 1900, and the Unix epoch, 1970
+const NTP_UNIX_OFFSET: u64 = 2_208_988_800;
+
+/// A Unix time in seconds, in November 2023
+const UNIX_SECONDS: u64 = 1_700_000_000;
+
+/// Build a timestamp from NTP seconds and a binary fraction
+fn timestamp(seconds: u64, fraction: u32) -> Timestamp {
+    Timestamp(seconds << 32 | u64::from(fraction))
+}
+
+/// Build a server response to a request
+fn response(request: &Packet, stratum: u8, reference_id: [u8; 4]) -> Packet {
+    let now = timestamp(UNIX_SECONDS + NTP_UNIX_OFFSET, 0);
+    Packet {
+        leap: 0,
+        version: 4,
+        mode: 4,
+        stratum,
+        reference_id,
+        origin: request.transmit,
+        receive: now,
+        transmit: now,
+    }
+}
+
+#[test]
+fn packet_round_trip() {
+    let packet = Packet {
+        leap: 1,
+        version: 4,
+        mode: 4,
+        stratum: 2,
+        reference_id: *b"GPS\0",
+        origin: timestamp(3_900_000_000, 0x1234_5678),
+        receive: timestamp(3_900_000_001, 0x8000_0000),
+        transmit: timestamp(3_900_000_002, 0xffff_ffff),
+    };
+
+    let decoded = Packet::decode(&packet.encode()).unwrap();
+    assert_eq!(decoded.leap, packet.leap);
+    assert_eq!(decoded.version, packet.version);
+    assert_eq!(decoded.mode, packet.mode);
+    assert_eq!(decoded.stratum, packet.stratum);
+    assert_eq!(decoded.reference_id, packet.reference_id);
+    assert_eq!(decoded.origin, packet.origin);
+    assert_eq!(decoded.receive, packet.receive);
+    assert_eq!(decoded.transmit, packet.transmit);
+}
+
+#[test]
+fn request_layout() {
+    let transmit = timestamp(1, 0x8000_0000);
+    let bytes = Packet::request(transmit).encode();
+
+    // No leap indicator, version 4, client mode
+    assert_eq!(bytes[0], 0b00_100_011);
+    assert!(bytes[1..40].iter().all(|byte| *byte == 0));
+    assert_eq!(bytes[40..48], [0, 0, 0, 1, 0x80, 0, 0, 0]);
+}
+
+#[test]
+fn decode_ignores_extensions_and_rejects_short_packets() {
+    let mut bytes = response(&Packet::request(timestamp(1, 0)), 1, *b"GPS\0").encode().to_vec();
+    bytes.extend_from_slice(&[0xaa; 16]);
+    assert_eq!(Packet::decode(&bytes).unwrap().stratum, 1);
+
+    let result = Packet::decode(&bytes[..PACKET_SIZE - 1]);
+    assert!(matches!(result, Err(Error::Malformed)));
+}
+
+#[test]
+fn micros_convert_to_fractions() {
+    assert_eq!(Timestamp::from_micros(1_500_000), timestamp(1, 0x8000_0000));
+    assert_eq!(Timestamp::from_micros(250_000), timestamp(0, 0x4000_0000));
+}
+
+#[test]
+fn unix_micros_in_first_era() {
+    let unix_epoch = timestamp(NTP_UNIX_OFFSET, 0);
+    assert_eq!(unix_epoch.to_unix_micros(), 0);
+
+    let now = timestamp(UNIX_SECONDS + NTP_UNIX_OFFSET, 0x4000_0000);
+    assert_eq!(now.to_unix_micros(), 1_700_000_000_250_000);
+
+    // Lowest seconds still taken from the first era, in 1968
+    let earliest = timestamp(1 << 31, 0);
+    assert_eq!(earliest.to_unix_micros(), -61_505_152_000_000);
+}
+
+#[test]
+fn unix_micros_in_second_era() {
+    // The first era ends on 2036-02-07 at 06:28:16
+    let rollover = timestamp(0, 0);
+    assert_eq!(rollover.to_unix_micros(), 2_085_978_496_000_000);
+
+    let later = timestamp(1_000, 0x8000_0000);
+    assert_eq!(later.to_unix_micros(), 2_085_979_496_500_000);
+
+    let latest = timestamp((1 << 31) - 1, 0);
+    assert_eq!(latest.to_unix_micros(), (2_085_978_496 + (1 << 31) - 1) * 1_000_000);
+}
+
+#[test]
+fn offset_and_delay() {
+    // T1 and T4 in microseconds since boot, T2 and T3 in Unix time
+    let t1 = 1_000_000;
+    let t2 = timestamp(UNIX_SECONDS + NTP_UNIX_OFFSET, 0x4000_0000);
+    let t3 = timestamp(UNIX_SECONDS + NTP_UNIX_OFFSET, 0x8000_0000);
+    let t4 = 1_750_000;
+
+    let mut packet = response(&Packet::request(timestamp(1, 0)), 2, *b"GPS\0");
+    packet.receive = t2;
+    packet.transmit = t3;
+
+    let measurement = Measurement::from_timestamps(t1, &packet, t4);
+
+    // ((T2 - T1) + (T3 - T4)) / 2 and (T4 - T1) - (T3 - T2)
+    let unix = 1_700_000_000_000_000;
+    assert_eq!(measurement.offset, unix - 1_000_000);
+    assert_eq!(measurement.delay, Duration::from_micros(500_000));
+    assert_eq!(measurement.stratum, 2);
+    assert_eq!(measurement.unix_micros_at(Instant::from_micros(t4)), unix + 750_000);
+}
+
+#[test]
+fn delay_is_never_negative() {
+    let mut packet = response(&Packet::request(timestamp(1, 0)), 2, *b"GPS\0");
+    packet.receive = timestamp(UNIX_SECONDS + NTP_UNIX_OFFSET, 0);
+    packet.transmit = timestamp(UNIX_SECONDS + NTP_UNIX_OFFSET + 1, 0);
+
+    let measurement = Measurement::from_timestamps(0, &packet, 1_000);
+    assert_eq!(measurement.delay, Duration::from_micros(0));
+}
+
+#[test]
+fn response_must_answer_the_request() {
+    let request = Packet::request(timestamp(1, 0));
+    let other = Packet::request(timestamp(2, 0));
+
+    assert!(response(&request, 1, *b"GPS\0").validate(&request).is_ok());
+
+    let result = response(&other, 1, *b"GPS\0").validate(&request);
+    assert!(matches!(result, Err(Error::UnexpectedResponse)));
+}
+
+#[test]
+fn invalid_responses() {
+    let request = Packet::request(timestamp(1, 0));
+
+    let mut client_mode = response(&request, 1, *b"GPS\0");
+    client_mode.mode = 3;
+    assert!(matches!(client_mode.validate(&request), Err(Error::Malformed)));
+
+    let mut alarm = response(&request, 1, *b"GPS\0");
+    alarm.leap = 3;
+    assert!(matches!(alarm.validate(&request), Err(Error::Unsynchronized)));
+
+    let mut no_time = response(&request, 1, *b"GPS\0");
+    no_time.transmit = Timestamp(0);
+    assert!(matches!(no_time.validate(&request), Err(Error::Unsynchronized)));
+}
+
+#[test]
+fn kiss_codes() {
+    let request = Packet::request(timestamp(1, 0));
+
+    for (code, denied, rate) in [(b"DENY", true, false), (b"RSTR", true, false), (b"RATE", false, true)] {
+        let error = response(&request, 0, *code).validate(&request).unwrap_err();
+        let Error::KissOfDeath(kiss) = error else {
+            panic!("unexpected error {error:?}");
+        };
+        assert_eq!(kiss, KissCode(*code));
+        assert_eq!(kiss.is_denied(), denied);
+        assert_eq!(kiss.is_rate(), rate);
+
+        let class = if denied { ErrorClass::Permanent } else { ErrorClass::Transient };
+        assert_eq!(error.class(), class);
+    }
+}
+
+/// How a stand-in server answers
+#[derive(Clone, Copy, Debug)]
+enum Answer {
+    /// Current time
+    Time,
+
+    /// A kiss-o'-death packet
+    Kiss(&'static [u8; 4]),
+
+    /// A response to another request, then the current time
+    Stale,
+
+    /// A datagram from another address, then the current time
+    Stranger,
+}
+
+/// A socket answering requests on behalf of stand-in servers
+struct TestSocket {
+    /// Answer of each server
+    servers: Vec<(IpAddr, Answer)>,
+
+    /// Servers queried, in order
+    queried: Vec<IpAddr>,
+
+    /// Datagrams waiting to be received
+    pending: VecDeque<([u8; PACKET_SIZE], SocketAddr)>,
+}
+
+impl TestSocket {
+    /// Create a socket for servers at `10.0.0.1`, `10.0.0.2` and so on
+    fn new(answers: &[Answer]) -> Self {
+        let servers = answers
+            .iter()
+            .enumerate()
+            .map(|(index, answer)| (server(index).parse().unwrap(), *answer))
+            .collect();
+        Self {
+            servers,
+            queried: Vec::new(),
+            pending: VecDeque::new(),
+        }
+    }
+}
+
+/// Return the address of a stand-in server
+fn server(index: usize) -> &'static str {
+    ["10.0.0.1", "10.0.0.2", "10.0.0.3"][index]
+}
+
+impl NtpSocket for TestSocket {
+    async fn send_to(&mut self, buffer: &[u8], remote: SocketAddr) -> Result<(), Error> {
+        assert_eq!(remote.port(), NTP_PORT);
+        let request = Packet::decode(buffer).unwrap();
+        self.queried.push(remote.ip());
+
+        let (_, answer) = self
+            .servers
+            .iter()
+            .find(|(address, _)| *address == remote.ip())
+            .unwrap();
+        let time = response(&request, 2, *b"GPS\0").encode();
+        match answer {
+            Answer::Time => {}
+            Answer::Kiss(code) => {
+                self.pending.push_back((response(&request, 0, **code).encode(), remote));
+                return Ok(());
+            }
+            Answer::Stale => {
+                let other = Packet::request(timestamp(1, 0));
+                self.pending.push_back((response(&other, 2, *b"GPS\0").encode(), remote));
+            }
+            Answer::Stranger => {
+                let stranger = SocketAddr::new("10.9.9.9".parse().unwrap(), NTP_PORT);
+                self.pending.push_back((time, stranger));
+            }
+        }
+        self.pending.push_back((time, remote));
+        Ok(())
+    }
+
+    async fn receive_from(&mut self, buffer: &mut [u8]) -> Result<(usize, SocketAddr), Error> {
+        let (datagram, sender) = self.pending.pop_front().ok_or(Error::Io)?;
+        buffer[..PACKET_SIZE].copy_from_slice(&datagram);
+        Ok((PACKET_SIZE, sender))
+    }
+}
+
+#[test]
+fn denied_servers_are_skipped_for_good() {
+    for code in [b"DENY", b"RSTR"] {
+        let mut socket = TestSocket::new(&[Answer::Kiss(code), Answer::Time]);
+        let servers = [server(0), server(1)];
+        let mut client = SntpClient::new(StdDns, &servers);
+
+        let measurement = block_on(client.query(&mut socket)).unwrap();
+        assert_eq!(measurement.stratum, 2);
+        block_on(client.query(&mut socket)).unwrap();
+
+        let first: IpAddr = server(0).parse().unwrap();
+        let second: IpAddr = server(1).parse().unwrap();
+        assert_eq!(socket.queried, [first, second, second]);
+    }
+}
+
+#[test]
+fn rate_limited_servers_are_skipped_for_a_while() {
+    let mut socket = TestSocket::new(&[Answer::Kiss(b"RATE")]);
+    let servers = [server(0)];
+    let mut client = SntpClient::new(StdDns, &servers);
+
+    let error = block_on(client.query(&mut socket)).unwrap_err();
+    assert!(matches!(error, Error::KissOfDeath(KissCode(code)) if &code == b"RATE"));
+
+    let error = block_on(client.query(&mut socket)).unwrap_err();
+    assert!(matches!(error, Error::NoServer));
+    assert_eq!(socket.queried.len(), 1);
+}
+
+#[test]
+fn unrelated_datagrams_are_ignored() {
+    let mut socket = TestSocket::new(&[Answer::Stale, Answer::Stranger]);
+    let servers = [server(0)];
+    let mut client = SntpClient::new(StdDns, &servers);
+    block_on(client.query(&mut socket)).unwrap();
+    assert!(socket.pending.is_empty());
+
+    let servers = [server(1)];
+    let mut client = SntpClient::new(StdDns, &servers);
+    block_on(client.query(&mut socket)).unwrap();
+    assert!(socket.pending.is_empty());
+}
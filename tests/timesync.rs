@@ -0,0 +1,66 @@
+// Copyright Claudio Mattera 2024.
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Time synchronization from the `Date` of earlier responses
+
+mod common;
+
+use std::net::SocketAddr;
+
+use embassy_futures::block_on;
+use embassy_time::Duration;
+
+use time::UtcOffset;
+
+use esp32c3_embassy::host::StdDns;
+use esp32c3_embassy::http::ClientTrait as _;
+use esp32c3_embassy::sntp::Error;
+use esp32c3_embassy::sntp::NtpSocket;
+use esp32c3_embassy::sntp::SntpClient;
+use esp32c3_embassy::timesync::Source;
+use esp32c3_embassy::timesync::TimeSync;
+
+use self::common::client;
+use self::common::response;
+use self::common::Server;
+
+/// Timeout of test requests
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A socket for tests that never query SNTP servers
+struct UnusedSocket;
+
+impl NtpSocket for UnusedSocket {
+    async fn send_to(&mut self, _buffer: &[u8], _remote: SocketAddr) -> Result<(), Error> {
+        unreachable!("no SNTP server is queried")
+    }
+
+    async fn receive_from(&mut self, _buffer: &mut [u8]) -> Result<(usize, SocketAddr), Error> {
+        unreachable!("no SNTP server is queried")
+    }
+}
+
+#[test]
+fn recent_date_is_used_without_request() {
+    let server = Server::start(vec![response(
+        "200 OK",
+        &[("Date", "Sun, 06 Nov 1994 08:49:37 GMT")],
+        b"",
+    )]);
+    let mut client = client();
+    block_on(client.get_request(&server.url("/"), TIMEOUT)).unwrap();
+
+    let date_url = server.url("/date");
+    let sntp_client = SntpClient::new(StdDns, &[]);
+    let mut time_sync = TimeSync::new(sntp_client, &date_url, UtcOffset::UTC).with_sources(&[Source::HttpDate]);
+    let sync = block_on(time_sync.synchronize(&mut UnusedSocket, &mut client)).unwrap();
+    assert_eq!(sync.source, Source::HttpDate);
+    let seconds = sync.clock.now_as_epoch();
+    assert!((784_111_777..784_111_780).contains(&seconds));
+
+    let received = server.finish(&mut client);
+    assert_eq!(received.len(), 1);
+}
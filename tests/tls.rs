@@ -0,0 +1,348 @@
+// Copyright Claudio Mattera 2024.
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Certificate verification against a stand-in TLS server
+
+#![allow(clippy::result_large_err)]
+
+mod common;
+
+use std::io::Read as _;
+use std::io::Write as _;
+use std::net::TcpListener;
+use std::sync::Arc;
+use std::thread::JoinHandle;
+
+use embassy_futures::block_on;
+use embassy_time::Duration;
+
+use rcgen::BasicConstraints;
+use rcgen::CertificateParams;
+use rcgen::CertifiedIssuer;
+use rcgen::DnType;
+use rcgen::IsCa;
+use rcgen::Issuer;
+use rcgen::KeyPair;
+use rcgen::KeyUsagePurpose;
+use rcgen::PublicKeyData as _;
+
+use rustls::pki_types::CertificateDer;
+use rustls::pki_types::PrivatePkcs8KeyDer;
+use rustls::ServerConfig;
+use rustls::ServerConnection;
+use rustls::StreamOwned;
+
+use sha2::Digest as _;
+use sha2::Sha256;
+
+use esp32c3_embassy::http::ClientTrait as _;
+use esp32c3_embassy::http::Error;
+use esp32c3_embassy::tls::Verification;
+use esp32c3_embassy::tls::VerificationError;
+
+use self::common::verifying_client;
+
+/// Timeout of test requests
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A certificate authority, with its certificate
+type Authority = CertifiedIssuer<'static, KeyPair>;
+
+/// Create a self-signed root authority
+fn root(name: &str) -> Authority {
+    CertifiedIssuer::self_signed(ca_params(name, None), KeyPair::generate().unwrap()).unwrap()
+}
+
+/// Create an intermediate authority issued by another one
+fn intermediate(name: &str, issuer: &Authority) -> Authority {
+    CertifiedIssuer::signed_by(ca_params(name, None), KeyPair::generate().unwrap(), issuer).unwrap()
+}
+
+/// Return the parameters of an authority, with an optional path length
+/// constraint
+fn ca_params(name: &str, path_length: Option<u8>) -> CertificateParams {
+    let mut params = CertificateParams::new(Vec::<String>::new()).unwrap();
+    params.distinguished_name.push(DnType::CommonName, name);
+    params.is_ca = match path_length {
+        Some(length) => IsCa::Ca(BasicConstraints::Constrained(length)),
+        None => IsCa::Ca(BasicConstraints::Unconstrained),
+    };
+    params.key_usages = vec![KeyUsagePurpose::KeyCertSign, KeyUsagePurpose::DigitalSignature];
+    params
+}
+
+/// Return the parameters of a leaf certificate for a host
+fn leaf_params(host: &str) -> CertificateParams {
+    let mut params = CertificateParams::new(vec![host.to_owned()]).unwrap();
+    params.distinguished_name.push(DnType::CommonName, host);
+    params
+}
+
+/// Issue a leaf certificate for a host, returning it with its key
+fn leaf(host: &str, issuer: &Issuer<'_, KeyPair>) -> (CertificateDer<'static>, KeyPair) {
+    let key = KeyPair::generate().unwrap();
+    let certificate = leaf_params(host).signed_by(&key, issuer).unwrap();
+    (certificate.der().clone(), key)
+}
+
+/// Leak a list of certificates, as verification expects static ones
+fn leak(certificates: &[&CertificateDer<'static>]) -> &'static [&'static [u8]] {
+    let certificates: Vec<&'static [u8]> = certificates
+        .iter()
+        .map(|certificate| &*Box::leak(certificate.to_vec().into_boxed_slice()))
+        .collect();
+    Box::leak(certificates.into_boxed_slice())
+}
+
+/// Return the SHA-256 digest of the public key of an authority
+fn pin(authority: &Authority) -> [u8; 32] {
+    Sha256::digest(authority.key().subject_public_key_info()).into()
+}
+
+/// A stand-in HTTPS server answering a single request
+struct TlsServer {
+    /// Host the server is requested at
+    host: &'static str,
+
+    /// Port the server listens on
+    port: u16,
+
+    /// Thread serving the response
+    thread: JoinHandle<()>,
+}
+
+impl TlsServer {
+    /// Start a server presenting a certificate chain, leaf first
+    fn start(chain: Vec<CertificateDer<'static>>, key: &KeyPair) -> Self {
+        Self::start_at("localhost", chain, key)
+    }
+
+    /// Start a server requested at a host, presenting a certificate chain
+    fn start_at(host: &'static str, chain: Vec<CertificateDer<'static>>, key: &KeyPair) -> Self {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let config = ServerConfig::builder_with_provider(provider)
+            .with_protocol_versions(&[&rustls::version::TLS13])
+            .unwrap()
+            .with_no_client_auth()
+            .with_single_cert(chain, PrivatePkcs8KeyDer::from(key.serialize_der()).into())
+            .unwrap();
+        let config = Arc::new(config);
+
+        // Bind to the address the client resolves first
+        let listener = TcpListener::bind((host, 0)).expect("bind");
+        let port = listener.local_addr().expect("address").port();
+
+        let thread = std::thread::spawn(move || {
+            let (socket, _) = listener.accept().expect("accept");
+            let connection = ServerConnection::new(config).expect("connection");
+            let mut stream = StreamOwned::new(connection, socket);
+
+            // Clients that reject the certificate abort the handshake
+            let mut head = Vec::new();
+            while !head.ends_with(b"\r\n\r\n") {
+                let mut byte = [0_u8];
+                match stream.read(&mut byte) {
+                    Ok(1) => head.push(byte[0]),
+                    _ => return,
+                }
+            }
+            let _ = stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 6\r\n\r\nSecret");
+            let _ = stream.flush();
+            let mut rest = Vec::new();
+            let _ = stream.read_to_end(&mut rest);
+        });
+
+        Self { host, port, thread }
+    }
+
+    /// Request the server with a verification mode, and return the body
+    fn request(self, verification: Verification) -> Result<Vec<u8>, Error> {
+        let mut client = verifying_client(verification);
+        let url = format!("https://{}:{}/", self.host, self.port);
+        let result = block_on(client.get_request(&url, TIMEOUT)).map(|body| body.to_vec());
+        client.close();
+        self.thread.join().expect("server thread");
+        result
+    }
+}
+
+/// Assert that a request failed certificate verification with an error
+#[track_caller]
+fn assert_rejected(result: Result<Vec<u8>, Error>, expected: VerificationError) {
+    match result {
+        Err(Error::TlsVerification(error)) => assert_eq!(error, expected),
+        result => panic!("expected {expected:?}, got {result:?}"),
+    }
+}
+
+#[test]
+fn trusted_chain_is_accepted() {
+    let root = root("Root");
+    let intermediate = intermediate("Intermediate", &root);
+    let (leaf, key) = leaf("localhost", &intermediate);
+
+    let server = TlsServer::start(vec![leaf, intermediate.der().clone()], &key);
+    let body = server.request(Verification::CaRoots(leak(&[root.der()]))).unwrap();
+    assert_eq!(body, b"Secret");
+}
+
+#[test]
+fn certificates_past_the_root_are_ignored() {
+    let root = root("Root");
+    let intermediate = intermediate("Intermediate", &root);
+    let (leaf, key) = leaf("localhost", &intermediate);
+
+    // The trusted root cross-signed by an older one
+    let old_root = self::root("Old root");
+    let cross_signed = ca_params("Root", None).signed_by(root.key(), &old_root).unwrap();
+
+    let chain = vec![leaf, intermediate.der().clone(), cross_signed.der().clone()];
+    let server = TlsServer::start(chain, &key);
+    let body = server.request(Verification::CaRoots(leak(&[root.der()]))).unwrap();
+    assert_eq!(body, b"Secret");
+}
+
+#[test]
+fn unknown_root_is_rejected() {
+    let root = root("Root");
+    let other = self::root("Other root");
+    let (leaf, key) = leaf("localhost", &root);
+
+    let server = TlsServer::start(vec![leaf], &key);
+    let result = server.request(Verification::CaRoots(leak(&[other.der()])));
+    assert_rejected(result, VerificationError::UntrustedChain);
+}
+
+#[test]
+fn hostname_must_match() {
+    let root = root("Root");
+    let (leaf, key) = leaf("example.com", &root);
+
+    let server = TlsServer::start(vec![leaf], &key);
+    let result = server.request(Verification::CaRoots(leak(&[root.der()])));
+    assert_rejected(result, VerificationError::HostnameMismatch);
+}
+
+#[test]
+fn leaf_certificates_cannot_issue() {
+    let root = root("Root");
+    let intermediate = intermediate("Intermediate", &root);
+
+    // A genuine leaf for another host, whose key issues a forged leaf
+    let attacker_key = KeyPair::generate().unwrap();
+    let attacker_params = leaf_params("attacker.example");
+    let attacker = attacker_params.signed_by(&attacker_key, &intermediate).unwrap();
+    let (forged, key) = leaf("localhost", &Issuer::new(attacker_params, attacker_key));
+
+    let chain = vec![forged, attacker.der().clone(), intermediate.der().clone()];
+    let server = TlsServer::start(chain, &key);
+    let result = server.request(Verification::CaRoots(leak(&[root.der()])));
+    assert_rejected(result, VerificationError::NotCa);
+}
+
+#[test]
+fn issuer_names_must_chain() {
+    let root = root("Root");
+    let intermediate = intermediate("Intermediate", &root);
+    let (leaf, key) = leaf("localhost", &intermediate);
+
+    // Same key as the issuer of the leaf, but another name
+    let renamed = ca_params("Renamed", None)
+        .signed_by(intermediate.key(), &root)
+        .unwrap();
+
+    let server = TlsServer::start(vec![leaf, renamed.der().clone()], &key);
+    let result = server.request(Verification::CaRoots(leak(&[root.der()])));
+    assert_rejected(result, VerificationError::IssuerMismatch);
+}
+
+#[test]
+fn long_chain_is_rejected() {
+    let root = root("Root");
+    let first = intermediate("First", &root);
+    let second = intermediate("Second", &first);
+    let third = intermediate("Third", &second);
+    let fourth = intermediate("Fourth", &third);
+    let (leaf, key) = leaf("localhost", &fourth);
+
+    let chain = vec![
+        leaf,
+        fourth.der().clone(),
+        third.der().clone(),
+        second.der().clone(),
+        first.der().clone(),
+    ];
+    let server = TlsServer::start(chain, &key);
+    let result = server.request(Verification::CaRoots(leak(&[root.der()])));
+    assert_rejected(result, VerificationError::ChainTooLong);
+}
+
+#[test]
+fn path_length_is_enforced() {
+    let root = root("Root");
+    let constrained = CertifiedIssuer::signed_by(
+        ca_params("Constrained", Some(0)),
+        KeyPair::generate().unwrap(),
+        &root,
+    )
+    .unwrap();
+    let nested = intermediate("Nested", &constrained);
+    let (leaf, key) = leaf("localhost", &nested);
+
+    let chain = vec![leaf, nested.der().clone(), constrained.der().clone()];
+    let server = TlsServer::start(chain, &key);
+    let result = server.request(Verification::CaRoots(leak(&[root.der()])));
+    assert_rejected(result, VerificationError::PathTooLong);
+}
+
+#[test]
+fn pinned_key_is_accepted() {
+    let root = root("Root");
+    let intermediate = intermediate("Intermediate", &root);
+    let (leaf, key) = leaf("localhost", &intermediate);
+
+    let pins: &'static [[u8; 32]] = Box::leak(Box::new([pin(&intermediate)]));
+    let server = TlsServer::start(vec![leaf, intermediate.der().clone()], &key);
+    let body = server.request(Verification::PinnedKeys(pins)).unwrap();
+    assert_eq!(body, b"Secret");
+}
+
+#[test]
+fn pinned_key_must_sign_the_path() {
+    let root = root("Root");
+    let intermediate = intermediate("Intermediate", &root);
+
+    // A leaf naming the pinned authority as issuer, signed by another key
+    let impostor = Issuer::new(ca_params("Intermediate", None), KeyPair::generate().unwrap());
+    let (forged, key) = leaf("localhost", &impostor);
+
+    let pins: &'static [[u8; 32]] = Box::leak(Box::new([pin(&intermediate)]));
+    let server = TlsServer::start(vec![forged, intermediate.der().clone()], &key);
+    let result = server.request(Verification::PinnedKeys(pins));
+    assert_rejected(result, VerificationError::InvalidSignature);
+}
+
+#[test]
+fn ip_host_matches_ip_address() {
+    let root = root("Root");
+    let intermediate = intermediate("Intermediate", &root);
+    let (leaf, key) = leaf("127.0.0.1", &intermediate);
+
+    let pins: &'static [[u8; 32]] = Box::leak(Box::new([pin(&intermediate)]));
+    let server = TlsServer::start_at("127.0.0.1", vec![leaf, intermediate.der().clone()], &key);
+    let body = server.request(Verification::PinnedKeys(pins)).unwrap();
+    assert_eq!(body, b"Secret");
+}
+
+#[test]
+fn ip_host_does_not_match_host_names() {
+    let root = root("Root");
+    let (leaf, key) = leaf("localhost", &root);
+
+    let server = TlsServer::start_at("127.0.0.1", vec![leaf], &key);
+    let result = server.request(Verification::CaRoots(leak(&[root.der()])));
+    assert_rejected(result, VerificationError::HostnameMismatch);
+}
@@ -0,0 +1,109 @@
+// Copyright Claudio Mattera 2024.
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! WebSocket client against a stand-in server
+
+mod common;
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine as _;
+
+use embassy_futures::block_on;
+use embassy_time::Duration;
+
+use rand_core::Rng as _;
+
+use sha1::Digest as _;
+use sha1::Sha1;
+
+use esp32c3_embassy::websocket::connect;
+use esp32c3_embassy::websocket::Error;
+use esp32c3_embassy::websocket::Message;
+
+use self::common::client;
+use self::common::Server;
+use self::common::TestRng;
+
+/// Encode bytes in base64
+fn encode(bytes: &[u8]) -> String {
+    let mut encoded = [0_u8; 64];
+    let length = BASE64.encode_slice(bytes, &mut encoded).unwrap();
+    String::from_utf8(encoded[..length].to_vec()).unwrap()
+}
+
+/// Return the handshake response accepting the key drawn from a fresh
+/// [`TestRng`]
+fn handshake() -> Vec<u8> {
+    let mut key = [0_u8; 16];
+    TestRng::default().fill_bytes(&mut key);
+    let digest = Sha1::new()
+        .chain_update(encode(&key))
+        .chain_update("258EAFA5-E914-47DA-95CA-C5AB0DC85B11")
+        .finalize();
+
+    format!(
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+        encode(&digest)
+    )
+    .into_bytes()
+}
+
+/// Build an unmasked server frame
+fn frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = vec![if fin { 0x80 | opcode } else { opcode }];
+    if payload.len() < 126 {
+        frame.push(payload.len() as u8);
+    } else {
+        frame.push(126);
+        frame.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+    }
+    frame.extend_from_slice(payload);
+    frame
+}
+
+#[test]
+fn large_messages_are_skipped() {
+    let mut response = handshake();
+    response.extend(frame(false, 0x1, &[b'x'; 200]));
+    response.extend(frame(false, 0x0, &[b'y'; 10]));
+    response.extend(frame(true, 0x0, &[b'z'; 300]));
+    response.extend(frame(true, 0x1, b"small"));
+    response.extend(frame(true, 0x2, &[b'w'; 100]));
+    response.extend(frame(true, 0x8, &1000_u16.to_be_bytes()));
+    let server = Server::start(vec![Some(response)]);
+    let mut client = client();
+
+    let url = server.url("/socket").replacen("http", "ws", 1);
+    let mut socket = block_on(connect(&mut client, &url, &[], TestRng::default())).unwrap();
+
+    let mut buffer = [0_u8; 64];
+    let result = block_on(socket.receive(&mut buffer));
+    assert!(matches!(result, Err(Error::MessageTooLarge)));
+    let result = block_on(socket.receive(&mut buffer));
+    assert!(matches!(result, Ok(Message::Text("small"))));
+
+    let result = block_on(socket.keep_alive(Duration::from_secs(5)));
+    assert!(matches!(result, Err(Error::Closed)));
+
+    drop(socket);
+    server.finish(&mut client);
+}
+
+#[test]
+fn close_ignores_frames_until_server_close() {
+    let mut response = handshake();
+    response.extend(frame(true, 0x1, b"late"));
+    response.extend(frame(true, 0x9, b"ping"));
+    response.extend(frame(true, 0x8, &1000_u16.to_be_bytes()));
+    let server = Server::start(vec![Some(response)]);
+    let mut client = client();
+
+    let url = server.url("/socket").replacen("http", "ws", 1);
+    let socket = block_on(connect(&mut client, &url, &[], TestRng::default())).unwrap();
+    block_on(socket.close(1000)).unwrap();
+
+    server.finish(&mut client);
+}